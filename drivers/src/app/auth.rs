@@ -0,0 +1,648 @@
+use std::{
+    collections::{
+        HashMap,
+        HashSet,
+    },
+    fmt,
+    fs,
+    sync::Arc,
+};
+
+use actix_web::{
+    body::{
+        EitherBody,
+        MessageBody,
+    },
+    dev::{
+        ServiceRequest,
+        ServiceResponse,
+    },
+    error,
+    http::header,
+    middleware::Next,
+    web,
+    Error,
+    HttpMessage,
+    HttpRequest,
+    Result,
+};
+use cloudevents::{
+    AttributesReader,
+    Event,
+};
+use jsonwebtoken::{
+    jwk::{
+        AlgorithmParameters,
+        EllipticCurve,
+        Jwk,
+        JwkSet,
+    },
+    Algorithm,
+    DecodingKey,
+    Validation,
+};
+use serde::Deserialize;
+use sha2::{
+    Digest,
+    Sha256,
+};
+use subtle::ConstantTimeEq;
+
+use crate::app::config::{
+    AuthConfig,
+    Events,
+    State,
+};
+use crate::support::cloudevents::verified;
+
+pub const API_KEY: &str = "x-api-key";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Role {
+    Dispatcher,
+    Hr,
+    Finance,
+    ReadOnly,
+}
+
+impl std::str::FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_value(serde_json::Value::String(s.to_string()))
+            .map_err(|_| format!("unknown role: {}", s))
+    }
+}
+
+/// The authenticated caller, kept in the request extensions.
+#[derive(Debug, Clone)]
+pub struct Principal {
+    pub subject: String,
+    pub roles:   HashSet<Role>,
+}
+
+/// Authenticates the callers, if any authenticator is configured. Without
+/// any, the API is open, as it was for local development.
+#[derive(Clone, Default)]
+pub struct Auth {
+    authenticators: Arc<Vec<Box<dyn Authenticator>>>,
+}
+
+/// A way of authenticating the caller, from the credentials in the request.
+pub trait Authenticator: Send + Sync {
+    /// Gives `None` when the request carries no credentials of this kind.
+    fn authenticate(
+        &self,
+        req: &ServiceRequest,
+    ) -> Option<Result<Principal, String>>;
+}
+
+impl Auth {
+    pub fn new(config: &AuthConfig) -> Result<Self, String> {
+        let mut authenticators: Vec<Box<dyn Authenticator>> = vec![];
+        if config.jwt_secret.is_some() || config.jwks_file.is_some() {
+            authenticators.push(Box::new(Jwt::new(config)?));
+        }
+        if !config.api_keys.is_empty() {
+            authenticators.push(Box::new(ApiKeys::new(&config.api_keys)));
+        }
+        Ok(Auth {
+            authenticators: Arc::new(authenticators),
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.authenticators.is_empty()
+    }
+
+    /// Checks that the caller has any of the given roles.
+    pub fn require(
+        &self,
+        req: &HttpRequest,
+        roles: &[Role],
+        action: &str,
+    ) -> Result<()> {
+        if !self.enabled() {
+            return Ok(());
+        }
+        let principal = req.extensions().get::<Principal>().cloned();
+        let principal = match principal {
+            Some(p) => p,
+            None => {
+                audit(req, "anonymous", action, "missing credentials");
+                return Err(error::ErrorUnauthorized("missing credentials"));
+            }
+        };
+        match roles.iter().any(|r| principal.roles.contains(r)) {
+            true => Ok(()),
+            false => {
+                let reason = format!("requires any of {:?}", roles);
+                audit(req, &principal.subject, action, &reason);
+                Err(error::ErrorForbidden(format!("{} {}", action, reason)))
+            }
+        }
+    }
+
+    /// Checks that the principal an event comes from has any of the given
+    /// roles, as [`Auth::require`] does for requests, or only that there is
+    /// one, with no roles given.
+    pub fn require_of(
+        &self,
+        principal: Option<&Principal>,
+        roles: &[Role],
+        action: &str,
+    ) -> Result<()> {
+        if !self.enabled() {
+            return Ok(());
+        }
+        let principal = match principal {
+            Some(p) => p,
+            None => {
+                log::warn!(
+                    target: "audit",
+                    "denied: actor=anonymous action={} reason=origin not verified",
+                    action
+                );
+                return Err(error::ErrorUnauthorized("origin not verified"));
+            }
+        };
+        let allowed = roles.is_empty()
+            || roles.iter().any(|r| principal.roles.contains(r));
+        match allowed {
+            true => Ok(()),
+            false => {
+                let reason = format!("requires any of {:?}", roles);
+                log::warn!(
+                    target: "audit",
+                    "denied: actor={} action={} reason={}",
+                    principal.subject,
+                    action,
+                    reason
+                );
+                Err(error::ErrorForbidden(format!("{} {}", action, reason)))
+            }
+        }
+    }
+
+    fn authenticate(
+        &self,
+        req: &ServiceRequest,
+    ) -> Option<Result<Principal, String>> {
+        self.authenticators.iter().find_map(|a| a.authenticate(req))
+    }
+}
+
+/// The principal the event comes from: its source, with the roles given to
+/// it, if the origin of the event is verified.
+pub fn event_principal(cfg: &Events, ce: &Event) -> Option<Principal> {
+    if !verified(cfg, ce) {
+        return None;
+    }
+    let subject = ce.source().to_string();
+    let roles = cfg.roles.get(&subject).cloned().unwrap_or_default();
+    Some(Principal { subject, roles })
+}
+
+impl fmt::Debug for Auth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Auth")
+            .field("authenticators", &self.authenticators.len())
+            .finish()
+    }
+}
+
+/// Middleware authenticating the caller, if credentials are given. Whether
+/// the caller may do what's asked is decided by the handlers.
+pub async fn middleware(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, Error> {
+    let res = req
+        .app_data::<web::Data<State>>()
+        .and_then(|state| state.auth.authenticate(&req));
+    match res {
+        None => (),
+        Some(Ok(principal)) => {
+            log::debug!("Authenticated: {:?}", principal);
+            req.extensions_mut().insert(principal);
+        }
+        Some(Err(err)) => {
+            audit(req.request(), "anonymous", "authenticate", &err);
+            let err = error::ErrorUnauthorized("invalid credentials");
+            return Ok(req.error_response(err).map_into_right_body());
+        }
+    }
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_left_body)
+}
+
+/// The subject of the caller, or `anonymous` if not authenticated.
+pub fn actor(req: &HttpRequest) -> String {
+    req.extensions()
+        .get::<Principal>()
+        .map(|p| p.subject.clone())
+        .unwrap_or_else(|| "anonymous".to_string())
+}
+
+fn audit(req: &HttpRequest, actor: &str, action: &str, reason: &str) {
+    log::warn!(
+        target: "audit",
+        "denied: actor={} action={} method={} path={} reason={}",
+        actor,
+        action,
+        req.method(),
+        req.path(),
+        reason
+    );
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    sub:   String,
+    #[serde(default)]
+    roles: HashSet<Role>,
+}
+
+/// Verifies JWT bearer tokens, with a shared secret or the keys of a local
+/// JWKS file.
+struct Jwt {
+    secret:   Option<DecodingKey>,
+    jwks:     Option<JwkSet>,
+    issuer:   Option<String>,
+    audience: Option<String>,
+}
+
+impl Jwt {
+    fn new(config: &AuthConfig) -> Result<Self, String> {
+        let jwks = match &config.jwks_file {
+            None => None,
+            Some(path) => {
+                let data = fs::read_to_string(path)
+                    .map_err(|err| format!("can't read {}: {}", path, err))?;
+                let jwks = serde_json::from_str(&data)
+                    .map_err(|err| format!("invalid JWKS {}: {}", path, err))?;
+                Some(jwks)
+            }
+        };
+        Ok(Jwt {
+            secret: config
+                .jwt_secret
+                .as_ref()
+                .map(|s| DecodingKey::from_secret(s.as_bytes())),
+            jwks,
+            issuer: config.jwt_issuer.clone(),
+            audience: config.jwt_audience.clone(),
+        })
+    }
+
+    fn verify(&self, token: &str) -> Result<Principal, String> {
+        let head =
+            jsonwebtoken::decode_header(token).map_err(|e| e.to_string())?;
+        let (key, algorithms) = match (&head.kid, &self.jwks, &self.secret) {
+            (Some(kid), Some(jwks), _) => {
+                let jwk =
+                    jwks.find(kid).ok_or(format!("unknown key id: {}", kid))?;
+                let key =
+                    DecodingKey::from_jwk(jwk).map_err(|e| e.to_string())?;
+                (key, algorithms(jwk)?)
+            }
+            (_, _, Some(secret)) => (
+                secret.clone(),
+                vec![Algorithm::HS256, Algorithm::HS384, Algorithm::HS512],
+            ),
+            _ => return Err("no key to verify the token with".to_string()),
+        };
+
+        // Tokens signed with any other algorithm are refused.
+        let mut validation = Validation::new(algorithms[0]);
+        validation.algorithms = algorithms;
+        if let Some(iss) = &self.issuer {
+            validation.set_issuer(&[iss]);
+        }
+        match &self.audience {
+            Some(aud) => validation.set_audience(&[aud]),
+            None => validation.validate_aud = false,
+        }
+
+        let data = jsonwebtoken::decode::<Claims>(token, &key, &validation)
+            .map_err(|e| e.to_string())?;
+        Ok(Principal {
+            subject: data.claims.sub,
+            roles:   data.claims.roles,
+        })
+    }
+}
+
+/// The algorithms the key verifies, as it declares, or else those of its
+/// family, never as the token header says.
+fn algorithms(jwk: &Jwk) -> Result<Vec<Algorithm>, String> {
+    if let Some(alg) = jwk.common.key_algorithm {
+        return match alg.to_string().parse() {
+            Ok(alg) => Ok(vec![alg]),
+            Err(_) => Err(format!("key not for signing: {}", alg)),
+        };
+    }
+    match &jwk.algorithm {
+        AlgorithmParameters::RSA(_) => {
+            Ok(vec![Algorithm::RS256, Algorithm::RS384, Algorithm::RS512])
+        }
+        AlgorithmParameters::EllipticCurve(ec) => match ec.curve {
+            EllipticCurve::P256 => Ok(vec![Algorithm::ES256]),
+            EllipticCurve::P384 => Ok(vec![Algorithm::ES384]),
+            _ => Err(format!("unsupported curve: {:?}", ec.curve)),
+        },
+        AlgorithmParameters::OctetKey(_) => {
+            Ok(vec![Algorithm::HS256, Algorithm::HS384, Algorithm::HS512])
+        }
+        AlgorithmParameters::OctetKeyPair(_) => Ok(vec![Algorithm::EdDSA]),
+    }
+}
+
+impl Authenticator for Jwt {
+    fn authenticate(
+        &self,
+        req: &ServiceRequest,
+    ) -> Option<Result<Principal, String>> {
+        let token = req
+            .headers()
+            .get(header::AUTHORIZATION)?
+            .to_str()
+            .ok()?
+            .strip_prefix("Bearer ")?;
+        Some(self.verify(token.trim()))
+    }
+}
+
+/// Static API keys, each given a set of roles. Keys are kept hashed, and
+/// compared in constant time.
+struct ApiKeys {
+    keys: Vec<([u8; 32], HashSet<Role>)>,
+}
+
+impl ApiKeys {
+    fn new(keys: &HashMap<String, HashSet<Role>>) -> Self {
+        ApiKeys {
+            keys: keys
+                .iter()
+                .map(|(key, roles)| (Sha256::digest(key).into(), roles.clone()))
+                .collect(),
+        }
+    }
+}
+
+impl Authenticator for ApiKeys {
+    fn authenticate(
+        &self,
+        req: &ServiceRequest,
+    ) -> Option<Result<Principal, String>> {
+        let key = req.headers().get(API_KEY)?;
+        let hash: [u8; 32] = Sha256::digest(key.as_bytes()).into();
+        // All the keys are compared, so the time taken tells nothing.
+        let roles =
+            self.keys.iter().fold(
+                None,
+                |found, (known, roles)| match bool::from(known.ct_eq(&hash)) {
+                    true => Some(roles),
+                    false => found,
+                },
+            );
+        Some(match roles {
+            Some(roles) => Ok(Principal {
+                subject: format!("api-key:{}", hex::encode(&hash[..4])),
+                roles:   roles.clone(),
+            }),
+            None => Err("unknown API key".to_string()),
+        })
+    }
+}
+
+/// Parses API keys given as `key:role,role;key:role`.
+pub fn parse_api_keys(
+    spec: &str,
+) -> Result<HashMap<String, HashSet<Role>>, String> {
+    spec.split(';')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|entry| {
+            let (key, roles) = entry
+                .split_once(':')
+                .ok_or(format!("API key without roles: {}", entry))?;
+            let roles = roles
+                .split(',')
+                .map(|r| r.trim().parse())
+                .collect::<Result<HashSet<Role>, String>>()?;
+            Ok((key.trim().to_string(), roles))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::StatusCode,
+        middleware::from_fn,
+        test::{
+            call_service,
+            init_service,
+            TestRequest,
+        },
+        App,
+        HttpResponse,
+    };
+    use jsonwebtoken::{
+        EncodingKey,
+        Header,
+    };
+
+    use super::*;
+
+    fn token(roles: &[&str], key: &[u8], kid: Option<&str>) -> String {
+        signed(Algorithm::HS256, roles, key, kid)
+    }
+
+    fn signed(
+        alg: Algorithm,
+        roles: &[&str],
+        key: &[u8],
+        kid: Option<&str>,
+    ) -> String {
+        let mut header = Header::new(alg);
+        header.kid = kid.map(String::from);
+        let claims = serde_json::json!({
+            "sub": "jane",
+            "roles": roles,
+            "exp": chrono::Utc::now().timestamp() + 60,
+        });
+        jsonwebtoken::encode(&header, &claims, &EncodingKey::from_secret(key))
+            .unwrap()
+    }
+
+    async fn call(config: AuthConfig, req: TestRequest) -> StatusCode {
+        let state = State {
+            auth: Auth::new(&config).unwrap(),
+            ..State::default()
+        };
+        let app = init_service(
+            App::new()
+                .wrap(from_fn(middleware))
+                .app_data(web::Data::new(state))
+                .route(
+                    "/graduate",
+                    web::put().to(
+                        |req: HttpRequest, state: web::Data<State>| async move {
+                            state.auth.require(
+                                &req,
+                                &[Role::Hr],
+                                "graduate",
+                            )?;
+                            Ok::<_, Error>(HttpResponse::Ok().finish())
+                        },
+                    ),
+                ),
+        )
+        .await;
+        call_service(&app, req.uri("/graduate").to_request())
+            .await
+            .status()
+    }
+
+    fn secret() -> AuthConfig {
+        AuthConfig {
+            jwt_secret: Some("s3cr3t".to_string()),
+            ..AuthConfig::default()
+        }
+    }
+
+    fn bearer(token: String) -> (header::HeaderName, String) {
+        (header::AUTHORIZATION, format!("Bearer {}", token))
+    }
+
+    #[actix_web::test]
+    async fn open_without_authenticators() {
+        let req = TestRequest::put();
+        assert_eq!(call(AuthConfig::default(), req).await, StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn jwt_with_shared_secret() {
+        let req = TestRequest::put();
+        assert_eq!(call(secret(), req).await, StatusCode::UNAUTHORIZED);
+
+        let hr = token(&["hr"], b"s3cr3t", None);
+        let req = TestRequest::put().insert_header(bearer(hr));
+        assert_eq!(call(secret(), req).await, StatusCode::OK);
+
+        let ro = token(&["read-only"], b"s3cr3t", None);
+        let req = TestRequest::put().insert_header(bearer(ro));
+        assert_eq!(call(secret(), req).await, StatusCode::FORBIDDEN);
+
+        let forged = token(&["hr"], b"guessed", None);
+        let req = TestRequest::put().insert_header(bearer(forged));
+        assert_eq!(call(secret(), req).await, StatusCode::UNAUTHORIZED);
+    }
+
+    #[actix_web::test]
+    async fn jwt_with_jwks_file() {
+        // The key is "s3cr3t-from-jwks", base64url encoded.
+        let jwks = serde_json::json!({"keys": [{
+            "kty": "oct",
+            "kid": "k1",
+            "alg": "HS256",
+            "k": "czNjcjN0LWZyb20tandrcw",
+        }]});
+        let path = std::env::temp_dir()
+            .join(format!("jwks-{}.json", uuid::Uuid::new_v4()))
+            .to_string_lossy()
+            .to_string();
+        fs::write(&path, jwks.to_string()).unwrap();
+        let config = AuthConfig {
+            jwks_file: Some(path.clone()),
+            ..AuthConfig::default()
+        };
+
+        let hr = token(&["hr"], b"s3cr3t-from-jwks", Some("k1"));
+        let req = TestRequest::put().insert_header(bearer(hr));
+        assert_eq!(call(config.clone(), req).await, StatusCode::OK);
+
+        let unknown = token(&["hr"], b"s3cr3t-from-jwks", Some("k2"));
+        let req = TestRequest::put().insert_header(bearer(unknown));
+        assert_eq!(call(config.clone(), req).await, StatusCode::UNAUTHORIZED);
+
+        // The key declares HS256, whatever the token says.
+        let other =
+            signed(Algorithm::HS512, &["hr"], b"s3cr3t-from-jwks", Some("k1"));
+        let req = TestRequest::put().insert_header(bearer(other));
+        assert_eq!(call(config, req).await, StatusCode::UNAUTHORIZED);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn algorithms_of_keys() {
+        let jwk = |jwk| serde_json::from_value::<Jwk>(jwk).unwrap();
+        let rsa = jwk(serde_json::json!({
+            "kty": "RSA",
+            "n": "AQAB",
+            "e": "AQAB",
+        }));
+        assert_eq!(
+            algorithms(&rsa).unwrap(),
+            [Algorithm::RS256, Algorithm::RS384, Algorithm::RS512]
+        );
+        let ec = jwk(serde_json::json!({
+            "kty": "EC",
+            "crv": "P-384",
+            "x": "AQAB",
+            "y": "AQAB",
+        }));
+        assert_eq!(algorithms(&ec).unwrap(), [Algorithm::ES384]);
+        let declared = jwk(serde_json::json!({
+            "kty": "RSA",
+            "alg": "PS256",
+            "n": "AQAB",
+            "e": "AQAB",
+        }));
+        assert_eq!(algorithms(&declared).unwrap(), [Algorithm::PS256]);
+        let encrypting = jwk(serde_json::json!({
+            "kty": "RSA",
+            "alg": "RSA-OAEP",
+            "n": "AQAB",
+            "e": "AQAB",
+        }));
+        assert!(algorithms(&encrypting).is_err());
+    }
+
+    #[actix_web::test]
+    async fn api_keys() {
+        let config = AuthConfig {
+            api_keys: parse_api_keys("k-hr:hr,finance; k-ro:read-only")
+                .unwrap(),
+            ..AuthConfig::default()
+        };
+
+        let req = TestRequest::put().insert_header((API_KEY, "k-hr"));
+        assert_eq!(call(config.clone(), req).await, StatusCode::OK);
+
+        let req = TestRequest::put().insert_header((API_KEY, "k-ro"));
+        assert_eq!(call(config.clone(), req).await, StatusCode::FORBIDDEN);
+
+        let req = TestRequest::put().insert_header((API_KEY, "nope"));
+        assert_eq!(call(config.clone(), req).await, StatusCode::UNAUTHORIZED);
+
+        let req = TestRequest::put()
+            .insert_header((API_KEY, "k-hr"))
+            .to_srv_request();
+        let keys = ApiKeys::new(&config.api_keys);
+        let principal = keys.authenticate(&req).unwrap().unwrap();
+        assert!(principal.subject.starts_with("api-key:"));
+        assert!(!principal.subject.contains("k-hr"));
+    }
+
+    #[test]
+    fn invalid_api_keys() {
+        assert!(parse_api_keys("k1").is_err());
+        assert!(parse_api_keys("k1:admin").is_err());
+        assert!(parse_api_keys("").unwrap().is_empty());
+    }
+}
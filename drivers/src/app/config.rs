@@ -1,7 +1,47 @@
 use env_logger::Target;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{
+    propagation::TraceContextPropagator,
+    runtime,
+    trace,
+    Resource,
+};
 
-use crate::support::clock::Clock;
-use std::{env, fmt};
+use crate::app::{
+    auth::{
+        self,
+        Auth,
+        Role,
+    },
+    health::Liveness,
+    logging,
+};
+use crate::support::{
+    clock::Clock,
+    cloudevents::Sender,
+    crypto::{
+        self,
+        Cipher,
+    },
+    id::{
+        self,
+        Generator,
+        Repr,
+    },
+    pii,
+    pool::Pool,
+};
+use std::collections::{
+    HashMap,
+    HashSet,
+};
+use std::{env, fmt, path::PathBuf, time::Duration};
+use std::hash::{
+    DefaultHasher,
+    Hash,
+    Hasher,
+};
 
 pub fn get_port() -> u16 {
     match env::var("PORT") {
@@ -10,8 +50,8 @@ pub fn get_port() -> u16 {
     }
 }
 
-pub fn setup_logger(env: &Environment) {
-    let level = match env {
+pub fn setup_logger(config: &Config) {
+    let level = match config.environment {
         Environment::Development => Some(log::LevelFilter::Debug),
         Environment::Production => Some(log::LevelFilter::Info),
     };
@@ -23,27 +63,247 @@ pub fn setup_logger(env: &Environment) {
         Err(_) => level,
     };
     let mut b = env_logger::builder();
-    b.target(Target::Stdout);
-    if level.is_some() {
-        b.filter_level(level.unwrap());
+    b.target(match config.logging.stderr {
+        true => Target::Stderr,
+        false => Target::Stdout,
+    });
+    if let Some(level) = level {
+        b.filter_level(level);
     }
+    if let LogFormat::Json = config.logging.format {
+        b.format(logging::json);
+    }
+    pii::redact(!config.logging.pii);
     match b.try_init() {
         Ok(_) => (),
         Err(err) => eprintln!("Error initializing logger: {}", err),
     };
 }
 
+pub fn setup_tracer(tracing: &Tracing) {
+    opentelemetry::global::set_text_map_propagator(
+        TraceContextPropagator::new(),
+    );
+    let endpoint = match tracing {
+        Tracing::Noop => return,
+        Tracing::Otlp(endpoint) => endpoint,
+    };
+    let res = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(
+            opentelemetry_otlp::new_exporter()
+                .http()
+                .with_endpoint(endpoint),
+        )
+        .with_trace_config(trace::config().with_resource(Resource::new(
+            vec![KeyValue::new("service.name", "drivers")],
+        )))
+        .install_batch(runtime::Tokio);
+    match res {
+        Ok(_) => log::info!("Exporting traces to {}", endpoint),
+        Err(err) => log::error!("Error initializing tracer: {}", err),
+    };
+}
+
 #[derive(Clone, Debug)]
 pub struct Config {
+    pub auth:        AuthConfig,
     pub db:          DbConfig,
     pub environment: Environment,
+    pub events:      Events,
+    pub health:      Health,
+    pub ids:         Ids,
     pub knative:     Knative,
+    pub logging:     Logging,
+    pub metrics:     Metrics,
     pub name:        String,
+    pub tracing:     Tracing,
+}
+
+/// Authentication of the API callers. With nothing configured, the API is
+/// open to anyone.
+#[derive(Clone, Default)]
+pub struct AuthConfig {
+    /// Shared secret verifying HMAC signed JWTs.
+    pub jwt_secret:   Option<String>,
+    /// Local JWKS file, with the keys verifying JWTs by their key ID.
+    pub jwks_file:    Option<String>,
+    pub jwt_issuer:   Option<String>,
+    pub jwt_audience: Option<String>,
+    /// Static API keys, with the roles each one is given.
+    pub api_keys:     HashMap<String, HashSet<Role>>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Logging {
+    pub format: LogFormat,
+    /// Whether personal data of drivers is logged as is, instead of being
+    /// redacted.
+    pub pii:    bool,
+    /// Whether logs go to the standard error, leaving the standard output
+    /// to the output of the admin commands.
+    pub stderr: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct Metrics {
+    /// How often the drivers stored are counted, in the background.
+    pub refresh: Duration,
+}
+
+#[derive(Clone, Debug)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+/// Verification of the origin of incoming CloudEvents.
+#[derive(Clone, Default)]
+pub struct Events {
+    /// Sources accepted, by event type, with `*` standing for any type. With
+    /// none given, events are accepted from any source.
+    pub sources: HashMap<String, HashSet<String>>,
+    /// Shared secret of the HMAC-SHA256 signature the events must carry.
+    pub secret:  Option<String>,
+    /// Roles of the sources of the commands, by source. With auth enabled,
+    /// commands are refused unless their origin is verified, by signature
+    /// or allowed sources, and their source has the roles required.
+    pub roles:   HashMap<String, HashSet<Role>>,
 }
 
 #[derive(Clone, Debug)]
 pub struct Knative {
-    pub sink: String,
+    pub sink:      String,
+    /// Base URL of the schemas of the event data, given as the `dataschema`
+    /// of the events sent.
+    pub schemas:   String,
+    pub transport: Transport,
+    pub reply:     Reply,
+    pub content:   ContentMode,
+    pub delivery:  Delivery,
+}
+
+/// How events are sent and received.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum Transport {
+    /// Sent to the sink, and received on `/`.
+    #[default]
+    Http,
+    Kafka(Kafka),
+    Redis(Streams),
+}
+
+/// Kafka brokers events are produced to and consumed from, with the
+/// CloudEvents Kafka protocol binding.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Kafka {
+    pub brokers:     String,
+    /// Topic the events sent are produced to.
+    pub topic:       String,
+    /// Topics the events received are consumed from.
+    pub topics:      Vec<String>,
+    /// Consumer group sharing the events received among replicas.
+    pub group:       String,
+    /// Deliveries after which an event still failing is dead-lettered.
+    pub deliveries:  usize,
+    /// Stream of the events received which couldn't be processed.
+    pub dead_letter: String,
+}
+
+/// Redis streams events are added to and read from, as JSON, in the
+/// `event` field of the entries.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Streams {
+    /// Stream the events sent are added to.
+    pub stream:      String,
+    /// Entries the stream is trimmed to, about.
+    pub max_len:     usize,
+    /// Streams the events received are read from.
+    pub streams:     Vec<String>,
+    /// Consumer group sharing the events received among replicas.
+    pub group:       String,
+    /// Name of this replica in the group, unique to each of them.
+    pub consumer:    String,
+    /// Time after which entries left pending by a consumer are claimed by
+    /// another.
+    pub claim_idle:  Duration,
+    /// Deliveries after which an entry still failing is dead-lettered.
+    pub deliveries:  usize,
+    /// Stream of the events received which couldn't be processed.
+    pub dead_letter: String,
+}
+
+/// Where the events resulting from an incoming one go.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Reply {
+    /// Sent to the sink.
+    #[default]
+    Sink,
+    /// Returned in the response to the incoming event, for the broker to
+    /// route.
+    Response,
+}
+
+/// How the events sent are carried in HTTP requests.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ContentMode {
+    /// Attributes in `ce-` headers, data in the body.
+    #[default]
+    Binary,
+    /// The whole event in the body, as `application/cloudevents+json`.
+    Structured,
+}
+
+/// Delivery of the events sent to the sink. Attempts failing with a 5xx or
+/// 429 status, or without reaching the sink, are retried after random
+/// delays, up to `factor * base ^ attempt`, or as the sink asks with
+/// `Retry-After`.
+#[derive(Clone, Debug)]
+pub struct Delivery {
+    pub timeout:         Duration,
+    pub connect_timeout: Duration,
+    pub backoff:         Backoff,
+    /// Longest delay between attempts, even if the sink asks for more.
+    pub max_delay:       Duration,
+    pub dead_letter:     DeadLetter,
+}
+
+/// Where events are kept once their delivery failed for good, to be
+/// replayed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DeadLetter {
+    None,
+    /// The Redis stream of the given key.
+    Redis(String),
+    /// The file, one event per line.
+    File(PathBuf),
+}
+
+#[derive(Clone, Debug)]
+pub struct Ids {
+    /// How the IDs are represented in JSON.
+    pub repr: Repr,
+    /// ID of this node among the replicas generating IDs, unique to each of
+    /// them.
+    pub node: u16,
+}
+
+#[derive(Clone, Debug)]
+pub struct Health {
+    /// How long a single readiness check may take before it's failed.
+    pub timeout:   Duration,
+    /// How often each worker runtime reports it is still making progress.
+    pub heartbeat: Duration,
+    /// How old a worker heartbeat may get before liveness fails.
+    pub stall:     Duration,
+}
+
+#[derive(Clone, Debug)]
+pub enum Tracing {
+    /// Spans are created, but not exported anywhere.
+    Noop,
+    /// Spans are exported with OTLP over HTTP, to the given endpoint.
+    Otlp(String),
 }
 
 #[derive(Clone, Debug)]
@@ -54,9 +314,40 @@ pub enum Environment {
 
 #[derive(Clone)]
 pub struct DbConfig {
-    pub uri:  String,
-    pub user: Option<String>,
-    pub pass: Option<String>,
+    pub uri:        String,
+    pub user:       Option<String>,
+    pub pass:       Option<String>,
+    pub pool:       PoolConfig,
+    pub encryption: Encryption,
+}
+
+/// Encryption of the sensitive driver fields at rest.
+#[derive(Clone)]
+pub struct Encryption {
+    /// AES-256 keys, by key ID. Retired keys stay, so what they encrypted can
+    /// still be read.
+    pub keys:      HashMap<String, Vec<u8>>,
+    /// ID of the key encrypting new values. Optional with a single key.
+    pub key_id:    Option<String>,
+    /// How often values encrypted with other keys are re-encrypted.
+    pub reencrypt: Duration,
+}
+
+#[derive(Clone, Debug)]
+pub struct PoolConfig {
+    /// Number of multiplexed connections shared by all the requests.
+    pub size:            usize,
+    pub connect_timeout: Duration,
+    pub command_timeout: Duration,
+    pub backoff:         Backoff,
+}
+
+/// Delays between attempts are random, up to `factor * base ^ attempt`.
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    pub base:    u64,
+    pub factor:  Duration,
+    pub retries: usize,
 }
 
 impl fmt::Debug for DbConfig {
@@ -68,28 +359,103 @@ impl fmt::Debug for DbConfig {
                 None    => &None::<String>,
                 Some(_) => &Some("***"),
             })
+            .field("pool", &self.pool)
+            .field("encryption", &self.encryption)
+            .finish()
+    }
+}
+
+impl fmt::Debug for Encryption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut kids: Vec<&String> = self.keys.keys().collect();
+        kids.sort();
+        f.debug_struct("Encryption")
+            .field("keys", &kids)
+            .field("key_id", &self.key_id)
+            .field("reencrypt", &self.reencrypt)
+            .finish()
+    }
+}
+
+impl fmt::Debug for AuthConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuthConfig")
+            .field("jwt_secret", match &self.jwt_secret {
+                None    => &None::<String>,
+                Some(_) => &Some("***"),
+            })
+            .field("jwks_file", &self.jwks_file)
+            .field("jwt_issuer", &self.jwt_issuer)
+            .field("jwt_audience", &self.jwt_audience)
+            .field("api_keys", &self.api_keys.len())
+            .finish()
+    }
+}
+
+impl fmt::Debug for Events {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Events")
+            .field("sources", &self.sources)
+            .field("secret", match &self.secret {
+                None    => &None::<String>,
+                Some(_) => &Some("***"),
+            })
+            .field("roles", &self.roles)
             .finish()
     }
 }
 
 #[derive(Clone, Debug)]
 pub struct State {
-    pub config: Config,
-    pub db:     Db,
-    pub clock:  Clock,
+    pub auth:     Auth,
+    pub config:   Config,
+    pub db:       Db,
+    pub clock:    Clock,
+    pub ids:      Generator,
+    pub liveness: Liveness,
+    pub sender:   Sender,
 }
 
 #[derive(Clone, Debug)]
 pub struct Db {
-    pub client: Option<redis::Client>,
+    pub pool:   Option<Pool>,
+    pub cipher: Cipher,
 }
 
 impl Default for Config {
     fn default() -> Config {
         let db = DbConfig {
-            uri:  env_or("APP_DB_URI", "redis://127.0.0.1/"),
-            user: env::var("APP_DB_USER").ok(),
-            pass: env::var("APP_DB_PASS").ok(),
+            uri:        env_or("APP_DB_URI", "redis://127.0.0.1/"),
+            user:       env::var("APP_DB_USER").ok(),
+            pass:       env::var("APP_DB_PASS").ok(),
+            pool:       PoolConfig {
+                size:            env_parse("APP_DB_POOL_SIZE", 4),
+                connect_timeout: env_ms("APP_DB_CONNECT_TIMEOUT_MS", 5000),
+                command_timeout: env_ms("APP_DB_COMMAND_TIMEOUT_MS", 2000),
+                backoff:         Backoff {
+                    base:    env_parse("APP_DB_BACKOFF_BASE", 2),
+                    factor:  env_ms("APP_DB_BACKOFF_FACTOR_MS", 100),
+                    retries: env_parse("APP_DB_RECONNECT_RETRIES", 6),
+                },
+            },
+            encryption: Encryption {
+                keys:      crypto::parse_keys(&env_or(
+                    "APP_DB_ENCRYPTION_KEYS",
+                    "",
+                ))
+                .expect("invalid APP_DB_ENCRYPTION_KEYS"),
+                key_id:    env::var("APP_DB_ENCRYPTION_KEY_ID").ok(),
+                reencrypt: env_ms("APP_DB_REENCRYPT_INTERVAL_MS", 3600000),
+            },
+        };
+
+        let auth = AuthConfig {
+            jwt_secret:   env::var("APP_AUTH_JWT_SECRET").ok(),
+            jwks_file:    env::var("APP_AUTH_JWKS_FILE").ok(),
+            jwt_issuer:   env::var("APP_AUTH_JWT_ISSUER").ok(),
+            jwt_audience: env::var("APP_AUTH_JWT_AUDIENCE").ok(),
+            api_keys:     auth::parse_api_keys(&env_or("APP_AUTH_API_KEYS", ""))
+                .expect("invalid APP_AUTH_API_KEYS"),
         };
 
         let def_env = match cfg!(debug_assertions) {
@@ -108,17 +474,167 @@ impl Default for Config {
             _ => def_env,
         };
 
+        let logging = Logging {
+            format: match env::var("APP_LOG_FORMAT")
+                .unwrap_or_default()
+                .to_ascii_lowercase()
+                .as_str()
+            {
+                "json" => LogFormat::Json,
+                "text" => LogFormat::Text,
+                _ => match environment {
+                    Environment::Development => LogFormat::Text,
+                    Environment::Production => LogFormat::Json,
+                },
+            },
+            pii:    env_or("APP_LOG_PII", "false") == "true",
+            stderr: false,
+        };
+
+        let metrics = Metrics {
+            refresh: env_ms("APP_METRICS_REFRESH_MS", 60000),
+        };
+
         let name = String::from("world");
 
+        let health = Health {
+            timeout:   env_ms("APP_HEALTH_TIMEOUT_MS", 1000),
+            heartbeat: env_ms("APP_HEALTH_HEARTBEAT_MS", 1000),
+            stall:     env_ms("APP_HEALTH_STALL_MS", 10000),
+        };
+
+        let events = Events {
+            sources: env_map("APP_EVENTS_SOURCES"),
+            secret:  env::var("APP_EVENTS_SECRET").ok(),
+            roles:   env_map("APP_EVENTS_ROLES")
+                .into_iter()
+                .map(|(source, roles)| {
+                    let roles = roles
+                        .iter()
+                        .map(|role| role.parse())
+                        .collect::<Result<_, String>>()
+                        .expect("invalid APP_EVENTS_ROLES");
+                    (source, roles)
+                })
+                .collect(),
+        };
+
+        let ids = Ids {
+            repr: env_or("APP_ID_REPR", "int")
+                .parse()
+                .expect("invalid APP_ID_REPR"),
+            node: match env::var("APP_ID_NODE") {
+                Ok(node) => node.parse().expect("invalid APP_ID_NODE"),
+                Err(_) => host_node(),
+            },
+        };
+
+        let dead_letter = env_or("APP_EVENTS_DEAD_LETTER", "redis");
         let knative = Knative {
-            sink: env_or("K_SINK", "http://localhost:31111/"),
+            sink:      env_or("K_SINK", "http://localhost:31111/"),
+            schemas:   env::var("APP_EVENTS_SCHEMAS_URL").unwrap_or_else(|_| {
+                format!("http://localhost:{}/schemas", get_port())
+            }),
+            transport: match env_or("APP_EVENTS_TRANSPORT", "http")
+                .to_ascii_lowercase()
+                .as_str()
+            {
+                "kafka" => Transport::Kafka(Kafka {
+                    brokers:     env_or("KAFKA_BROKERS", "localhost:9092"),
+                    topic:       env_or("APP_KAFKA_TOPIC", "cabs.drivers"),
+                    topics:      env_or("APP_KAFKA_TOPICS", "cabs.legacy")
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|t| !t.is_empty())
+                        .map(String::from)
+                        .collect(),
+                    group:       env_or("APP_KAFKA_GROUP", "drivers"),
+                    deliveries:  env_parse("APP_KAFKA_DELIVERIES", 5),
+                    dead_letter: env_or(
+                        "APP_KAFKA_DEAD_LETTER",
+                        "events-received-dead-letter",
+                    ),
+                }),
+                "redis" => Transport::Redis(Streams {
+                    stream:      env_or("APP_REDIS_STREAM", "cabs.drivers"),
+                    max_len:     env_parse("APP_REDIS_MAX_LEN", 10000),
+                    streams:     env_or("APP_REDIS_STREAMS", "cabs.legacy")
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|s| !s.is_empty())
+                        .map(String::from)
+                        .collect(),
+                    group:       env_or("APP_REDIS_GROUP", "drivers"),
+                    consumer:    env::var("APP_REDIS_CONSUMER")
+                        .or_else(|_| env::var("HOSTNAME"))
+                        .unwrap_or_else(|_| "drivers".to_string()),
+                    claim_idle:  env_ms("APP_REDIS_CLAIM_IDLE_MS", 30000),
+                    deliveries:  env_parse("APP_REDIS_DELIVERIES", 5),
+                    dead_letter: env_or(
+                        "APP_REDIS_DEAD_LETTER",
+                        "events-received-dead-letter",
+                    ),
+                }),
+                _ => Transport::Http,
+            },
+            reply:     match env_or("APP_EVENTS_REPLY", "sink")
+                .to_ascii_lowercase()
+                .as_str()
+            {
+                "response" => Reply::Response,
+                _ => Reply::Sink,
+            },
+            content:   match env_or("APP_EVENTS_CONTENT_MODE", "binary")
+                .to_ascii_lowercase()
+                .as_str()
+            {
+                "structured" => ContentMode::Structured,
+                _ => ContentMode::Binary,
+            },
+            delivery:  Delivery {
+                timeout:         env_ms("APP_EVENTS_TIMEOUT_MS", 5000),
+                connect_timeout: env_ms("APP_EVENTS_CONNECT_TIMEOUT_MS", 1000),
+                backoff:         Backoff {
+                    base:    env_parse("APP_EVENTS_BACKOFF_BASE", 2),
+                    factor:  env_ms("APP_EVENTS_BACKOFF_FACTOR_MS", 100),
+                    retries: env_parse("APP_EVENTS_RETRIES", 5),
+                },
+                max_delay:       env_ms("APP_EVENTS_BACKOFF_MAX_MS", 30000),
+                dead_letter:     match dead_letter.split_once(':') {
+                    _ if dead_letter == "none" => DeadLetter::None,
+                    Some(("file", path)) => DeadLetter::File(path.into()),
+                    Some(("redis", key)) if !key.is_empty() => {
+                        DeadLetter::Redis(key.to_string())
+                    }
+                    _ => DeadLetter::Redis("events-dead-letter".to_string()),
+                },
+            },
+        };
+
+        let tracing = match env::var("APP_TRACING")
+            .unwrap_or_default()
+            .to_ascii_lowercase()
+            .as_str()
+        {
+            "otlp" => Tracing::Otlp(env_or(
+                "OTEL_EXPORTER_OTLP_ENDPOINT",
+                "http://localhost:4318",
+            )),
+            _ => Tracing::Noop,
         };
 
         Config {
+            auth,
             db,
             environment,
+            events,
+            health,
+            ids,
             knative,
+            logging,
+            metrics,
             name,
+            tracing,
         }
     }
 }
@@ -126,17 +642,73 @@ impl Default for Config {
 impl Default for State {
     fn default() -> State {
         let config = Config::default();
+        let auth = Auth::new(&config.auth).expect("invalid auth config");
         let db = Db {
-            client: redis::Client::open(config.db.uri.as_str()).ok(),
+            pool:   redis::Client::open(config.db.uri.as_str())
+                .ok()
+                .map(|client| Pool::new(client, &config.db.pool)),
+            cipher: Cipher::new(
+                &config.db.encryption.keys,
+                config.db.encryption.key_id.as_deref(),
+            )
+            .expect("invalid encryption config"),
         };
         let clock = Clock::default();
-        State { config, db, clock }
+        let ids = Generator::new(config.ids.node).expect("invalid ID node");
+        let liveness = Liveness::default();
+        let sender = Sender::new(&config.knative, db.pool.clone());
+        State {
+            auth,
+            config,
+            db,
+            clock,
+            ids,
+            liveness,
+            sender,
+        }
     }
 }
 
+/// Derives the node generating IDs from the host name, for replicas which
+/// aren't given one. Nodes of distinct hosts may still collide, which
+/// creating drivers only if absent makes up for.
+fn host_node() -> u16 {
+    let mut hasher = DefaultHasher::new();
+    env_or("HOSTNAME", "").hash(&mut hasher);
+    (hasher.finish() % (id::MAX_NODE as u64 + 1)) as u16
+}
+
 fn env_or(key: &str, default: &str) -> String {
     match env::var(key) {
         Ok(v) => v,
         Err(_) => default.to_string(),
     }
 }
+
+fn env_ms(key: &str, default: u64) -> Duration {
+    Duration::from_millis(env_parse(key, default))
+}
+
+fn env_parse<T: std::str::FromStr>(key: &str, default: T) -> T {
+    env::var(key)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+/// Reads a map of sets, given as `key=val,val;key=val`.
+fn env_map(key: &str) -> HashMap<String, HashSet<String>> {
+    env_or(key, "")
+        .split(';')
+        .filter_map(|entry| entry.split_once('='))
+        .map(|(k, vals)| {
+            let vals = vals
+                .split(',')
+                .map(str::trim)
+                .filter(|v| !v.is_empty())
+                .map(String::from)
+                .collect();
+            (k.trim().to_string(), vals)
+        })
+        .collect()
+}
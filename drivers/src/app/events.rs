@@ -1,49 +1,300 @@
 use actix_web::{
     dev::HttpServiceFactory,
     error,
+    guard,
+    http::header,
+    rt::task::JoinHandle,
     web,
+    HttpRequest,
     HttpResponse,
     Result,
 };
+use serde::Serialize;
+use serde_json::value::RawValue;
 
-use crate::app::config::State;
+use crate::app::{
+    config::{
+        ContentMode,
+        DeadLetter,
+        State,
+        Transport,
+    },
+    logging,
+    metrics,
+};
 use crate::drivers::{
     service,
     Binding,
 };
+use crate::support::{
+    cloudevents::{
+        from_structured,
+        received,
+        structured_data,
+        verify,
+    },
+    deadletter::Store,
+    kafka,
+    streams,
+    trace,
+};
 use cloudevents::{
+    binding::{
+        actix::HttpResponseBuilderExt,
+        http::to_event,
+    },
     AttributesReader,
     Event,
 };
 
+/// Content type of a batch of events, in structured mode.
+const BATCH: &str = "application/cloudevents-batch+json";
+/// Content type of an event in structured mode.
+const STRUCTURED: &str = "application/cloudevents+json";
+
 pub fn routes() -> impl HttpServiceFactory + 'static {
-    web::resource("/").route(web::post().to(recv))
+    web::resource("/")
+        .route(
+            web::post()
+                .guard(guard::fn_guard(|ctx| {
+                    ctx.header::<header::ContentType>()
+                        .is_some_and(|ct| ct.essence_str() == BATCH)
+                }))
+                .to(recv_batch),
+        )
+        .route(web::post().to(recv))
 }
 
 async fn recv(
-    ce: Event,
+    req: HttpRequest,
+    body: web::Bytes,
     state: web::Data<State>,
     binding: web::Data<Binding>,
 ) -> Result<HttpResponse> {
-    log::info!("Received event:\n{}", ce);
+    let ce = event(&req, &body)?;
+    let content = state.config.knative.content;
+    match process(ce, state, binding).await? {
+        Some(reply) => respond(content, reply),
+        None => Ok(HttpResponse::Ok().finish()),
+    }
+}
+
+/// The event the request carries, in either content mode, with its data as
+/// received.
+fn event(req: &HttpRequest, body: &web::Bytes) -> Result<Event> {
+    let ce = to_event(req.headers(), body.to_vec())
+        .map_err(error::ErrorBadRequest)?;
+    let structured = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|ct| ct.to_str().ok())
+        .is_some_and(|ct| ct.starts_with(STRUCTURED));
+    let data = match structured {
+        true => structured_data(body),
+        false => Some(body.to_vec()),
+    };
+    Ok(received(ce, data))
+}
 
-    let mut svc = service::new(state, binding).await?;
+/// Responds with the event, in the content mode events are sent in.
+fn respond(content: ContentMode, ce: Event) -> Result<HttpResponse> {
+    match content {
+        ContentMode::Binary => HttpResponse::Ok().event(ce),
+        ContentMode::Structured => Ok(HttpResponse::Ok()
+            .content_type("application/cloudevents+json")
+            .body(serde_json::to_vec(&ce)?)),
+    }
+}
 
-    match ce.ty() {
-        "cabs.drivers.calculate-fee" => svc.calculate_fee(ce).await,
-        _ => Err(error::ErrorBadRequest("unsupported event type")),
-    }?;
+/// Outcome of the processing of one event of a batch.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct Outcome {
+    id:     String,
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error:  Option<String>,
+    /// The event in reply, if replies are responded with.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    event:  Option<Event>,
+}
 
-    Ok(HttpResponse::Ok().finish())
+/// Processes the events of the batch in turn, responding with the outcome of
+/// each, in the same order, whether they failed or not.
+async fn recv_batch(
+    body: web::Bytes,
+    state: web::Data<State>,
+    binding: web::Data<Binding>,
+) -> Result<HttpResponse> {
+    let invalid = |err: serde_json::Error| {
+        error::ErrorBadRequest(format!("invalid batch of events: {}", err))
+    };
+    let batch: Vec<&RawValue> =
+        serde_json::from_slice(&body).map_err(invalid)?;
+    let batch = batch
+        .into_iter()
+        .map(|ce| from_structured(ce.get().as_bytes()))
+        .collect::<serde_json::Result<Vec<Event>>>()
+        .map_err(invalid)?;
+    log::info!("Received batch of {} events", batch.len());
+
+    let mut outcomes = Vec::with_capacity(batch.len());
+    for ce in batch {
+        let id = ce.id().to_string();
+        let outcome = match process(ce, state.clone(), binding.clone()).await {
+            Ok(event) => Outcome {
+                id,
+                status: 200,
+                error: None,
+                event,
+            },
+            Err(err) => Outcome {
+                id,
+                status: err.as_response_error().status_code().as_u16(),
+                error: Some(err.to_string()),
+                event: None,
+            },
+        };
+        outcomes.push(outcome);
+    }
+    Ok(HttpResponse::Ok().json(outcomes))
+}
+
+/// Consumes the events received with the configured transport, unless they
+/// are received on `/`, sending the replies to them, if any. Returns the task
+/// consuming, if any.
+pub fn subscribe(
+    state: &State,
+    binding: Binding,
+) -> Result<Option<JoinHandle<()>>> {
+    let data = web::Data::new(state.clone());
+    let binding = web::Data::new(binding);
+    let handle = move |ce| consumed(ce, data.clone(), binding.clone());
+    match &state.config.knative.transport {
+        Transport::Http => Ok(None),
+        Transport::Kafka(cfg) => {
+            let dead = Store::new(
+                &DeadLetter::Redis(cfg.dead_letter.clone()),
+                state.db.pool.clone(),
+            );
+            let delivery = &state.config.knative.delivery;
+            kafka::subscribe(cfg, delivery, dead, handle)
+                .map(Some)
+                .map_err(error::ErrorInternalServerError)
+        }
+        Transport::Redis(cfg) => {
+            let client = redis::Client::open(state.config.db.uri.as_str())
+                .map_err(error::ErrorInternalServerError)?;
+            let dead = Store::new(
+                &DeadLetter::Redis(cfg.dead_letter.clone()),
+                state.db.pool.clone(),
+            );
+            Ok(Some(streams::subscribe(cfg, client, dead, handle)))
+        }
+    }
+}
+
+async fn consumed(
+    ce: Event,
+    state: web::Data<State>,
+    binding: web::Data<Binding>,
+) -> Result<()> {
+    let ctx = logging::Context {
+        event_id: Some(ce.id().to_string()),
+        ..logging::Context::default()
+    };
+    logging::scope(ctx, async move {
+        let sender = state.sender.clone();
+        match process(ce, state, binding).await? {
+            Some(reply) => sender.send(reply).await,
+            None => Ok(()),
+        }
+    })
+    .await
+}
+
+async fn process(
+    ce: Event,
+    state: web::Data<State>,
+    binding: web::Data<Binding>,
+) -> Result<Option<Event>> {
+    logging::event(&ce);
+    log::info!(
+        "Received {} event {} from {}",
+        ce.ty(),
+        ce.id(),
+        ce.source()
+    );
+
+    let ty = ce.ty().to_string();
+    if let Err(rejection) = verify(&state.config.events, &ce) {
+        log::warn!(
+            "Rejected {} event from {}: invalid {}",
+            ty,
+            ce.source(),
+            rejection.reason()
+        );
+        metrics::event_rejected(&ty, rejection.reason());
+        return Err(error::ErrorForbidden("event origin not verified"));
+    }
+    let res = trace::handle_event(ce, |ce| handle(ce, state, binding)).await;
+    metrics::event_received(&ty, res.is_ok());
+    res
+}
+
+async fn handle(
+    ce: Event,
+    state: web::Data<State>,
+    binding: web::Data<Binding>,
+) -> Result<Option<Event>> {
+    let svc = service::new(state, binding, &ce).await?;
+    service::HANDLERS.dispatch(svc, ce).await
 }
 
 #[cfg(test)]
 mod tests {
+    use std::sync::{
+        Arc,
+        Mutex,
+    };
+
     use super::*;
+    use crate::app::config::{
+        ContentMode,
+        Kafka,
+        Reply,
+    };
+    use crate::drivers::{
+        audit::Record,
+        entity::Driver,
+        repository::{
+            self,
+            memory::InMemory,
+        },
+    };
+    use crate::support::{
+        cloudevents::{
+            Publisher,
+            Sender,
+        },
+        id::ID,
+        kafka::tests::{
+            eventually,
+            stop,
+        },
+    };
     use actix_web::{
         test,
         App,
+        HttpRequest,
+        HttpServer,
     };
+    use cloudevents::{
+        AttributesWriter,
+        EventBuilder,
+        EventBuilderV10,
+    };
+    use rdkafka::mocking::MockCluster;
 
     #[actix_web::test]
     async fn post() {
@@ -54,4 +305,651 @@ mod tests {
         let body = test::read_body(resp).await;
         assert_eq!("Thanks world!\n", body);
     }
+
+    fn calculate_fee(id: &str, driver_id: i64) -> Event {
+        EventBuilderV10::new()
+            .id(id)
+            .source("usvc://cabs/legacy")
+            .ty("cabs.drivers.calculate-fee")
+            .data(
+                "application/json",
+                serde_json::json!({
+                    "driver-id": driver_id,
+                    "transit-price": 1000,
+                }),
+            )
+            .build()
+            .unwrap()
+    }
+
+    #[actix_web::test]
+    async fn batch() {
+        let sent = Arc::new(Mutex::new(vec![]));
+        let sink = {
+            let sent = sent.clone();
+            HttpServer::new(move || {
+                let sent = sent.clone();
+                App::new().route(
+                    "/",
+                    web::post().to(move |req: HttpRequest, ce: Event| {
+                        let ct =
+                            req.headers().get(header::CONTENT_TYPE).cloned();
+                        sent.lock().unwrap().push((ct, ce));
+                        async { HttpResponse::Accepted().finish() }
+                    }),
+                )
+            })
+            .bind(("127.0.0.1", 0))
+            .unwrap()
+        };
+        let addr = sink.addrs()[0];
+        let sink = sink.run();
+        let handle = sink.handle();
+        actix_web::rt::spawn(sink);
+
+        let mut state = State::default();
+        state.config.knative.sink = format!("http://{}/", addr);
+        state.config.knative.content = ContentMode::Structured;
+        state.sender = Sender::new(&state.config.knative, None);
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .app_data(web::Data::new(Binding::in_memory(
+                    InMemory::default(),
+                )))
+                .service(routes())
+                .service(crate::drivers::routes()),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/drivers")
+            .set_json(serde_json::json!({
+                "name": "John",
+                "surname": "Doe",
+                "fee": {"type": "flat", "amount": 10},
+            }))
+            .to_request();
+        let drv: ID<Driver> = test::call_and_read_body_json(&app, req).await;
+
+        let mut unknown = calculate_fee("3", drv.id.int());
+        unknown.set_type("cabs.drivers.unknown");
+        let batch = vec![
+            calculate_fee("1", drv.id.int()),
+            calculate_fee("2", drv.id.int() + 1),
+            unknown,
+        ];
+        let req = test::TestRequest::post()
+            .uri("/")
+            .insert_header((header::CONTENT_TYPE, BATCH))
+            .set_payload(serde_json::to_vec(&batch).unwrap())
+            .to_request();
+        let outcomes: serde_json::Value =
+            test::call_and_read_body_json(&app, req).await;
+        assert_eq!(outcomes[0], serde_json::json!({"id": "1", "status": 200}));
+        assert_eq!(outcomes[1]["status"], 404);
+        assert_eq!(outcomes[2]["status"], 400);
+
+        let req = test::TestRequest::post()
+            .uri("/")
+            .insert_header((header::CONTENT_TYPE, BATCH))
+            .set_payload("{}")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 400);
+
+        handle.stop(false).await;
+        let sent = sent.lock().unwrap();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].0.as_ref().unwrap(), "application/cloudevents+json");
+        assert_eq!(sent[0].1.ty(), "cabs.drivers.driver-fee");
+    }
+
+    #[actix_web::test]
+    async fn replies() {
+        let mut state = State::default();
+        state.config.knative.sink = "http://127.0.0.1:1/".to_string();
+        state.config.knative.reply = Reply::Response;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .app_data(web::Data::new(Binding::in_memory(
+                    InMemory::default(),
+                )))
+                .service(routes())
+                .service(crate::drivers::routes()),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/drivers")
+            .set_json(serde_json::json!({
+                "name": "John",
+                "surname": "Doe",
+                "fee": {"type": "flat", "amount": 10},
+            }))
+            .to_request();
+        let drv: ID<Driver> = test::call_and_read_body_json(&app, req).await;
+
+        let req = test::TestRequest::post()
+            .uri("/")
+            .insert_header((
+                header::CONTENT_TYPE,
+                "application/cloudevents+json",
+            ))
+            .set_payload(
+                serde_json::to_vec(&calculate_fee("1", drv.id.int())).unwrap(),
+            )
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+        assert_eq!(
+            resp.headers().get("ce-type").unwrap(),
+            "cabs.drivers.driver-fee"
+        );
+        assert_eq!(
+            resp.headers().get("ce-dataschema").unwrap(),
+            "http://localhost:8081/schemas/cabs.drivers.driver-fee"
+        );
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["driver-id"], drv.id.int());
+        assert_eq!(body["fee"], 990);
+
+        let req = test::TestRequest::post()
+            .uri("/")
+            .insert_header((header::CONTENT_TYPE, BATCH))
+            .set_payload(
+                serde_json::to_vec(&[calculate_fee("2", drv.id.int())])
+                    .unwrap(),
+            )
+            .to_request();
+        let outcomes: serde_json::Value =
+            test::call_and_read_body_json(&app, req).await;
+        assert_eq!(outcomes[0]["status"], 200);
+        assert_eq!(outcomes[0]["event"]["type"], "cabs.drivers.driver-fee");
+        assert_eq!(outcomes[0]["event"]["data"]["fee"], 990);
+    }
+
+    #[actix_web::test]
+    async fn verifies_data_as_received() {
+        use hmac::{
+            Hmac,
+            Mac,
+        };
+        use sha2::Sha256;
+
+        let mut state = State::default();
+        state.config.knative.reply = Reply::Response;
+        state.config.events.secret = Some("s3cr3t".to_string());
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .app_data(web::Data::new(Binding::in_memory(
+                    InMemory::default(),
+                )))
+                .service(routes())
+                .service(crate::drivers::routes()),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/drivers")
+            .set_json(serde_json::json!({"name": "John", "surname": "Doe"}))
+            .to_request();
+        let drv: ID<Driver> = test::call_and_read_body_json(&app, req).await;
+
+        // Not as it would be serialized anew, keys unsorted.
+        let data = format!(
+            r#"{{ "transit-price": 1000, "driver-id": {} }}"#,
+            drv.id.int()
+        );
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"s3cr3t").unwrap();
+        mac.update(data.as_bytes());
+        let sig =
+            format!("sha256={}", hex::encode(mac.finalize().into_bytes()));
+        let post = |sig: &str| {
+            test::TestRequest::post()
+                .uri("/")
+                .insert_header(("ce-specversion", "1.0"))
+                .insert_header(("ce-id", "1"))
+                .insert_header(("ce-source", "usvc://cabs/legacy"))
+                .insert_header(("ce-type", "cabs.drivers.calculate-fee"))
+                .insert_header(("ce-signature", sig))
+                .insert_header((header::CONTENT_TYPE, "application/json"))
+                .set_payload(data.clone())
+                .to_request()
+        };
+        let resp = test::call_service(&app, post(&sig)).await;
+        assert_eq!(resp.status(), 200);
+        let resp = test::call_service(&app, post("sha256=00")).await;
+        assert_eq!(resp.status(), 403);
+    }
+
+    #[actix_web::test]
+    async fn replies_in_version() {
+        let mut state = State::default();
+        state.config.knative.reply = Reply::Response;
+        state.config.knative.content = ContentMode::Structured;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .app_data(web::Data::new(Binding::in_memory(
+                    InMemory::default(),
+                )))
+                .service(routes())
+                .service(crate::drivers::routes()),
+        )
+        .await;
+
+        let req = test::TestRequest::post()
+            .uri("/drivers")
+            .set_json(serde_json::json!({
+                "name": "John",
+                "surname": "Doe",
+                "fee": {"type": "flat", "amount": 10},
+            }))
+            .to_request();
+        let drv: ID<Driver> = test::call_and_read_body_json(&app, req).await;
+
+        let mut ce = calculate_fee("1", drv.id.int());
+        ce.set_type("cabs.drivers.calculate-fee.v2");
+        ce.set_data(
+            "application/json",
+            serde_json::json!({
+                "driver-id": drv.id.int(),
+                "transit-price": 1000,
+                "currency": "EUR",
+                "transit-time": "2023-05-01T12:00:00Z",
+            }),
+        );
+        let req = test::TestRequest::post()
+            .uri("/")
+            .insert_header((
+                header::CONTENT_TYPE,
+                "application/cloudevents+json",
+            ))
+            .set_payload(serde_json::to_vec(&ce).unwrap())
+            .to_request();
+        let reply: serde_json::Value =
+            test::call_and_read_body_json(&app, req).await;
+        assert_eq!(reply["type"], "cabs.drivers.driver-fee.v2");
+        assert!(reply["dataschema"]
+            .as_str()
+            .unwrap()
+            .ends_with("/schemas/cabs.drivers.driver-fee.v2"));
+        assert_eq!(reply["data"]["fee"], 990);
+        assert_eq!(reply["data"]["currency"], "EUR");
+
+        // Those of the first version are answered in it, without currency.
+        let req = test::TestRequest::post()
+            .uri("/")
+            .insert_header((
+                header::CONTENT_TYPE,
+                "application/cloudevents+json",
+            ))
+            .set_payload(
+                serde_json::to_vec(&calculate_fee("2", drv.id.int())).unwrap(),
+            )
+            .to_request();
+        let reply: serde_json::Value =
+            test::call_and_read_body_json(&app, req).await;
+        assert_eq!(reply["type"], "cabs.drivers.driver-fee");
+        assert_eq!(reply["data"]["fee"], 990);
+        assert!(reply["data"].get("currency").is_none());
+    }
+
+    #[actix_web::test]
+    async fn commands() {
+        let mut state = State::default();
+        state.config.knative.reply = Reply::Response;
+        state.config.knative.content = ContentMode::Structured;
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(state))
+                .app_data(web::Data::new(Binding::in_memory(
+                    InMemory::default(),
+                )))
+                .service(routes()),
+        )
+        .await;
+        let command = |id: &str, ty: &str, data: serde_json::Value| {
+            let ce = EventBuilderV10::new()
+                .id(id)
+                .source("usvc://cabs/legacy")
+                .ty(ty)
+                .subject("transit-7")
+                .data("application/json", data)
+                .build()
+                .unwrap();
+            test::TestRequest::post()
+                .uri("/")
+                .insert_header((
+                    header::CONTENT_TYPE,
+                    "application/cloudevents+json",
+                ))
+                .set_payload(serde_json::to_vec(&ce).unwrap())
+                .to_request()
+        };
+
+        let req = command(
+            "1",
+            "cabs.drivers.register",
+            serde_json::json!({"name": "John", "surname": "Doe"}),
+        );
+        let reply: serde_json::Value =
+            test::call_and_read_body_json(&app, req).await;
+        assert_eq!(reply["type"], "cabs.drivers.registered");
+        assert_eq!(reply["subject"], "transit-7");
+        assert_eq!(reply["correlationid"], "1");
+        assert_eq!(reply["data"]["status"], "Inactive");
+        assert_eq!(reply["data"]["type"], "Candidate");
+        let id = reply["data"]["driver-id"].clone();
+
+        // Without a license, the driver can't be activated.
+        let req = command(
+            "2",
+            "cabs.drivers.activate",
+            serde_json::json!({"driver-id": id}),
+        );
+        let reply: serde_json::Value =
+            test::call_and_read_body_json(&app, req).await;
+        assert_eq!(reply["type"], "cabs.drivers.command-failed");
+        assert_eq!(reply["correlationid"], "2");
+        assert_eq!(reply["data"]["command"], "cabs.drivers.activate");
+        assert_eq!(reply["data"]["status"], 400);
+
+        let req = command(
+            "3",
+            "cabs.drivers.update",
+            serde_json::json!({
+                "driver-id": id,
+                "name": "John",
+                "surname": "Doe",
+                "license": {"number": "FARME100165AB5EW"},
+            }),
+        );
+        let reply: serde_json::Value =
+            test::call_and_read_body_json(&app, req).await;
+        assert_eq!(reply["type"], "cabs.drivers.updated");
+
+        for (ty, replied, status, typ) in [
+            ("activate", "activated", "Active", "Candidate"),
+            ("graduate", "graduated", "Active", "Regular"),
+            ("deactivate", "deactivated", "Inactive", "Regular"),
+        ] {
+            let req = command(
+                "4",
+                &format!("cabs.drivers.{}", ty),
+                serde_json::json!({"driver-id": id}),
+            );
+            let reply: serde_json::Value =
+                test::call_and_read_body_json(&app, req).await;
+            assert_eq!(reply["type"], format!("cabs.drivers.{}", replied));
+            assert_eq!(reply["data"]["driver-id"], id);
+            assert_eq!(reply["data"]["status"], status);
+            assert_eq!(reply["data"]["type"], typ);
+        }
+
+        let req = command(
+            "5",
+            "cabs.drivers.graduate",
+            serde_json::json!({"driver-id": 999}),
+        );
+        let reply: serde_json::Value =
+            test::call_and_read_body_json(&app, req).await;
+        assert_eq!(reply["type"], "cabs.drivers.command-failed");
+        assert_eq!(reply["data"]["status"], 404);
+
+        // Commands which can't be parsed are refused as other events.
+        let req = command(
+            "6",
+            "cabs.drivers.register",
+            serde_json::json!({"name": "John"}),
+        );
+        assert_eq!(test::call_service(&app, req).await.status(), 400);
+    }
+
+    #[actix_web::test]
+    async fn authorizes_commands() {
+        use crate::app::auth::{
+            self,
+            Auth,
+            Role,
+        };
+        use crate::app::config::AuthConfig;
+        use std::collections::{
+            HashMap,
+            HashSet,
+        };
+
+        let app = |sources: &[&str]| {
+            let mut state = State::default();
+            state.config.knative.reply = Reply::Response;
+            state.config.knative.content = ContentMode::Structured;
+            state.auth = Auth::new(&AuthConfig {
+                api_keys: auth::parse_api_keys("k-hr:hr").unwrap(),
+                ..AuthConfig::default()
+            })
+            .unwrap();
+            let sources: HashSet<String> =
+                sources.iter().map(|s| s.to_string()).collect();
+            if !sources.is_empty() {
+                state.config.events.sources =
+                    HashMap::from([("*".to_string(), sources)]);
+            }
+            state.config.events.roles = HashMap::from([(
+                "usvc://cabs/hr".to_string(),
+                HashSet::from([Role::Hr]),
+            )]);
+            test::init_service(
+                App::new()
+                    .app_data(web::Data::new(state))
+                    .app_data(web::Data::new(Binding::in_memory(
+                        InMemory::default(),
+                    )))
+                    .service(routes()),
+            )
+        };
+        let command = |source: &str, data: serde_json::Value| {
+            let ce = EventBuilderV10::new()
+                .id("1")
+                .source(source)
+                .ty("cabs.drivers.register")
+                .data("application/json", data)
+                .build()
+                .unwrap();
+            test::TestRequest::post()
+                .uri("/")
+                .insert_header((
+                    header::CONTENT_TYPE,
+                    "application/cloudevents+json",
+                ))
+                .set_payload(serde_json::to_vec(&ce).unwrap())
+                .to_request()
+        };
+        let john = serde_json::json!({"name": "John", "surname": "Doe"});
+        let paid = serde_json::json!({
+            "name": "John",
+            "surname": "Doe",
+            "fee": {"type": "flat", "amount": 10},
+        });
+
+        // Without signatures nor allowed sources, no origin is verified.
+        let open = app(&[]).await;
+        let req = command("usvc://cabs/hr", john.clone());
+        let reply: serde_json::Value =
+            test::call_and_read_body_json(&open, req).await;
+        assert_eq!(reply["type"], "cabs.drivers.command-failed");
+        assert_eq!(reply["data"]["status"], 401);
+
+        let allowed = app(&["usvc://cabs/hr", "usvc://cabs/legacy"]).await;
+        let req = command("usvc://cabs/hr", john.clone());
+        let reply: serde_json::Value =
+            test::call_and_read_body_json(&allowed, req).await;
+        assert_eq!(reply["type"], "cabs.drivers.registered");
+
+        let req = command("usvc://cabs/hr", paid);
+        let reply: serde_json::Value =
+            test::call_and_read_body_json(&allowed, req).await;
+        assert_eq!(reply["type"], "cabs.drivers.command-failed");
+        assert_eq!(reply["data"]["status"], 403);
+
+        let req = command("usvc://cabs/legacy", john);
+        let reply: serde_json::Value =
+            test::call_and_read_body_json(&allowed, req).await;
+        assert_eq!(reply["type"], "cabs.drivers.command-failed");
+        assert_eq!(reply["data"]["status"], 403);
+
+        // Changes in the legacy app are only projected from it, verified.
+        let changed = || {
+            let ce = EventBuilderV10::new()
+                .id("2")
+                .source("usvc://cabs/legacy")
+                .ty("cabs.legacy.driver-changed")
+                .data(
+                    "application/json",
+                    serde_json::json!({
+                        "driver-id": 7,
+                        "version": 1,
+                        "updated-at": "2023-05-01T10:00:00Z",
+                        "name": "John",
+                        "surname": "Doe",
+                    }),
+                )
+                .build()
+                .unwrap();
+            test::TestRequest::post()
+                .uri("/")
+                .insert_header((
+                    header::CONTENT_TYPE,
+                    "application/cloudevents+json",
+                ))
+                .set_payload(serde_json::to_vec(&ce).unwrap())
+                .to_request()
+        };
+        let resp = test::call_service(&open, changed()).await;
+        assert_eq!(resp.status(), 401);
+        let resp = test::call_service(&allowed, changed()).await;
+        assert_eq!(resp.status(), 200);
+    }
+
+    #[actix_web::test]
+    async fn projects_legacy_changes() {
+        let repo = InMemory::default();
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(State::default()))
+                .app_data(web::Data::new(Binding::in_memory(repo.clone())))
+                .service(routes()),
+        )
+        .await;
+
+        let ce = EventBuilderV10::new()
+            .id("1")
+            .source("usvc://cabs/legacy")
+            .ty("cabs.legacy.driver-changed")
+            .data(
+                "application/json",
+                serde_json::json!({
+                    "driver-id": 42,
+                    "version": 3,
+                    "updated-at": "2023-05-01T10:00:00Z",
+                    "name": "John",
+                    "surname": "Doe",
+                    "type": "Regular",
+                }),
+            )
+            .build()
+            .unwrap();
+        let req = test::TestRequest::post()
+            .uri("/")
+            .insert_header((
+                header::CONTENT_TYPE,
+                "application/cloudevents+json",
+            ))
+            .set_payload(serde_json::to_vec(&ce).unwrap())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), 200);
+
+        let drv = repo.drivers.lock().unwrap()[&42].clone();
+        assert_eq!(drv.name, "John");
+        assert_eq!(drv.legacy.unwrap().version, 3);
+    }
+
+    #[actix_web::test]
+    async fn consumes_from_kafka() {
+        let cluster = MockCluster::new(1).unwrap();
+        cluster.create_topic("cabs.legacy", 1, 1).unwrap();
+        cluster.create_topic("cabs.drivers", 1, 1).unwrap();
+        let cfg = Kafka {
+            brokers:     cluster.bootstrap_servers(),
+            topic:       "cabs.drivers".to_string(),
+            topics:      vec!["cabs.legacy".to_string()],
+            group:       "drivers".to_string(),
+            deliveries:  5,
+            dead_letter: "events-received-dead-letter".to_string(),
+        };
+
+        let mut state = State::default();
+        state.config.knative.transport = Transport::Kafka(cfg.clone());
+        state.sender = Sender::new(&state.config.knative, None);
+        let mut repo = InMemory::default();
+        let drv = repository::create(
+            &mut repo,
+            &state.ids,
+            &state.clock,
+            serde_json::from_value(serde_json::json!({
+                "name": "John",
+                "surname": "Doe",
+                "fee": {"type": "flat", "amount": 10},
+            }))
+            .unwrap(),
+            |id, drv| Record::by("test", &state.clock, "add", id, None, drv),
+        )
+        .await
+        .unwrap();
+        let consumer = subscribe(&state, Binding::in_memory(repo))
+            .unwrap()
+            .unwrap();
+
+        let sent = Arc::new(Mutex::new(vec![]));
+        let fees = {
+            let sent = sent.clone();
+            let cfg = Kafka {
+                topics: vec!["cabs.drivers".to_string()],
+                group: "legacy".to_string(),
+                ..cfg.clone()
+            };
+            let delivery = &state.config.knative.delivery;
+            kafka::subscribe(&cfg, delivery, Store::None, move |ce| {
+                sent.lock().unwrap().push(ce);
+                async { Ok(()) }
+            })
+            .unwrap()
+        };
+
+        let legacy = kafka::Producer::new(
+            &Kafka {
+                topic: "cabs.legacy".to_string(),
+                ..cfg
+            },
+            ContentMode::Binary,
+            &state.config.knative.delivery,
+        )
+        .unwrap();
+        legacy
+            .publish(calculate_fee("1", drv.id.int()))
+            .await
+            .unwrap();
+
+        eventually(|| !sent.lock().unwrap().is_empty()).await;
+        stop(consumer).await;
+        stop(fees).await;
+        let sent = sent.lock().unwrap();
+        assert_eq!(sent[0].ty(), "cabs.drivers.driver-fee");
+        let data = serde_json::Value::try_from(sent[0].data().unwrap().clone())
+            .unwrap();
+        assert_eq!(data["fee"], 990);
+    }
 }
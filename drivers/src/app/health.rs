@@ -0,0 +1,351 @@
+use std::{
+    future::Future,
+    sync::{
+        atomic::{
+            AtomicU64,
+            Ordering,
+        },
+        Arc,
+        Mutex,
+        Weak,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use actix_web::{
+    dev::HttpServiceFactory,
+    get,
+    rt,
+    web,
+    HttpResponse,
+};
+use serde::Serialize;
+
+use crate::{
+    app::config::State,
+    support::pool::{
+        Connection,
+        PoolStats,
+    },
+};
+
+pub fn routes() -> impl HttpServiceFactory + 'static {
+    web::scope("/health").service(ready).service(live)
+}
+
+#[get("/ready")]
+async fn ready(state: web::Data<State>) -> HttpResponse {
+    readiness(&state).await.into()
+}
+
+#[get("/live")]
+async fn live(state: web::Data<State>) -> HttpResponse {
+    liveness(&state).into()
+}
+
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub status: Status,
+    pub checks: Vec<Check>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pool:   Option<PoolStats>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Check {
+    pub name:       &'static str,
+    pub status:     Status,
+    pub latency_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error:      Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Status {
+    Up,
+    Down,
+}
+
+impl Report {
+    fn new(checks: Vec<Check>) -> Self {
+        let status = match checks.iter().all(|c| c.status == Status::Up) {
+            true => Status::Up,
+            false => Status::Down,
+        };
+        Report {
+            status,
+            checks,
+            pool: None,
+        }
+    }
+
+    pub fn failed(&self) -> Vec<&Check> {
+        self.checks
+            .iter()
+            .filter(|c| c.status == Status::Down)
+            .collect()
+    }
+}
+
+impl From<Report> for HttpResponse {
+    fn from(report: Report) -> Self {
+        match report.status {
+            Status::Up => HttpResponse::Ok().json(&report),
+            Status::Down => HttpResponse::ServiceUnavailable().json(&report),
+        }
+    }
+}
+
+/// Checks whether the dependencies needed to serve traffic are available.
+pub async fn readiness(state: &State) -> Report {
+    let timeout = state.config.health.timeout;
+    let mut report = Report::new(vec![
+        check("redis", timeout, ping(state)).await,
+        check("redis-json", timeout, json_module(state)).await,
+        check("sink", timeout, sink(state)).await,
+    ]);
+    report.pool = state.db.pool.as_ref().map(|p| p.stats());
+    report
+}
+
+/// Checks whether every worker runtime is still making progress.
+pub fn liveness(state: &State) -> Report {
+    let stall = state.config.health.stall;
+    let oldest = state.liveness.oldest();
+    let error = match oldest > stall {
+        true => Some(format!(
+            "worker runtime stalled for {}ms",
+            oldest.as_millis()
+        )),
+        false => None,
+    };
+    Report::new(vec![Check {
+        name: "runtime",
+        status: match error {
+            None => Status::Up,
+            Some(_) => Status::Down,
+        },
+        latency_ms: millis(oldest),
+        error,
+    }])
+}
+
+async fn check<F>(name: &'static str, timeout: Duration, f: F) -> Check
+where
+    F: Future<Output = Result<(), String>>,
+{
+    let start = Instant::now();
+    let res = match rt::time::timeout(timeout, f).await {
+        Ok(res) => res,
+        Err(_) => Err(format!("timed out after {}ms", timeout.as_millis())),
+    };
+    let latency_ms = millis(start.elapsed());
+    match res {
+        Ok(()) => Check {
+            name,
+            status: Status::Up,
+            latency_ms,
+            error: None,
+        },
+        Err(err) => Check {
+            name,
+            status: Status::Down,
+            latency_ms,
+            error: Some(err),
+        },
+    }
+}
+
+async fn connect(state: &State) -> Result<Connection, String> {
+    let pool = state.db.pool.as_ref().ok_or("no redis client configured")?;
+    pool.get().await.map_err(|err| err.to_string())
+}
+
+async fn ping(state: &State) -> Result<(), String> {
+    let mut conn = connect(state).await?;
+    let pong: String = redis::cmd("PING")
+        .query_async(&mut conn)
+        .await
+        .map_err(|err| err.to_string())?;
+    match pong.as_str() {
+        "PONG" => Ok(()),
+        _ => Err(format!("unexpected PING reply: {}", pong)),
+    }
+}
+
+async fn json_module(state: &State) -> Result<(), String> {
+    let mut conn = connect(state).await?;
+    let modules: redis::Value = redis::cmd("MODULE")
+        .arg("LIST")
+        .query_async(&mut conn)
+        .await
+        .map_err(|err| err.to_string())?;
+    match contains(&modules, "rejson") {
+        true => Ok(()),
+        false => Err("RedisJSON module is not loaded".to_string()),
+    }
+}
+
+async fn sink(state: &State) -> Result<(), String> {
+    let sink = &state.config.knative.sink;
+    let url = reqwest::Url::parse(sink)
+        .map_err(|err| format!("invalid sink {}: {}", sink, err))?;
+    match (url.scheme(), url.has_host()) {
+        ("http" | "https", true) => Ok(()),
+        _ => Err(format!("invalid sink {}: not an HTTP URL", sink)),
+    }
+}
+
+fn contains(val: &redis::Value, needle: &str) -> bool {
+    match val {
+        redis::Value::Data(data) => {
+            String::from_utf8_lossy(data).eq_ignore_ascii_case(needle)
+        }
+        redis::Value::Status(s) => s.eq_ignore_ascii_case(needle),
+        redis::Value::Bulk(vals) => vals.iter().any(|v| contains(v, needle)),
+        _ => false,
+    }
+}
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
+
+/// Keeps the heartbeats of all the worker runtimes.
+#[derive(Clone, Debug)]
+pub struct Liveness {
+    started: Instant,
+    beats:   Arc<Mutex<Vec<Weak<AtomicU64>>>>,
+}
+
+impl Default for Liveness {
+    fn default() -> Self {
+        Liveness {
+            started: Instant::now(),
+            beats:   Arc::new(Mutex::new(vec![])),
+        }
+    }
+}
+
+impl Liveness {
+    /// Spawns a heartbeat on the current runtime. The heartbeat stops being
+    /// tracked once the runtime is shut down.
+    pub fn watch(&self, every: Duration) {
+        let beat = Arc::new(AtomicU64::new(self.elapsed()));
+        self.beats.lock().unwrap().push(Arc::downgrade(&beat));
+        let liveness = self.clone();
+        rt::spawn(async move {
+            let mut interval = rt::time::interval(every);
+            loop {
+                interval.tick().await;
+                beat.store(liveness.elapsed(), Ordering::Relaxed);
+            }
+        });
+    }
+
+    /// Age of the oldest heartbeat of still running runtimes.
+    fn oldest(&self) -> Duration {
+        let now = self.elapsed();
+        let mut beats = self.beats.lock().unwrap();
+        beats.retain(|b| b.strong_count() > 0);
+        let oldest = beats
+            .iter()
+            .filter_map(Weak::upgrade)
+            .map(|b| b.load(Ordering::Relaxed))
+            .min()
+            .unwrap_or(now);
+        Duration::from_millis(now.saturating_sub(oldest))
+    }
+
+    fn elapsed(&self) -> u64 {
+        self.started.elapsed().as_millis() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        http::StatusCode,
+        test::{
+            call_service,
+            init_service,
+            read_body_json,
+            TestRequest,
+        },
+        App,
+    };
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn ready_without_redis() {
+        let mut state = State::default();
+        state.db.pool = None;
+        let app = init_service(
+            App::new().app_data(web::Data::new(state)).service(routes()),
+        )
+        .await;
+        let req = TestRequest::get().uri("/health/ready").to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let body: serde_json::Value = read_body_json(resp).await;
+        assert_eq!(body["status"], "down");
+        assert_eq!(body["checks"][0]["name"], "redis");
+        assert_eq!(body["checks"][0]["status"], "down");
+        assert_eq!(body["checks"][0]["error"], "no redis client configured");
+        assert_eq!(body["checks"][2]["name"], "sink");
+        assert_eq!(body["checks"][2]["status"], "up");
+    }
+
+    #[actix_web::test]
+    async fn invalid_sink() {
+        let mut state = State::default();
+        state.config.knative.sink = "localhost:31111".to_string();
+        let check = check("sink", Duration::from_secs(1), sink(&state)).await;
+        assert_eq!(check.status, Status::Down);
+    }
+
+    #[actix_web::test]
+    async fn live() {
+        let state = State::default();
+        state.liveness.watch(Duration::from_millis(10));
+        let app = init_service(
+            App::new().app_data(web::Data::new(state)).service(routes()),
+        )
+        .await;
+        let req = TestRequest::get().uri("/health/live").to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn live_stalled() {
+        let mut state = State::default();
+        state.config.health.stall = Duration::from_millis(50);
+        state.liveness.watch(Duration::from_secs(60));
+        // Blocks the only runtime, so the heartbeat can't be updated.
+        std::thread::sleep(Duration::from_millis(100));
+
+        let report = liveness(&state);
+        assert_eq!(report.status, Status::Down);
+        assert_eq!(report.failed()[0].name, "runtime");
+    }
+
+    #[test]
+    fn json_module_listed() {
+        let modules = redis::Value::Bulk(vec![redis::Value::Bulk(vec![
+            redis::Value::Data(b"name".to_vec()),
+            redis::Value::Data(b"ReJSON".to_vec()),
+            redis::Value::Data(b"ver".to_vec()),
+            redis::Value::Int(20609),
+        ])]);
+        assert!(contains(&modules, "rejson"));
+        assert!(!contains(&modules, "search"));
+    }
+}
@@ -0,0 +1,243 @@
+use std::{
+    cell::RefCell,
+    future::Future,
+    io::Write,
+    time::Instant,
+};
+
+use actix_web::{
+    body::MessageBody,
+    dev::{
+        ServiceRequest,
+        ServiceResponse,
+    },
+    http::header::{
+        HeaderName,
+        HeaderValue,
+    },
+    middleware::Next,
+    Error,
+};
+use chrono::{
+    SecondsFormat,
+    Utc,
+};
+use cloudevents::{
+    AttributesReader,
+    Event,
+};
+use serde::Serialize;
+
+use crate::support::id::Identifier;
+
+pub const REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+tokio::task_local! {
+    static CONTEXT: RefCell<Context>;
+}
+
+/// Correlates the log lines written while handling a request.
+#[derive(Clone, Debug, Default, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Context {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event_id:   Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub route:      Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub driver_id:  Option<String>,
+}
+
+/// Sets the driver the current request is about.
+pub fn driver(id: &Identifier) {
+    update(|ctx| ctx.driver_id = Some(id.to_string()));
+}
+
+/// Sets the CloudEvent the current request carries.
+pub fn event(ce: &Event) {
+    update(|ctx| ctx.event_id = Some(ce.id().to_string()));
+}
+
+/// Runs the future within the correlation context, e.g. of an event consumed
+/// outside of any request.
+pub async fn scope<F: Future>(ctx: Context, f: F) -> F::Output {
+    CONTEXT.scope(RefCell::new(ctx), f).await
+}
+
+pub fn current() -> Option<Context> {
+    CONTEXT.try_with(|ctx| ctx.borrow().clone()).ok()
+}
+
+fn update(f: impl FnOnce(&mut Context)) {
+    let _ = CONTEXT.try_with(|ctx| f(&mut ctx.borrow_mut()));
+}
+
+/// Middleware keeping the correlation context of the request, and logging
+/// the request once handled. The request ID is taken from the `X-Request-ID`
+/// header, or generated, and returned in the response.
+pub async fn middleware(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let request_id = req
+        .headers()
+        .get(REQUEST_ID)
+        .and_then(|v| v.to_str().ok())
+        .map(String::from)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    let ctx = Context {
+        request_id: Some(request_id.clone()),
+        route: req.match_pattern(),
+        ..Context::default()
+    };
+
+    CONTEXT
+        .scope(RefCell::new(ctx), async move {
+            let start = Instant::now();
+            let line = format!(
+                "{} \"{} {} {:?}\"",
+                req.peer_addr().map(|a| a.to_string()).unwrap_or_default(),
+                req.method(),
+                req.uri(),
+                req.version()
+            );
+            let mut res = next.call(req).await?;
+            log::info!(
+                target: "access",
+                "{} {} {:.6}",
+                line,
+                res.status().as_u16(),
+                start.elapsed().as_secs_f64()
+            );
+            if let Ok(val) = HeaderValue::from_str(&request_id) {
+                res.headers_mut().insert(REQUEST_ID, val);
+            }
+            Ok(res)
+        })
+        .await
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    ts:      String,
+    level:   &'a str,
+    target:  &'a str,
+    message: String,
+    #[serde(flatten)]
+    context: Option<Context>,
+}
+
+/// Formats the log record as a single JSON line, with the correlation
+/// context of the current request, if any.
+pub fn json(
+    buf: &mut env_logger::fmt::Formatter,
+    record: &log::Record,
+) -> std::io::Result<()> {
+    let line = to_line(record, current());
+    let json = serde_json::to_string(&line)?;
+    writeln!(buf, "{}", json)
+}
+
+fn to_line<'a>(record: &'a log::Record, context: Option<Context>) -> Line<'a> {
+    Line {
+        ts: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        level: record.level().as_str(),
+        target: record.target(),
+        message: record.args().to_string(),
+        context,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        test::{
+            call_service,
+            init_service,
+            TestRequest,
+        },
+        web,
+        App,
+        HttpResponse,
+    };
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn correlates_request() {
+        let app = init_service(
+            App::new()
+                .wrap(actix_web::middleware::from_fn(middleware))
+                .route(
+                    "/drivers/{id}",
+                    web::get().to(|| async {
+                        driver(&Identifier::from(100));
+                        HttpResponse::Ok().json(current())
+                    }),
+                ),
+        )
+        .await;
+
+        let req = TestRequest::get()
+            .uri("/drivers/100")
+            .insert_header((REQUEST_ID, "abc-123"))
+            .to_request();
+        let res = call_service(&app, req).await;
+        assert_eq!(
+            res.headers().get(REQUEST_ID),
+            Some(&HeaderValue::from_static("abc-123"))
+        );
+        let ctx: serde_json::Value = actix_web::test::read_body_json(res).await;
+        assert_eq!(
+            ctx,
+            serde_json::json!({
+                "request-id": "abc-123",
+                "route": "/drivers/{id}",
+                "driver-id": "gk",
+            })
+        );
+
+        let req = TestRequest::get().uri("/drivers/100").to_request();
+        let res = call_service(&app, req).await;
+        let id = res.headers().get(REQUEST_ID).unwrap().to_str().unwrap();
+        assert_eq!(id.len(), 36);
+    }
+
+    #[actix_web::test]
+    async fn scopes_context() {
+        let ctx = Context {
+            event_id: Some("42".to_string()),
+            ..Context::default()
+        };
+        let curr = scope(ctx, async { current() }).await.unwrap();
+        assert_eq!(curr.event_id.as_deref(), Some("42"));
+        assert!(current().is_none());
+    }
+
+    #[test]
+    fn json_line() {
+        let args = format_args!("calculated fee");
+        let record = log::Record::builder()
+            .args(args)
+            .level(log::Level::Info)
+            .target("drivers::service")
+            .build();
+        let ctx = Context {
+            event_id: Some("42".to_string()),
+            ..Context::default()
+        };
+
+        let line = serde_json::to_value(to_line(&record, Some(ctx))).unwrap();
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["target"], "drivers::service");
+        assert_eq!(line["message"], "calculated fee");
+        assert_eq!(line["event-id"], "42");
+        assert!(line.get("request-id").is_none());
+        assert!(line["ts"].as_str().unwrap().ends_with('Z'));
+
+        let line = serde_json::to_value(to_line(&record, None)).unwrap();
+        assert!(line.get("event-id").is_none());
+    }
+}
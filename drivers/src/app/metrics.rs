@@ -0,0 +1,315 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use actix_web::{
+    body::MessageBody,
+    dev::{
+        ServiceRequest,
+        ServiceResponse,
+    },
+    get,
+    middleware::Next,
+    rt,
+    web,
+    Error,
+    HttpResponse,
+    Result,
+};
+use prometheus::{
+    register_histogram,
+    register_histogram_vec,
+    register_int_counter,
+    register_int_counter_vec,
+    register_int_gauge_vec,
+    Encoder,
+    Histogram,
+    HistogramVec,
+    IntCounter,
+    IntCounterVec,
+    IntGaugeVec,
+    TextEncoder,
+};
+
+use crate::{
+    app::config::State,
+    drivers::Binding,
+    support::page::Page,
+};
+
+lazy_static! {
+    static ref HTTP_REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "http_requests_total",
+        "HTTP requests handled, by route and status.",
+        &["method", "route", "status"]
+    )
+    .unwrap();
+    static ref HTTP_DURATION: HistogramVec = register_histogram_vec!(
+        "http_request_duration_seconds",
+        "HTTP request latencies, by route.",
+        &["method", "route"]
+    )
+    .unwrap();
+    static ref EVENTS_RECEIVED: IntCounterVec = register_int_counter_vec!(
+        "cloudevents_received_total",
+        "CloudEvents received, by type and outcome.",
+        &["type", "outcome"]
+    )
+    .unwrap();
+    static ref EVENTS_REJECTED: IntCounterVec = register_int_counter_vec!(
+        "cloudevents_rejected_total",
+        "CloudEvents refused for their origin, by type and reason.",
+        &["type", "reason"]
+    )
+    .unwrap();
+    static ref EVENTS_SENT: IntCounterVec = register_int_counter_vec!(
+        "cloudevents_sent_total",
+        "CloudEvents sent to the sink, by type and outcome.",
+        &["type", "outcome"]
+    )
+    .unwrap();
+    static ref EVENTS_RETRIED: IntCounterVec = register_int_counter_vec!(
+        "cloudevents_send_retries_total",
+        "Attempts at sending CloudEvents retried, by type.",
+        &["type"]
+    )
+    .unwrap();
+    static ref FEES: Histogram = register_histogram!(
+        "driver_fee_amount",
+        "Driver fee amounts computed.",
+        vec![1.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0, 500.0, 1000.0]
+    )
+    .unwrap();
+    static ref REDIS_DURATION: HistogramVec = register_histogram_vec!(
+        "redis_command_duration_seconds",
+        "Redis command latencies, by command.",
+        &["command"],
+        vec![0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 1.0]
+    )
+    .unwrap();
+    static ref REDIS_POOL: IntGaugeVec = register_int_gauge_vec!(
+        "redis_pool",
+        "Redis connection pool usage, by statistic.",
+        &["stat"]
+    )
+    .unwrap();
+    static ref CORRUPT_DRIVERS: IntCounter = register_int_counter!(
+        "drivers_corrupt_total",
+        "Stored drivers skipped, as they couldn't be read."
+    )
+    .unwrap();
+    static ref DRIVERS: IntGaugeVec = register_int_gauge_vec!(
+        "drivers",
+        "Drivers stored, by status and type.",
+        &["status", "type"]
+    )
+    .unwrap();
+    /// Serializes updates of the gauges, so the driver counts aren't mixed
+    /// up.
+    static ref SCRAPE: Mutex<()> = Mutex::new(());
+}
+
+pub fn event_received(ty: &str, ok: bool) {
+    let outcome = if ok { "ok" } else { "error" };
+    EVENTS_RECEIVED.with_label_values(&[ty, outcome]).inc();
+}
+
+pub fn event_rejected(ty: &str, reason: &str) {
+    EVENTS_REJECTED.with_label_values(&[ty, reason]).inc();
+}
+
+/// Counts the event as sent, if it was, or else as dead-lettered, if it
+/// could be.
+pub fn event_sent(ty: &str, sent: Option<bool>) {
+    let outcome = match sent {
+        Some(true) => "sent",
+        Some(false) => "dead-lettered",
+        None => "failed",
+    };
+    EVENTS_SENT.with_label_values(&[ty, outcome]).inc();
+}
+
+pub fn event_retried(ty: &str) {
+    EVENTS_RETRIED.with_label_values(&[ty]).inc();
+}
+
+pub fn fee_computed(amount: f64) {
+    FEES.observe(amount);
+}
+
+pub fn corrupt_driver() {
+    CORRUPT_DRIVERS.inc();
+}
+
+pub fn redis_command(command: &str, secs: f64) {
+    REDIS_DURATION.with_label_values(&[command]).observe(secs);
+}
+
+/// Middleware recording the count and latency of HTTP requests per route.
+pub async fn track(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let start = Instant::now();
+    let method = req.method().to_string();
+    let res = next.call(req).await?;
+    let route = res
+        .request()
+        .match_pattern()
+        .unwrap_or_else(|| "unmatched".to_string());
+    let status = res.status().as_u16().to_string();
+    HTTP_REQUESTS
+        .with_label_values(&[&method, &route, &status])
+        .inc();
+    HTTP_DURATION
+        .with_label_values(&[&method, &route])
+        .observe(start.elapsed().as_secs_f64());
+    Ok(res)
+}
+
+/// Counts the drivers stored, by status and type, in the background, every
+/// given period, so scrapes don't have to read them all.
+pub fn count_drivers_every(state: State, binding: Binding, every: Duration) {
+    rt::spawn(async move {
+        let mut interval = rt::time::interval(every);
+        loop {
+            interval.tick().await;
+            if let Err(err) = refresh_drivers(&state, &binding).await {
+                log::warn!("Failed to count drivers: {}", err);
+            }
+        }
+    });
+}
+
+#[get("/metrics")]
+pub async fn endpoint(state: web::Data<State>) -> Result<HttpResponse> {
+    {
+        let _guard = SCRAPE.lock().unwrap();
+        if let Some(pool) = &state.db.pool {
+            let stats = pool.stats();
+            for (stat, val) in [
+                ("size", stats.size as i64),
+                ("connects", stats.connects as i64),
+                ("in-flight", stats.in_flight as i64),
+                ("acquired", stats.acquired as i64),
+                ("acquire-errors", stats.acquire_errors as i64),
+                ("command-errors", stats.command_errors as i64),
+                ("timeouts", stats.timeouts as i64),
+            ] {
+                REDIS_POOL.with_label_values(&[stat]).set(val);
+            }
+        }
+    }
+
+    let encoder = TextEncoder::new();
+    let mut buf = vec![];
+    encoder
+        .encode(&prometheus::gather(), &mut buf)
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(encoder.format_type())
+        .body(buf))
+}
+
+async fn refresh_drivers(state: &State, binding: &Binding) -> Result<()> {
+    let counts = count_drivers(state, binding).await?;
+    let _guard = SCRAPE.lock().unwrap();
+    DRIVERS.reset();
+    for ((status, ty), n) in counts {
+        DRIVERS.with_label_values(&[&status, &ty]).set(n);
+    }
+    Ok(())
+}
+
+async fn count_drivers(
+    state: &State,
+    binding: &Binding,
+) -> Result<HashMap<(String, String), i64>> {
+    let mut repo = binding.repo_factory.call(state.db.clone()).await?;
+    let total = repo.count().await?;
+    let mut page = Page { num: 1, per: 1000 };
+    let mut counts = HashMap::new();
+    while page.start() < total {
+        for drv in repo.list(&page).await?.drivers {
+            let status = format!("{:?}", drv.entity.status).to_lowercase();
+            let ty = format!("{:?}", drv.entity.r#type).to_lowercase();
+            *counts.entry((status, ty)).or_insert(0) += 1;
+        }
+        page.num += 1;
+    }
+    Ok(counts)
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        middleware::from_fn,
+        test::{
+            call_service,
+            init_service,
+            read_body,
+            TestRequest,
+        },
+        App,
+    };
+
+    use super::*;
+    use crate::drivers::{
+        entity::{
+            Driver,
+            Status,
+        },
+        repository::memory::InMemory,
+    };
+
+    #[actix_web::test]
+    async fn scrape() {
+        let repo = InMemory::default();
+        {
+            let mut drivers = repo.drivers.lock().unwrap();
+            drivers.insert(1, Driver::default());
+            drivers.insert(2, Driver::default());
+            drivers.insert(
+                3,
+                Driver {
+                    status: Status::Active,
+                    ..Driver::default()
+                },
+            );
+        }
+        let state = State::default();
+        refresh_drivers(&state, &Binding::in_memory(repo))
+            .await
+            .unwrap();
+        let app = init_service(
+            App::new()
+                .wrap(from_fn(track))
+                .app_data(web::Data::new(state))
+                .service(endpoint),
+        )
+        .await;
+
+        let req = TestRequest::get().uri("/metrics").to_request();
+        let resp = call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        let req = TestRequest::get().uri("/metrics").to_request();
+        let resp = call_service(&app, req).await;
+        let body = read_body(resp).await;
+        let body = std::str::from_utf8(&body).unwrap();
+
+        assert!(
+            body.contains(r#"drivers{status="inactive",type="candidate"} 2"#)
+        );
+        assert!(body.contains(r#"drivers{status="active",type="candidate"} 1"#));
+        assert!(body.contains(
+            r#"http_requests_total{method="GET",route="/metrics",status="200"}"#
+        ));
+        assert!(body.contains(r#"redis_pool{stat="size"} 4"#));
+    }
+}
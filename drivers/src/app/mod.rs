@@ -1,21 +1,24 @@
+pub mod auth;
 pub mod config;
 pub mod events;
+pub mod health;
 pub mod index;
+pub mod logging;
+pub mod metrics;
+pub mod schemas;
 
 use actix_web::{
     body,
     dev,
-    get,
     middleware,
     web::Data,
     App,
     Error,
-    HttpRequest,
-    HttpResponse,
 };
 
 use crate::drivers;
 use crate::drivers::Binding;
+use crate::support::trace;
 
 pub fn create(
     state: config::State,
@@ -28,19 +31,20 @@ pub fn create(
         Error = Error,
     >,
 > {
+    state.liveness.watch(state.config.health.heartbeat);
     App::new()
         .wrap(middleware::NormalizePath::trim())
-        .wrap(middleware::Logger::default())
+        .wrap(middleware::from_fn(auth::middleware))
+        .wrap(middleware::from_fn(metrics::track))
+        .wrap(middleware::from_fn(trace::middleware))
+        .wrap(middleware::from_fn(logging::middleware))
         .app_data(Data::new(state.config.clone()))
         .app_data(Data::new(state))
         .app_data(Data::new(Binding::default()))
         .service(index::endpoint)
         .service(events::routes())
-        .service(health)
+        .service(health::routes())
+        .service(metrics::endpoint)
+        .service(schemas::routes())
         .service(drivers::routes())
 }
-
-#[get("/health/{_:(ready|live)}")]
-async fn health(_req: HttpRequest) -> HttpResponse {
-    HttpResponse::Ok().finish()
-}
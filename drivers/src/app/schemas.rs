@@ -0,0 +1,90 @@
+use actix_web::{
+    dev::HttpServiceFactory,
+    error,
+    get,
+    web,
+    HttpResponse,
+    Result,
+};
+
+use crate::{
+    app::config::State,
+    drivers::service::{
+        self,
+        TYPES,
+    },
+};
+
+pub fn routes() -> impl HttpServiceFactory + 'static {
+    web::scope("/schemas").service(list).service(get)
+}
+
+/// Lists the types of the events received and sent.
+#[get("")]
+async fn list() -> HttpResponse {
+    HttpResponse::Ok().json(TYPES)
+}
+
+/// The JSON Schema of the data of the events of the type.
+#[get("/{type}")]
+async fn get(
+    ty: web::Path<String>,
+    state: web::Data<State>,
+) -> Result<HttpResponse> {
+    let mut schema = service::schema(&ty)
+        .ok_or_else(|| error::ErrorNotFound("unknown event type"))?;
+    schema.schema.metadata().id =
+        Some(service::schema_url(&state.config.knative.schemas, &ty));
+    Ok(HttpResponse::Ok()
+        .content_type("application/schema+json")
+        .json(schema))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{
+        test,
+        App,
+    };
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn serves_schemas() {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(State::default()))
+                .service(routes()),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/schemas").to_request();
+        let types: Vec<String> = test::call_and_read_body_json(&app, req).await;
+        assert!(types.contains(&service::CALCULATE_FEE.to_string()));
+
+        for ty in TYPES {
+            let req = test::TestRequest::get()
+                .uri(&format!("/schemas/{}", ty))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), 200, "{}", ty);
+            let schema: serde_json::Value = test::read_body_json(resp).await;
+            assert!(schema["$id"].as_str().unwrap().ends_with(ty));
+        }
+
+        let req = test::TestRequest::get()
+            .uri("/schemas/cabs.drivers.calculate-fee")
+            .to_request();
+        let schema: serde_json::Value =
+            test::call_and_read_body_json(&app, req).await;
+        assert_eq!(
+            schema["required"],
+            serde_json::json!(["driver-id", "transit-price"])
+        );
+
+        let req = test::TestRequest::get()
+            .uri("/schemas/cabs.drivers.unknown")
+            .to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), 404);
+    }
+}
@@ -0,0 +1,634 @@
+use std::{
+    fs::File,
+    io::{
+        self,
+        BufRead,
+        BufReader,
+        Read,
+        Write,
+    },
+    path::PathBuf,
+};
+
+use actix_web::Result;
+use clap::{
+    builder::RangedU64ValueParser,
+    Parser,
+    Subcommand,
+    ValueEnum,
+};
+use cloudevents::AttributesReader;
+use serde::Deserialize;
+use serde_json::{
+    Map,
+    Value,
+};
+
+use crate::{
+    app::config::State,
+    drivers::{
+        self,
+        audit::Record,
+        entity::{
+            Driver,
+            NewDriver,
+        },
+        projection,
+        repository::{
+            self,
+            Repository,
+        },
+        Binding,
+    },
+    support::{
+        clock::Clock,
+        id::{
+            Generator,
+            Identifier,
+            ID,
+        },
+        page::Page,
+    },
+};
+
+/// Actor of the audit records of the drivers imported from the command line.
+const ACTOR: &str = "cli";
+
+#[derive(Debug, Parser)]
+#[command(about = "Drivers of the cabs app")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Default, PartialEq, Subcommand)]
+pub enum Command {
+    /// Serves the HTTP API, the default
+    #[default]
+    Serve,
+    /// Migrates the stored drivers to the current schema
+    Migrate,
+    /// Imports drivers from a NDJSON or CSV file, `-` for the standard input
+    Import {
+        file:   PathBuf,
+        /// Format of the file, told from its extension by default
+        #[arg(long, value_enum)]
+        format: Option<Format>,
+    },
+    /// Exports all the drivers, as NDJSON, to the standard output
+    Export,
+    /// Rebuilds the index of the drivers from the stored drivers
+    Reindex,
+    /// Reports the stored drivers which don't pass the validation rules
+    Validate,
+    /// Projects all the drivers of the legacy app, listed page by page at
+    /// the URL
+    Backfill {
+        url:  String,
+        /// Drivers per page
+        #[arg(
+            long,
+            default_value_t = 100,
+            value_parser = RangedU64ValueParser::<usize>::new().range(1..)
+        )]
+        size: usize,
+    },
+    /// Inspects and replays the events which couldn't be delivered
+    DeadLetters {
+        #[command(subcommand)]
+        command: DeadLettersCommand,
+    },
+    /// Converts driver IDs between numbers and their textual form
+    Id {
+        #[command(subcommand)]
+        command: IdCommand,
+    },
+}
+
+#[derive(Debug, PartialEq, Subcommand)]
+pub enum DeadLettersCommand {
+    /// Prints the events, as JSON, with when and why their delivery failed
+    List,
+    /// Sends the events again, removing the ones delivered
+    Replay,
+}
+
+#[derive(Debug, PartialEq, Subcommand)]
+pub enum IdCommand {
+    /// Prints the textual form of the numeric ID
+    Encode {
+        #[arg(value_parser = clap::value_parser!(i64).range(0..))]
+        id: i64,
+    },
+    /// Prints the number of the textual ID
+    Decode { id: String },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum Format {
+    Ndjson,
+    Csv,
+}
+
+/// Runs the administrative command, as opposed to serving.
+pub async fn run(command: Command, state: State) -> io::Result<()> {
+    match command {
+        Command::Serve => Err(io::Error::other("not an admin command")),
+        Command::Migrate => drivers::migrate(state.db.clone()).await,
+        Command::Import { file, format } => {
+            let format = format.unwrap_or(match file.extension() {
+                Some(ext) if ext.eq_ignore_ascii_case("csv") => Format::Csv,
+                _ => Format::Ndjson,
+            });
+            let input: Box<dyn Read> = match file.to_str() {
+                Some("-") => Box::new(io::stdin()),
+                _ => Box::new(File::open(&file)?),
+            };
+            let rows = read(format, input)?;
+            let mut repo = connect(&state).await?;
+            let failed = import(repo.as_mut(), &state.clock, &state.ids, rows)
+                .await
+                .map_err(other)?;
+            for (row, err) in &failed {
+                log::error!("Row {} not imported: {}", row, err);
+            }
+            match failed.len() {
+                0 => Ok(()),
+                n => Err(io::Error::other(format!("{} rows not imported", n))),
+            }
+        }
+        Command::Export => {
+            let mut repo = connect(&state).await?;
+            export(repo.as_mut(), &mut io::stdout().lock()).await
+        }
+        Command::Reindex => {
+            let n =
+                repository::reindex(state.db.clone()).await.map_err(other)?;
+            log::info!("Indexed {} drivers", n);
+            Ok(())
+        }
+        Command::Validate => {
+            let mut repo = connect(&state).await?;
+            let invalid =
+                validate(repo.as_mut(), &state.clock).await.map_err(other)?;
+            for (id, err) in &invalid {
+                println!("{}: {}", id, err);
+            }
+            match invalid.len() {
+                0 => Ok(()),
+                n => Err(io::Error::other(format!("{} invalid drivers", n))),
+            }
+        }
+        Command::Backfill { url, size } => {
+            let mut repo = connect(&state).await?;
+            let client = reqwest::Client::new();
+            let report = projection::backfill(
+                repo.as_mut(),
+                &state.clock,
+                &client,
+                &url,
+                size,
+            )
+            .await
+            .map_err(other)?;
+            log::info!(
+                "Backfilled drivers: {} created, {} updated, {} stale, {} \
+                 erased",
+                report.created,
+                report.updated,
+                report.stale,
+                report.erased
+            );
+            for (n, err) in &report.failed {
+                log::error!("Driver {} not backfilled: {}", n, err);
+            }
+            match report.failed.len() {
+                0 => Ok(()),
+                n => Err(io::Error::other(format!(
+                    "{} drivers not backfilled",
+                    n
+                ))),
+            }
+        }
+        Command::DeadLetters { command } => {
+            let store = state.sender.dead_letters();
+            let letters = store.list().await.map_err(other)?;
+            match command {
+                DeadLettersCommand::List => {
+                    for letter in letters {
+                        println!("{}", serde_json::to_string(&letter)?);
+                    }
+                    Ok(())
+                }
+                DeadLettersCommand::Replay => {
+                    let mut sent = vec![];
+                    for letter in &letters {
+                        match state.sender.resend(&letter.event).await {
+                            Ok(()) => sent.push(letter.id.clone()),
+                            Err(err) => log::error!(
+                                "Failed to replay event {}: {}",
+                                letter.event.id(),
+                                err
+                            ),
+                        }
+                    }
+                    store.remove(&sent).await.map_err(other)?;
+                    log::info!(
+                        "Replayed {} of {} events",
+                        sent.len(),
+                        letters.len()
+                    );
+                    match letters.len() - sent.len() {
+                        0 => Ok(()),
+                        n => Err(io::Error::other(format!(
+                            "{} events not replayed",
+                            n
+                        ))),
+                    }
+                }
+            }
+        }
+        Command::Id { command } => {
+            match command {
+                IdCommand::Encode { id } => {
+                    println!("{}", Identifier::from(id))
+                }
+                IdCommand::Decode { id } => {
+                    let id = Identifier::decode(&id)
+                        .map_err(|err| io::Error::other(err.to_string()))?;
+                    println!("{}", id.int())
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+async fn connect(state: &State) -> io::Result<Box<dyn Repository>> {
+    Binding::default()
+        .repo_factory
+        .call(state.db.clone())
+        .await
+        .map_err(other)
+}
+
+fn other(err: actix_web::Error) -> io::Error {
+    io::Error::other(err.to_string())
+}
+
+/// A driver to import, as exported, or as it would be added.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Row {
+    Stored(ID<Driver>),
+    New(NewDriver),
+}
+
+/// Reads the rows of the drivers to import. Rows which can't be read are
+/// kept as errors, so the others can still be imported.
+fn read(
+    format: Format,
+    input: impl Read,
+) -> io::Result<Vec<std::result::Result<Row, String>>> {
+    match format {
+        Format::Ndjson => BufReader::new(input)
+            .lines()
+            .filter(|line| {
+                line.as_ref().map(|l| !l.trim().is_empty()).unwrap_or(true)
+            })
+            .map(|line| Ok(serde_json::from_str(&line?).map_err(text)))
+            .collect(),
+        Format::Csv => {
+            let mut reader = csv::Reader::from_reader(input);
+            let headers = reader.headers().map_err(io::Error::other)?.clone();
+            Ok(reader
+                .records()
+                .map(|rec| {
+                    let rec = rec.map_err(text)?;
+                    let row = columns(headers.iter().zip(rec.iter()));
+                    serde_json::from_value(row).map_err(text)
+                })
+                .collect())
+        }
+    }
+}
+
+fn text(err: impl std::fmt::Display) -> String {
+    err.to_string()
+}
+
+/// Nests the CSV columns as the fields of a driver. The `license-` and
+/// `fee-` columns make the license and fee, and unknown columns make the
+/// attributes.
+fn columns<'a>(cols: impl Iterator<Item = (&'a str, &'a str)>) -> Value {
+    let mut drv = Map::new();
+    let mut id = None;
+    for (col, val) in cols.filter(|(_, val)| !val.is_empty()) {
+        let num = val
+            .parse::<i64>()
+            .map(Value::from)
+            .unwrap_or(Value::from(val));
+        let (field, key, val) = match col {
+            "id" => {
+                id = Some(num);
+                continue;
+            }
+            "name" | "surname" | "status" | "type" | "photo" => {
+                drv.insert(col.to_string(), val.into());
+                continue;
+            }
+            "license-number" => ("license", "number", val.into()),
+            "license-expires" => ("license", "expires", val.into()),
+            "fee-type" => ("fee", "type", val.into()),
+            "fee-amount" => ("fee", "amount", num),
+            "fee-min" => ("fee", "min", num),
+            attr => ("attributes", attr, val.into()),
+        };
+        if let Value::Object(obj) =
+            drv.entry(field).or_insert(Value::Object(Map::new()))
+        {
+            obj.insert(key.to_string(), val);
+        }
+    }
+    match id {
+        Some(id) => serde_json::json!({"id": id, "entity": drv}),
+        None => Value::Object(drv),
+    }
+}
+
+/// Imports the drivers, validated as they would be when added, returning
+/// the rows which weren't imported, numbered from 1, with the reason.
+async fn import(
+    repo: &mut dyn Repository,
+    clock: &Clock,
+    ids: &Generator,
+    rows: Vec<std::result::Result<Row, String>>,
+) -> Result<Vec<(usize, String)>> {
+    let mut failed = vec![];
+    for (n, row) in rows.into_iter().enumerate().map(|(n, r)| (n + 1, r)) {
+        let row = row.and_then(|row| {
+            let entity = match &row {
+                Row::Stored(drv) => drv.entity.clone(),
+                Row::New(drv) => drv.clone().onto(&Driver::default()),
+            };
+            match NewDriver::from(entity).validate(clock) {
+                Ok(()) => Ok(row),
+                Err(err) => Err(err.to_string()),
+            }
+        });
+        let drv = match row {
+            Ok(Row::Stored(drv)) => drv,
+            Ok(Row::New(drv)) => {
+                let drv = repository::create(
+                    repo,
+                    ids,
+                    clock,
+                    drv.onto(&Driver::default()),
+                    |id, drv| Record::by(ACTOR, clock, "import", id, None, drv),
+                )
+                .await?;
+                log::debug!("Imported driver {}", drv.id);
+                continue;
+            }
+            Err(err) => {
+                failed.push((n, err));
+                continue;
+            }
+        };
+
+        let before = match repo.get(&drv.id).await {
+            Ok(curr) => Some(curr),
+            Err(err) if err.as_response_error().status_code() == 404 => None,
+            Err(err) => return Err(err),
+        };
+        let rec = Record::by(
+            ACTOR,
+            clock,
+            "import",
+            &drv.id,
+            before.as_ref(),
+            &drv.entity,
+        )?;
+        repo.set_audited(&drv, &rec).await?;
+        log::debug!("Imported driver {}", drv.id);
+    }
+    Ok(failed)
+}
+
+/// Writes all the drivers, one JSON per line, as they can be imported.
+async fn export(
+    repo: &mut dyn Repository,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let mut page = Page { num: 1, per: 100 };
+    let mut skipped = vec![];
+    loop {
+        let drvs = repo.list(&page).await.map_err(other)?;
+        if drvs.is_empty() {
+            break;
+        }
+        for drv in drvs.drivers {
+            serde_json::to_writer(&mut *out, &drv)?;
+            writeln!(out)?;
+        }
+        skipped.extend(drvs.skipped);
+        page.num += 1;
+    }
+    out.flush()?;
+    match skipped.len() {
+        0 => Ok(()),
+        _ => Err(io::Error::other(format!(
+            "Corrupt drivers not exported: {}",
+            skipped.join(", ")
+        ))),
+    }
+}
+
+/// Lists the stored drivers which wouldn't be accepted if added now, or
+/// couldn't be read at all, with the reason.
+async fn validate(
+    repo: &mut dyn Repository,
+    clock: &Clock,
+) -> Result<Vec<(String, String)>> {
+    let mut invalid = vec![];
+    let mut page = Page { num: 1, per: 100 };
+    loop {
+        let drvs = repo.list(&page).await?;
+        if drvs.is_empty() {
+            return Ok(invalid);
+        }
+        for drv in drvs.drivers {
+            if let Err(err) = NewDriver::from(drv.entity).validate(clock) {
+                invalid.push((drv.id.to_string(), err.to_string()));
+            }
+        }
+        for id in drvs.skipped {
+            invalid.push((id, "corrupt".to_string()));
+        }
+        page.num += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{
+        Local,
+        TimeZone,
+    };
+
+    use super::*;
+    use crate::drivers::repository::memory::InMemory;
+    use crate::support::clock::Fixed;
+
+    fn clock() -> Clock {
+        Clock::FixedClock(Fixed {
+            time: Local.with_ymd_and_hms(2023, 5, 1, 10, 0, 0).unwrap(),
+        })
+    }
+
+    #[test]
+    fn parses_commands() {
+        let cli = Cli::try_parse_from(["drivers"]).unwrap();
+        assert_eq!(cli.command.unwrap_or_default(), Command::Serve);
+
+        let cli =
+            Cli::try_parse_from(["drivers", "import", "drivers.csv"]).unwrap();
+        assert_eq!(
+            cli.command,
+            Some(Command::Import {
+                file:   "drivers.csv".into(),
+                format: None,
+            })
+        );
+
+        let cli = Cli::try_parse_from(["drivers", "id", "decode", "gk"]);
+        assert_eq!(
+            cli.unwrap().command,
+            Some(Command::Id {
+                command: IdCommand::Decode {
+                    id: "gk".to_string(),
+                },
+            })
+        );
+        let cli = Cli::try_parse_from([
+            "drivers",
+            "backfill",
+            "http://legacy/drivers",
+            "--size",
+            "50",
+        ]);
+        assert_eq!(
+            cli.unwrap().command,
+            Some(Command::Backfill {
+                url:  "http://legacy/drivers".to_string(),
+                size: 50,
+            })
+        );
+
+        assert!(Cli::try_parse_from([
+            "drivers",
+            "backfill",
+            "http://legacy/drivers",
+            "--size",
+            "0",
+        ])
+        .is_err());
+
+        assert!(Cli::try_parse_from(["drivers", "id", "encode", "x"]).is_err());
+        assert!(Cli::try_parse_from(["drivers", "id", "encode", "-1"]).is_err());
+    }
+
+    #[actix_web::test]
+    async fn imports_and_exports() {
+        let ndjson = r#"
+{"name": "John", "surname": "Doe", "license": {"number": "FARME100165AB5EW"}}
+{"id": 100, "entity": {"name": "Jane", "surname": "Roe", "status": "Active"}}
+{"name": ""}
+not json
+"#;
+        let csv = "\
+id,name,surname,license-number,fee-type,fee-amount,email
+,Bob,Falc,AASSQ453654QW1WW,flat,1000,bob@example.com
+,Anice,Kowalsky,,flat,0,
+";
+        let mut repo = InMemory::default();
+        let rows = read(Format::Ndjson, ndjson.as_bytes()).unwrap();
+        let failed = import(&mut repo, &clock(), &Generator::default(), rows)
+            .await
+            .unwrap();
+        assert_eq!(
+            failed.iter().map(|(n, _)| *n).collect::<Vec<_>>(),
+            vec![3, 4]
+        );
+        let rows = read(Format::Csv, csv.as_bytes()).unwrap();
+        let failed = import(&mut repo, &clock(), &Generator::default(), rows)
+            .await
+            .unwrap();
+        assert_eq!(failed.len(), 1);
+        assert!(failed[0].1.contains("fee amount"));
+
+        let drivers = repo.drivers.lock().unwrap().clone();
+        assert_eq!(drivers.len(), 3);
+        assert_eq!(drivers[&100].name, "Jane");
+        let bob = drivers.values().find(|d| d.name == "Bob").unwrap();
+        assert_eq!(bob.fee.as_ref().unwrap().amount, 1000);
+        assert_eq!(bob.attributes.len(), 1);
+        let audit = repo.audit.lock().unwrap().clone();
+        assert!(audit
+            .iter()
+            .all(|r| r.action == "import" && r.actor == ACTOR));
+
+        let mut out = vec![];
+        export(&mut repo, &mut out).await.unwrap();
+        let mut copy = InMemory::default();
+        let rows = read(Format::Ndjson, out.as_slice()).unwrap();
+        let failed = import(&mut copy, &clock(), &Generator::default(), rows)
+            .await
+            .unwrap();
+        assert!(failed.is_empty());
+        assert_eq!(
+            copy.drivers.lock().unwrap().keys().collect::<Vec<_>>(),
+            drivers.keys().collect::<Vec<_>>()
+        );
+    }
+
+    #[actix_web::test]
+    async fn validates_stored() {
+        let mut repo = InMemory::default();
+        repo.drivers.lock().unwrap().extend([
+            (
+                1,
+                Driver {
+                    name: "John".to_string(),
+                    surname: "Doe".to_string(),
+                    ..Driver::default()
+                },
+            ),
+            (2, Driver::default()),
+        ]);
+        let invalid = validate(&mut repo, &clock()).await.unwrap();
+        assert_eq!(invalid.len(), 1);
+        assert_eq!(invalid[0].0, Identifier::from(2).to_string());
+    }
+
+    #[actix_web::test]
+    async fn reports_corrupt_stored() {
+        let mut repo = InMemory::default();
+        repo.drivers
+            .lock()
+            .unwrap()
+            .extend((1..=150).map(|id| (id, Driver::default())));
+        repo.corrupt.lock().unwrap().extend(101..=150);
+
+        let invalid = validate(&mut repo, &clock()).await.unwrap();
+        assert!(invalid.contains(&(
+            Identifier::from(150).to_string(),
+            "corrupt".to_string()
+        )));
+
+        let mut out = vec![];
+        let err = export(&mut repo, &mut out).await.unwrap_err();
+        assert!(err.to_string().contains(&Identifier::from(150).to_string()));
+        assert_eq!(out.iter().filter(|&&b| b == b'\n').count(), 100);
+    }
+}
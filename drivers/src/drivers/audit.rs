@@ -0,0 +1,224 @@
+use std::collections::BTreeMap;
+
+use actix_web::Result;
+use chrono::{
+    DateTime,
+    Utc,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use serde_json::Value;
+
+use crate::app::logging;
+use crate::support::{
+    clock::{
+        Clock,
+        Now,
+    },
+    id::Identifier,
+};
+
+use super::entity::{
+    Attribute,
+    Driver,
+    ERASED,
+};
+
+/// A mutation of a driver, with who did it, when, and what changed.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Record {
+    pub driver_id:  Identifier,
+    pub action:     String,
+    pub actor:      String,
+    pub at:         DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub request_id: Option<String>,
+    pub changes:    BTreeMap<String, Change>,
+}
+
+/// The values of a driver field before and after the mutation. A missing
+/// value means the field wasn't set.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Change {
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub before: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub after:  Option<Value>,
+}
+
+impl Record {
+    /// Records the mutation done by the actor, from the driver as it was, if
+    /// it existed, to the driver as it is.
+    pub fn by(
+        actor: &str,
+        clock: &Clock,
+        action: &str,
+        id: &Identifier,
+        before: Option<&Driver>,
+        after: &Driver,
+    ) -> Result<Self> {
+        Ok(Record {
+            driver_id:  id.clone(),
+            action:     action.to_string(),
+            actor:      actor.to_string(),
+            at:         clock.now().with_timezone(&Utc),
+            request_id: logging::current().and_then(|ctx| ctx.request_id),
+            changes:    diff(before, after)?,
+        })
+    }
+
+    /// Pseudonymizes the personal data in the changes, as the driver was
+    /// erased.
+    pub fn erased(mut self) -> Self {
+        for (field, change) in self.changes.iter_mut() {
+            change.before = change.before.take().map(|v| erase(field, v));
+            change.after = change.after.take().map(|v| erase(field, v));
+        }
+        self
+    }
+}
+
+fn erase(field: &str, val: Value) -> Value {
+    match (field, val) {
+        ("name" | "surname" | "photo" | "license", _) => ERASED.into(),
+        ("attributes", Value::Object(attrs)) => attrs
+            .into_iter()
+            .map(|(k, v)| {
+                let attr: Option<Attribute> =
+                    serde_json::from_value(Value::String(k.clone())).ok();
+                match attr.map(|a| a.is_pii()).unwrap_or(true) {
+                    true => (k, ERASED.into()),
+                    false => (k, v),
+                }
+            })
+            .collect(),
+        (_, val) => val,
+    }
+}
+
+/// Compares the top level fields of the drivers.
+fn diff(
+    before: Option<&Driver>,
+    after: &Driver,
+) -> Result<BTreeMap<String, Change>> {
+    let before = match before {
+        Some(drv) => fields(drv)?,
+        None => serde_json::Map::new(),
+    };
+    let mut after = fields(after)?;
+    let mut changes = BTreeMap::new();
+    for (key, val) in before {
+        let new = after.remove(&key);
+        if new.as_ref() != Some(&val) {
+            changes.insert(
+                key,
+                Change {
+                    before: Some(val),
+                    after:  new,
+                },
+            );
+        }
+    }
+    for (key, val) in after {
+        changes.insert(
+            key,
+            Change {
+                before: None,
+                after:  Some(val),
+            },
+        );
+    }
+    Ok(changes)
+}
+
+fn fields(drv: &Driver) -> Result<serde_json::Map<String, Value>> {
+    match serde_json::to_value(drv)? {
+        Value::Object(map) => Ok(map),
+        _ => Ok(serde_json::Map::new()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{
+        Local,
+        TimeZone,
+    };
+    use serde_json::json;
+
+    use super::*;
+    use crate::drivers::entity::Status;
+    use crate::support::clock::Fixed;
+
+    #[test]
+    fn records_changes() {
+        let clock = Clock::FixedClock(Fixed {
+            time: Local.with_ymd_and_hms(2023, 5, 1, 10, 0, 0).unwrap(),
+        });
+        let before = Driver {
+            name: "John".to_string(),
+            surname: "Doe".to_string(),
+            ..Driver::default()
+        };
+        let after = Driver {
+            name: "Jim".to_string(),
+            status: Status::Active,
+            ..before.clone()
+        };
+
+        let rec = Record::by(
+            "jane",
+            &clock,
+            "update",
+            &100.into(),
+            Some(&before),
+            &after,
+        )
+        .unwrap();
+        assert_eq!(rec.actor, "jane");
+        assert_eq!(rec.at, clock.now().with_timezone(&Utc));
+        assert_eq!(
+            serde_json::to_value(&rec.changes).unwrap(),
+            json!({
+                "name": {"before": "John", "after": "Jim"},
+                "status": {"after": "Active"},
+            })
+        );
+
+        let rec = Record::by("jane", &clock, "add", &100.into(), None, &before)
+            .unwrap();
+        assert_eq!(rec.changes.len(), 2);
+        assert_eq!(rec.changes["surname"].after, Some(json!("Doe")));
+    }
+
+    #[test]
+    fn erased() {
+        let rec = Record {
+            driver_id:  100.into(),
+            action:     "update".to_string(),
+            actor:      "jane".to_string(),
+            at:         Utc::now(),
+            request_id: None,
+            changes:    serde_json::from_value(json!({
+                "name": {"before": "John", "after": "Jim"},
+                "attributes": {"after": {"email": "jim@example.com", "penalty-points": "3"}},
+                "fee": {"after": {"type": "flat", "amount": 10}},
+            }))
+            .unwrap(),
+        };
+
+        let rec = rec.erased();
+        assert_eq!(
+            serde_json::to_value(&rec.changes).unwrap(),
+            json!({
+                "name": {"before": "erased", "after": "erased"},
+                "attributes": {"after": {"email": "erased", "penalty-points": "3"}},
+                "fee": {"after": {"type": "flat", "amount": 10}},
+            })
+        );
+        assert_eq!(rec.actor, "jane");
+    }
+}
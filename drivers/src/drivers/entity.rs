@@ -1,21 +1,30 @@
 use chrono::{
     DateTime,
     Local,
+    Utc,
 };
 use std::collections::HashMap;
-use std::fmt::Display;
+use std::fmt::{
+    self,
+    Display,
+};
 
 use crate::support::clock::{
     Clock,
     Now,
 };
 use crate::support::money::Money;
+use crate::support::pii::Pii;
+use schemars::JsonSchema;
 use serde::{
     Deserialize,
     Serialize,
 };
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Stands in for the personal data of erased drivers.
+pub const ERASED: &str = "erased";
+
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
 pub struct NewDriver {
     pub name:       String,
     pub surname:    String,
@@ -27,12 +36,13 @@ pub struct NewDriver {
         skip_serializing_if = "HashMap::is_empty",
         default = "HashMap::new"
     )]
+    #[schemars(default)]
     pub attributes: HashMap<Attribute, String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub fee:        Option<Fee>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Clone, Default, Serialize, Deserialize)]
 pub struct Driver {
     pub name:       String,
     pub surname:    String,
@@ -51,6 +61,20 @@ pub struct Driver {
     pub attributes: HashMap<Attribute, String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub fee:        Option<Fee>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub legacy:     Option<Legacy>,
+    /// Whether the personal data of the driver was erased, so it's never
+    /// brought back, as by changes projected from the legacy app.
+    #[serde(skip_serializing_if = "default", default)]
+    pub erased:     bool,
+}
+
+/// The version of the driver in the legacy app it was last projected from.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub struct Legacy {
+    pub version:    u64,
+    pub updated_at: DateTime<Utc>,
 }
 
 impl Driver {
@@ -67,7 +91,7 @@ impl Driver {
 
         let fee = transit_price.subtract(&fee);
 
-        let fee = match company_fee.min {
+        match company_fee.min {
             Some(min) => {
                 let mut min = Money::new(min as i64);
                 let leftover = transit_price.subtract(&min);
@@ -81,9 +105,7 @@ impl Driver {
                 }
             }
             None => fee,
-        };
-
-        return fee;
+        }
     }
 
     pub(crate) fn with_type(&self, typ: Type) -> Driver {
@@ -97,15 +119,10 @@ impl Driver {
         let now = clk.now();
         let mut driver = self.clone();
         driver.status = Status::Active;
-        if !driver
+        driver
             .attributes
-            .contains_key(&Attribute::YearsOfExperience)
-        {
-            driver.attributes.insert(
-                Attribute::YearsOfExperience,
-                format!("{}", now.to_rfc3339()),
-            );
-        }
+            .entry(Attribute::YearsOfExperience)
+            .or_insert_with(|| now.to_rfc3339());
 
         match self.license {
             Some(ref license) => license.validate(clk),
@@ -123,9 +140,29 @@ impl Driver {
 
         driver
     }
+
+    /// Pseudonymizes the personal data of the driver, keeping the fee and
+    /// what else the financial records need.
+    pub(crate) fn erase(&self) -> Driver {
+        Driver {
+            name: ERASED.to_string(),
+            surname: ERASED.to_string(),
+            status: Status::Inactive,
+            photo: None,
+            license: None,
+            attributes: self
+                .attributes
+                .iter()
+                .filter(|(k, _)| !k.is_pii())
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+            erased: true,
+            ..self.clone()
+        }
+    }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize, JsonSchema)]
 pub struct License {
     pub number:  String,
     #[serde(
@@ -150,25 +187,96 @@ pub enum Attribute {
     CompanyName,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[derive(
+    Debug, Clone, Default, Serialize, Deserialize, PartialEq, JsonSchema,
+)]
 pub enum Status {
     Active,
+    #[default]
     Inactive,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[derive(
+    Debug, Clone, Default, Serialize, Deserialize, PartialEq, JsonSchema,
+)]
 pub enum Type {
+    #[default]
     Candidate,
     Regular,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, JsonSchema)]
 #[serde(rename_all = "kebab-case")]
 pub enum FeeType {
     Flat,
     Percentage,
 }
 
+impl Attribute {
+    pub(crate) fn is_pii(&self) -> bool {
+        matches!(
+            self,
+            Attribute::Email
+                | Attribute::Birthplace
+                | Attribute::MedicalExaminationRemarks
+        )
+    }
+}
+
+impl fmt::Debug for NewDriver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NewDriver")
+            .field("name", &Pii(&self.name))
+            .field("surname", &Pii(&self.surname))
+            .field("photo", &self.photo.as_ref().map(Pii))
+            .field("license", &self.license)
+            .field("attributes", &Attributes(&self.attributes))
+            .field("fee", &self.fee)
+            .finish()
+    }
+}
+
+impl fmt::Debug for Driver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Driver")
+            .field("name", &Pii(&self.name))
+            .field("surname", &Pii(&self.surname))
+            .field("status", &self.status)
+            .field("type", &self.r#type)
+            .field("photo", &self.photo.as_ref().map(Pii))
+            .field("license", &self.license)
+            .field("attributes", &Attributes(&self.attributes))
+            .field("fee", &self.fee)
+            .field("legacy", &self.legacy)
+            .field("erased", &self.erased)
+            .finish()
+    }
+}
+
+impl fmt::Debug for License {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("License")
+            .field("number", &Pii(&self.number))
+            .field("expires", &self.expires)
+            .finish()
+    }
+}
+
+struct Attributes<'a>(&'a HashMap<Attribute, String>);
+
+impl<'a> fmt::Debug for Attributes<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut m = f.debug_map();
+        for (k, v) in self.0 {
+            match k.is_pii() {
+                true => m.entry(k, &Pii(v)),
+                false => m.entry(k, v),
+            };
+        }
+        m.finish()
+    }
+}
+
 impl Display for FeeType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let repr = serde_json::to_string(self).map_err(|_| std::fmt::Error)?;
@@ -176,7 +284,7 @@ impl Display for FeeType {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
 #[serde(rename_all = "kebab-case")]
 pub struct Fee {
     pub r#type: FeeType,
@@ -190,7 +298,7 @@ impl Fee {
         let ft = self.r#type.clone();
         match self.r#type {
             FeeType::Flat => {
-                if self.amount <= 0 {
+                if self.amount == 0 {
                     return Err(Error::InvalidFeeAmount(self.amount, ft));
                 }
 
@@ -222,6 +330,7 @@ impl Default for Fee {
 }
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum Error {
     InvalidName(String),
     InvalidSurname(String),
@@ -230,33 +339,6 @@ pub enum Error {
     InvalidFeeMin(usize, FeeType),
 }
 
-impl Default for Driver {
-    fn default() -> Self {
-        Self {
-            name:       String::new(),
-            surname:    String::new(),
-            status:     Status::default(),
-            r#type:     Type::default(),
-            photo:      None,
-            license:    None,
-            attributes: HashMap::new(),
-            fee:        None,
-        }
-    }
-}
-
-impl Default for Type {
-    fn default() -> Self {
-        Type::Candidate
-    }
-}
-
-impl Default for Status {
-    fn default() -> Self {
-        Status::Inactive
-    }
-}
-
 impl Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -334,6 +416,21 @@ impl NewDriver {
                 .map(|(k, v)| (k.clone(), v.clone()))
                 .collect(),
             fee:        self.fee,
+            legacy:     defaults.legacy.clone(),
+            erased:     defaults.erased,
+        }
+    }
+}
+
+impl From<Driver> for NewDriver {
+    fn from(drv: Driver) -> Self {
+        NewDriver {
+            name:       drv.name,
+            surname:    drv.surname,
+            photo:      drv.photo,
+            license:    drv.license,
+            attributes: drv.attributes,
+            fee:        drv.fee,
         }
     }
 }
@@ -373,3 +470,73 @@ where
         DateTime::parse_from_rfc3339(&s).map_err(serde::de::Error::custom)?;
     Ok(Some(dt.into()))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debug_redacts_pii() {
+        let drv = Driver {
+            name: "Jan".to_string(),
+            surname: "Kowalski".to_string(),
+            photo: Some("aGVsbG8=".to_string()),
+            license: Some(License {
+                number:  "FARME100165AB5EW".to_string(),
+                expires: None,
+            }),
+            attributes: HashMap::from([
+                (Attribute::Email, "jan@example.com".to_string()),
+                (Attribute::Nationality, "Polish".to_string()),
+            ]),
+            ..Driver::default()
+        };
+
+        let repr = format!("{:?}", drv);
+        assert!(!repr.contains("Jan"));
+        assert!(!repr.contains("Kowalski"));
+        assert!(!repr.contains("aGVsbG8="));
+        assert!(!repr.contains("FARME100165AB5EW"));
+        assert!(!repr.contains("jan@example.com"));
+        assert!(repr.contains("Polish"));
+        assert!(repr.contains("status: Inactive"));
+    }
+
+    #[test]
+    fn erase() {
+        let drv = Driver {
+            name: "Jan".to_string(),
+            surname: "Kowalski".to_string(),
+            status: Status::Active,
+            photo: Some("https://example.com/jan.png".to_string()),
+            license: Some(License {
+                number:  "FARME100165AB5EW".to_string(),
+                expires: None,
+            }),
+            attributes: HashMap::from([
+                (Attribute::Email, "jan@example.com".to_string()),
+                (Attribute::Birthplace, "Gdańsk".to_string()),
+                (Attribute::PenaltyPoints, "3".to_string()),
+            ]),
+            fee: Some(Fee {
+                r#type: FeeType::Flat,
+                amount: 10,
+                min:    None,
+            }),
+            ..Driver::default()
+        };
+
+        let erased = drv.erase();
+        assert_eq!(erased.name, ERASED);
+        assert_eq!(erased.surname, ERASED);
+        assert_eq!(erased.status, Status::Inactive);
+        assert!(erased.photo.is_none());
+        assert!(erased.license.is_none());
+        assert_eq!(
+            erased.attributes,
+            HashMap::from([(Attribute::PenaltyPoints, "3".to_string())])
+        );
+        assert_eq!(erased.fee.map(|f| f.amount), Some(10));
+        assert!(erased.erased);
+    }
+}
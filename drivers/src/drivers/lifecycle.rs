@@ -0,0 +1,160 @@
+use actix_web::{
+    error,
+    Result,
+};
+
+use crate::drivers::{
+    audit::Record,
+    entity::{
+        Driver,
+        NewDriver,
+        Type,
+    },
+    repository::{
+        self,
+        Repository,
+    },
+};
+use crate::support::{
+    clock::Clock,
+    id::{
+        Generator,
+        Identifier,
+        ID,
+    },
+};
+
+/// Registers the new driver, on behalf of the actor.
+pub(crate) async fn register(
+    repo: &mut dyn Repository,
+    ids: &Generator,
+    clock: &Clock,
+    actor: &str,
+    drv: NewDriver,
+) -> Result<ID<Driver>> {
+    drv.validate(clock).map_err(error::ErrorBadRequest)?;
+    let entity = drv.onto(&Driver::default());
+    let id = repository::create(repo, ids, clock, entity, |id, drv| {
+        Record::by(actor, clock, "add", id, None, drv)
+    })
+    .await?;
+    log::debug!("new id: {:?}", id.id);
+    Ok(id)
+}
+
+/// Updates the driver, once the actor is authorized to change its fee,
+/// and whether anything else. Erased drivers aren't updated, so their
+/// personal data stays erased.
+pub(crate) async fn update(
+    repo: &mut dyn Repository,
+    clock: &Clock,
+    actor: &str,
+    id: Identifier,
+    drv: NewDriver,
+    authorize: impl FnOnce(bool, bool) -> Result<()>,
+) -> Result<ID<Driver>> {
+    drv.validate(clock).map_err(error::ErrorBadRequest)?;
+    let curr = repo.get(&id).await?;
+    if curr.erased {
+        return Err(error::ErrorConflict("Driver is erased"));
+    }
+    let upd = drv.onto(&curr);
+    let (fee, others) = changes(&curr, &upd)?;
+    authorize(fee, others)?;
+    save(repo, clock, actor, "update", id, &curr, upd).await
+}
+
+pub(crate) async fn activate(
+    repo: &mut dyn Repository,
+    clock: &Clock,
+    actor: &str,
+    id: Identifier,
+) -> Result<ID<Driver>> {
+    let curr = repo.get(&id).await?;
+    let upd = curr.activate(clock).map_err(error::ErrorBadRequest)?;
+    save(repo, clock, actor, "activate", id, &curr, upd).await
+}
+
+pub(crate) async fn deactivate(
+    repo: &mut dyn Repository,
+    clock: &Clock,
+    actor: &str,
+    id: Identifier,
+) -> Result<ID<Driver>> {
+    let curr = repo.get(&id).await?;
+    let upd = curr.deactivate();
+    save(repo, clock, actor, "deactivate", id, &curr, upd).await
+}
+
+/// Makes the candidate a regular driver.
+pub(crate) async fn graduate(
+    repo: &mut dyn Repository,
+    clock: &Clock,
+    actor: &str,
+    id: Identifier,
+) -> Result<ID<Driver>> {
+    let curr = repo.get(&id).await?;
+    let upd = curr.with_type(Type::Regular);
+    save(repo, clock, actor, "graduate", id, &curr, upd).await
+}
+
+/// Most attempts at erasing a driver changed meanwhile.
+const ERASE_ATTEMPTS: usize = 3;
+
+/// Erases the personal data of the driver, in its audit records too. Tried
+/// again if the driver changes meanwhile.
+pub(crate) async fn erase(
+    repo: &mut dyn Repository,
+    clock: &Clock,
+    actor: &str,
+    id: Identifier,
+) -> Result<ID<Driver>> {
+    for _ in 0..ERASE_ATTEMPTS {
+        let curr = repo.get(&id).await?;
+        let inst = ID {
+            id:     id.clone(),
+            entity: curr.erase(),
+        };
+        let rec =
+            Record::by(actor, clock, "erase", &id, Some(&curr), &inst.entity)?
+                .erased();
+        if repo.erase(&inst, &curr, &rec).await? {
+            return Ok(inst);
+        }
+        log::warn!("Driver {} changed while erased, erasing again", id);
+    }
+    Err(error::ErrorConflict("Driver changed while erased"))
+}
+
+/// Stores the driver changed from `curr`, unless it changed meanwhile, as
+/// by an erasure, which is then a conflict.
+async fn save(
+    repo: &mut dyn Repository,
+    clock: &Clock,
+    actor: &str,
+    action: &str,
+    id: Identifier,
+    curr: &Driver,
+    upd: Driver,
+) -> Result<ID<Driver>> {
+    let inst = ID { id, entity: upd };
+    log::debug!("to update: {:?}", &inst);
+    let rec =
+        Record::by(actor, clock, action, &inst.id, Some(curr), &inst.entity)?;
+    if !repo.replace(&inst, curr, &rec).await? {
+        return Err(error::ErrorConflict("Driver changed concurrently"));
+    }
+    Ok(inst)
+}
+
+/// Tells whether the fee, and whether anything else, differs between the
+/// drivers.
+fn changes(curr: &Driver, upd: &Driver) -> Result<(bool, bool)> {
+    let mut curr = serde_json::to_value(curr)?;
+    let mut upd = serde_json::to_value(upd)?;
+    let fee = match (curr.as_object_mut(), upd.as_object_mut()) {
+        (Some(c), Some(u)) => c.remove("fee") != u.remove("fee"),
+        _ => true,
+    };
+    Ok((fee, curr != upd))
+}
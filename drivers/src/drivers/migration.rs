@@ -0,0 +1,97 @@
+use serde_json::{
+    Map,
+    Value,
+};
+
+/// Field of the stored drivers holding the version of their schema. Drivers
+/// stored before it was introduced are of version 0.
+pub const SCHEMA: &str = "schema";
+
+/// Version of the schema drivers are stored with.
+pub const CURRENT: u64 = 1;
+
+type Migration = fn(&mut Map<String, Value>);
+
+/// Migrations from each version of the schema to the next one.
+const MIGRATIONS: [Migration; CURRENT as usize] = [v1];
+
+/// Version of the schema the driver is stored with.
+pub fn version(drv: &Value) -> u64 {
+    drv.get(SCHEMA).and_then(Value::as_u64).unwrap_or(0)
+}
+
+/// Migrates the stored driver to the current schema, dropping the version.
+pub fn migrate(drv: &mut Value) -> Result<(), String> {
+    let from = version(drv);
+    if from > CURRENT {
+        return Err(format!("unknown schema version {}", from));
+    }
+    let obj = drv
+        .as_object_mut()
+        .ok_or("driver is not an object".to_string())?;
+    for migration in &MIGRATIONS[from as usize..] {
+        migration(obj);
+    }
+    obj.remove(SCHEMA);
+    Ok(())
+}
+
+/// Sets the current version of the schema on the driver to be stored.
+pub fn stamp(drv: &mut Value) {
+    if let Some(obj) = drv.as_object_mut() {
+        obj.insert(SCHEMA.to_string(), CURRENT.into());
+    }
+}
+
+/// Makes the status explicit, and license numbers upper case, without
+/// surrounding whitespace.
+fn v1(drv: &mut Map<String, Value>) {
+    drv.entry("status").or_insert("Inactive".into());
+    if let Some(Value::String(num)) =
+        drv.get_mut("license").and_then(|l| l.get_mut("number"))
+    {
+        *num = num.trim().to_uppercase();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn from_unversioned() {
+        let mut drv = json!({
+            "name": "Bob",
+            "surname": "Falc",
+            "license": {"number": " aassq453654qw1ww"},
+        });
+        migrate(&mut drv).unwrap();
+        assert_eq!(
+            drv,
+            json!({
+                "name": "Bob",
+                "surname": "Falc",
+                "status": "Inactive",
+                "license": {"number": "AASSQ453654QW1WW"},
+            })
+        );
+    }
+
+    #[test]
+    fn current_untouched() {
+        let mut drv = json!({"name": "Anice", "license": {"number": "ab"}});
+        stamp(&mut drv);
+        assert_eq!(version(&drv), CURRENT);
+        migrate(&mut drv).unwrap();
+        assert_eq!(drv, json!({"name": "Anice", "license": {"number": "ab"}}));
+    }
+
+    #[test]
+    fn newer_rejected() {
+        let mut drv = json!({"name": "Anice", "schema": CURRENT + 1});
+        assert!(migrate(&mut drv).is_err());
+        assert!(migrate(&mut json!([])).is_err());
+    }
+}
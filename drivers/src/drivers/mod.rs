@@ -4,18 +4,76 @@ use crate::{
 };
 use actix_web::{
     dev::HttpServiceFactory,
+    rt,
     Result,
 };
 use futures::future::BoxFuture;
 use std::future::Future;
+use std::io;
+use std::time::Duration;
 
+pub mod audit;
 pub mod entity;
-mod repository;
+pub(crate) mod lifecycle;
+pub mod migration;
+pub(crate) mod projection;
+pub(crate) mod repository;
 pub mod rest;
 pub mod service;
 
 pub fn routes() -> impl HttpServiceFactory + 'static {
-    rest::new()
+    (rest::new(), rest::audit())
+}
+
+/// Migrates all the stored drivers to the current schema. Fails if any of
+/// them is corrupt, after migrating the others.
+pub async fn migrate(db: Db) -> io::Result<()> {
+    let report = repository::migrate(db)
+        .await
+        .map_err(|err| io::Error::other(err.to_string()))?;
+    log::info!(
+        "Migrated {} drivers to schema version {}",
+        report.rewritten,
+        migration::CURRENT
+    );
+    for (id, err) in &report.corrupt {
+        log::error!("Corrupt driver {}: {}", id, err);
+    }
+    match report.corrupt.len() {
+        0 => Ok(()),
+        n => Err(io::Error::other(format!("{} corrupt drivers", n))),
+    }
+}
+
+/// Re-encrypts the sensitive driver fields in the background, every given
+/// period, so retired keys can eventually be dropped.
+pub fn reencrypt_every(db: Db, every: Duration) {
+    if !db.cipher.enabled() {
+        return;
+    }
+    rt::spawn(async move {
+        let mut interval = rt::time::interval(every);
+        loop {
+            interval.tick().await;
+            match repository::reencrypt(db.clone()).await {
+                Ok(report) => {
+                    if report.rewritten > 0 {
+                        log::info!("Re-encrypted {} drivers", report.rewritten);
+                    }
+                    for (id, err) in report.corrupt {
+                        log::error!(
+                            "Failed to re-encrypt driver {}: {}",
+                            id,
+                            err
+                        );
+                    }
+                }
+                Err(err) => {
+                    log::error!("Failed to re-encrypt drivers: {}", err)
+                }
+            }
+        }
+    });
 }
 
 pub(crate) struct Binding {
@@ -30,6 +88,18 @@ impl Default for Binding {
     }
 }
 
+#[cfg(test)]
+impl Binding {
+    pub(crate) fn in_memory(repo: repository::memory::InMemory) -> Self {
+        Self {
+            repo_factory: Box::new(move |_| {
+                let repo = repo.clone();
+                async move { Ok(Box::new(repo) as Box<dyn Repository>) }
+            }),
+        }
+    }
+}
+
 // See: https://stackoverflow.com/a/66070319/844449
 pub(crate) trait AsyncFactory {
     fn call(&self, args: Db)
@@ -0,0 +1,413 @@
+use actix_web::{
+    error,
+    Result,
+};
+use chrono::{
+    DateTime,
+    Utc,
+};
+use schemars::JsonSchema;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+
+use crate::drivers::{
+    audit::Record,
+    entity::{
+        Driver,
+        Legacy,
+        NewDriver,
+        Status,
+        Type,
+    },
+    repository::Repository,
+};
+use crate::support::{
+    clock::Clock,
+    id::{
+        Identifier,
+        ID,
+    },
+};
+
+/// Actor of the audit records of the changes projected from the legacy app.
+pub(crate) const ACTOR: &str = "legacy";
+
+/// A driver as changed in the legacy app, at some version of it.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct LegacyDriver {
+    pub(crate) driver_id:  Identifier,
+    pub(crate) version:    u64,
+    pub(crate) updated_at: DateTime<Utc>,
+    #[serde(default)]
+    pub(crate) status:     Status,
+    #[serde(default)]
+    pub(crate) r#type:     Type,
+    #[serde(flatten)]
+    pub(crate) driver:     NewDriver,
+}
+
+/// What became of a change projected.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Projected {
+    Created,
+    Updated,
+    /// Skipped, as older than the driver stored.
+    Stale,
+    /// Skipped, as the driver was erased.
+    Erased,
+}
+
+/// Stores the driver as changed in the legacy app, unless the change is
+/// older than the version last projected, or than a change made here since,
+/// or the driver was erased. Versions are compared first, then when they
+/// were changed. Drivers are stored as they are in the legacy app, which
+/// stays the authority on them, so they aren't validated.
+pub(crate) async fn project(
+    repo: &mut dyn Repository,
+    clock: &Clock,
+    drv: LegacyDriver,
+) -> Result<Projected> {
+    let id = drv.driver_id.clone();
+    let curr = match repo.get(&id).await {
+        Ok(curr) => Some(curr),
+        Err(err) if err.as_response_error().status_code() == 404 => None,
+        Err(err) => return Err(err),
+    };
+    if let Some(curr) = &curr {
+        if curr.erased {
+            log::debug!("Skipping version {} of erased {}", drv.version, id);
+            return Ok(Projected::Erased);
+        }
+        if stale(repo, &id, curr, &drv).await? {
+            log::debug!("Skipping stale version {} of {}", drv.version, id);
+            return Ok(Projected::Stale);
+        }
+    }
+
+    let NewDriver {
+        name,
+        surname,
+        photo,
+        license,
+        attributes,
+        fee,
+    } = drv.driver;
+    let inst = ID {
+        id,
+        entity: Driver {
+            name,
+            surname,
+            status: drv.status,
+            r#type: drv.r#type,
+            photo,
+            license,
+            attributes,
+            fee,
+            legacy: Some(Legacy {
+                version:    drv.version,
+                updated_at: drv.updated_at,
+            }),
+            erased: false,
+        },
+    };
+    let rec = Record::by(
+        ACTOR,
+        clock,
+        "project",
+        &inst.id,
+        curr.as_ref(),
+        &inst.entity,
+    )?;
+    match curr {
+        Some(curr) if repo.replace(&inst, &curr, &rec).await? => {
+            Ok(Projected::Updated)
+        }
+        None if repo.create(&inst, &rec).await? => Ok(Projected::Created),
+        // Changed meanwhile, to be projected again.
+        Some(_) => Err(error::ErrorConflict("Driver changed concurrently")),
+        None => Err(error::ErrorConflict("Driver created concurrently")),
+    }
+}
+
+async fn stale(
+    repo: &mut dyn Repository,
+    id: &Identifier,
+    curr: &Driver,
+    drv: &LegacyDriver,
+) -> Result<bool> {
+    if let Some(seen) = &curr.legacy {
+        if (drv.version, drv.updated_at) <= (seen.version, seen.updated_at) {
+            return Ok(true);
+        }
+    }
+    // Changes made here, as by commands, win over older ones, even after a
+    // version was projected.
+    let audit = repo.audit_of(id).await?;
+    let last = audit.iter().rev().find(|rec| rec.actor != ACTOR);
+    Ok(last.is_some_and(|rec| rec.at > drv.updated_at))
+}
+
+/// Counts of the drivers backfilled, and those which couldn't be.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Report {
+    pub(crate) created: usize,
+    pub(crate) updated: usize,
+    pub(crate) stale:   usize,
+    pub(crate) erased:  usize,
+    /// The drivers not backfilled, by their index in the listing, from 0,
+    /// with the reason.
+    pub(crate) failed:  Vec<(usize, String)>,
+}
+
+/// Projects all the drivers listed by the legacy app, fetched from the URL
+/// page by page, numbered from 0 with `page` and sized by `size`, until a
+/// page isn't full, or is empty. Drivers listed are as in the events of
+/// their changes.
+pub(crate) async fn backfill(
+    repo: &mut dyn Repository,
+    clock: &Clock,
+    client: &reqwest::Client,
+    url: &str,
+    size: usize,
+) -> Result<Report> {
+    let mut report = Report::default();
+    for page in 0.. {
+        let resp = client
+            .get(url)
+            .query(&[("page", page), ("size", size)])
+            .send()
+            .await
+            .and_then(|resp| resp.error_for_status())
+            .map_err(error::ErrorBadGateway)?;
+        let body = resp.bytes().await.map_err(error::ErrorBadGateway)?;
+        let drvs: Vec<serde_json::Value> =
+            serde_json::from_slice(&body).map_err(error::ErrorBadGateway)?;
+        log::info!("Backfilling {} drivers of page {}", drvs.len(), page);
+
+        let full = !drvs.is_empty() && drvs.len() >= size;
+        for (n, drv) in drvs.into_iter().enumerate() {
+            let n = page * size + n;
+            let drv = match serde_json::from_value(drv) {
+                Ok(drv) => drv,
+                Err(err) => {
+                    report.failed.push((n, err.to_string()));
+                    continue;
+                }
+            };
+            match project(repo, clock, drv).await {
+                Ok(Projected::Created) => report.created += 1,
+                Ok(Projected::Updated) => report.updated += 1,
+                Ok(Projected::Stale) => report.stale += 1,
+                Ok(Projected::Erased) => report.erased += 1,
+                Err(err) if err.as_response_error().status_code() == 409 => {
+                    report.failed.push((n, err.to_string()))
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        if !full {
+            break;
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{
+            Arc,
+            Mutex,
+        },
+    };
+
+    use actix_web::{
+        web,
+        App,
+        HttpResponse,
+        HttpServer,
+    };
+    use chrono::{
+        Local,
+        TimeZone,
+    };
+
+    use super::*;
+    use crate::drivers::{
+        entity::ERASED,
+        lifecycle,
+        repository::memory::InMemory,
+    };
+    use crate::support::clock::Fixed;
+
+    fn clock() -> Clock {
+        Clock::FixedClock(Fixed {
+            time: Utc
+                .with_ymd_and_hms(2023, 5, 1, 12, 0, 0)
+                .unwrap()
+                .with_timezone(&Local),
+        })
+    }
+
+    fn changed(
+        id: i64,
+        version: u64,
+        day: u32,
+        name: &str,
+    ) -> serde_json::Value {
+        serde_json::json!({
+            "driver-id": id,
+            "version": version,
+            "updated-at": format!("2023-05-{:02}T10:00:00Z", day),
+            "name": name,
+            "surname": "Doe",
+            "status": "Active",
+        })
+    }
+
+    async fn projected(
+        repo: &mut InMemory,
+        id: i64,
+        version: u64,
+        day: u32,
+        name: &str,
+    ) -> Projected {
+        let drv = serde_json::from_value(changed(id, version, day, name));
+        project(repo, &clock(), drv.unwrap()).await.unwrap()
+    }
+
+    #[actix_web::test]
+    async fn projects() {
+        let mut repo = InMemory::default();
+        let repo = &mut repo;
+        assert_eq!(projected(repo, 7, 1, 1, "John").await, Projected::Created);
+        assert_eq!(projected(repo, 7, 2, 2, "Jim").await, Projected::Updated);
+        // Redelivered, or older.
+        assert_eq!(projected(repo, 7, 2, 2, "Jim").await, Projected::Stale);
+        assert_eq!(projected(repo, 7, 1, 3, "John").await, Projected::Stale);
+        // The same version, changed later.
+        assert_eq!(projected(repo, 7, 2, 3, "Jack").await, Projected::Updated);
+
+        let id = Identifier::from(7);
+        let drv = repo.get(&id).await.unwrap();
+        assert_eq!(drv.name, "Jack");
+        assert_eq!(drv.status, Status::Active);
+        assert_eq!(drv.legacy.unwrap().version, 2);
+        let audit = repo.audit_of(&id).await.unwrap();
+        assert_eq!(audit.len(), 3);
+        assert!(audit.iter().all(|rec| rec.actor == ACTOR));
+    }
+
+    #[actix_web::test]
+    async fn local_changes_win() {
+        let mut repo = InMemory::default();
+        let drv = ID {
+            id:     Identifier::from(8),
+            entity: Driver {
+                name: "Jane".to_string(),
+                surname: "Roe".to_string(),
+                ..Driver::default()
+            },
+        };
+        let rec = Record::by("hr", &clock(), "add", &drv.id, None, &drv.entity);
+        repo.create(&drv, &rec.unwrap()).await.unwrap();
+
+        // Added here on May 1st, at noon.
+        let repo = &mut repo;
+        assert_eq!(projected(repo, 8, 1, 1, "Janet").await, Projected::Stale);
+        assert_eq!(projected(repo, 8, 1, 2, "Janet").await, Projected::Updated);
+        assert_eq!(repo.get(&drv.id).await.unwrap().name, "Janet");
+
+        // Changed here again, at noon, after the version was projected.
+        lifecycle::graduate(repo, &clock(), "hr", drv.id.clone())
+            .await
+            .unwrap();
+        assert_eq!(projected(repo, 8, 2, 1, "Jan").await, Projected::Stale);
+        assert_eq!(repo.get(&drv.id).await.unwrap().r#type, Type::Regular);
+        assert_eq!(projected(repo, 8, 2, 2, "Jan").await, Projected::Updated);
+    }
+
+    #[actix_web::test]
+    async fn keeps_erased() {
+        let mut repo = InMemory::default();
+        let repo = &mut repo;
+        assert_eq!(projected(repo, 9, 1, 1, "John").await, Projected::Created);
+        let id = Identifier::from(9);
+        lifecycle::erase(repo, &clock(), "hr", id.clone())
+            .await
+            .unwrap();
+
+        // Newer, or projected again by a backfill.
+        assert_eq!(projected(repo, 9, 2, 2, "John").await, Projected::Erased);
+        assert_eq!(projected(repo, 9, 1, 1, "John").await, Projected::Erased);
+        let drv = repo.get(&id).await.unwrap();
+        assert!(drv.erased);
+        assert_eq!(drv.name, ERASED);
+        assert_eq!(drv.surname, ERASED);
+    }
+
+    #[actix_web::test]
+    async fn backfills() {
+        let requested = Arc::new(Mutex::new(vec![]));
+        let legacy = {
+            let requested = requested.clone();
+            HttpServer::new(move || {
+                let requested = requested.clone();
+                App::new().route(
+                    "/drivers",
+                    web::get().to(
+                        move |query: web::Query<HashMap<String, usize>>| {
+                            requested.lock().unwrap().push(query.clone());
+                            let page = match query["page"] {
+                                0 => vec![
+                                    changed(1, 1, 1, "John"),
+                                    changed(2, 1, 1, "Jane"),
+                                ],
+                                1 => vec![
+                                    changed(3, 1, 1, "Jim"),
+                                    serde_json::json!({"driver-id": 4}),
+                                ],
+                                _ => vec![],
+                            };
+                            async move { HttpResponse::Ok().json(page) }
+                        },
+                    ),
+                )
+            })
+            .bind(("127.0.0.1", 0))
+            .unwrap()
+        };
+        let addr = legacy.addrs()[0];
+        let legacy = legacy.run();
+        let handle = legacy.handle();
+        actix_web::rt::spawn(legacy);
+
+        let mut repo = InMemory::default();
+        projected(&mut repo, 2, 2, 2, "Jane").await;
+        let client = reqwest::Client::new();
+        let url = format!("http://{}/drivers", addr);
+        let report = backfill(&mut repo, &clock(), &client, &url, 2)
+            .await
+            .unwrap();
+        assert_eq!(report.created, 2);
+        assert_eq!(report.updated, 0);
+        assert_eq!(report.stale, 1);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].0, 3);
+        assert_eq!(repo.drivers.lock().unwrap().len(), 3);
+
+        // The second page was full too, so a third was asked for.
+        let pages: Vec<usize> = requested
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|q| q["page"])
+            .collect();
+        assert_eq!(pages, [0, 1, 2]);
+        handle.stop(true).await;
+    }
+}
@@ -3,46 +3,180 @@ use actix_web::{
     Result,
 };
 use async_trait::async_trait;
-use redis::aio::ConnectionManager;
+use chrono::{
+    DateTime,
+    Utc,
+};
+use serde::Serialize;
+use serde_json::Value;
 
-use crate::support::id::Identifier;
+use crate::support::id::{
+    Generator,
+    Identifier,
+};
 use crate::{
-    app::config::Db,
+    app::{
+        config::Db,
+        metrics,
+    },
     support::{
+        clock::Clock,
+        crypto::Cipher,
         id::ID,
         page::Page,
+        pool::Connection,
+        trace,
     },
 };
 
-use super::entity::Driver;
+use super::{
+    audit::Record,
+    entity::Driver,
+    migration,
+};
+
+/// Stream of the audit records of all the drivers.
+const AUDIT: &str = "drivers-audit";
+
+/// Most audit records read at once.
+const AUDIT_BATCH: usize = 1000;
+
+lazy_static! {
+    /// Stores a new driver as `set_audited` does, unless its key exists.
+    static ref CREATE: redis::Script = redis::Script::new(
+        r"
+        if not redis.call('JSON.SET', KEYS[1], '$', ARGV[1], 'NX') then
+            return 0
+        end
+        redis.call('ZADD', KEYS[2], ARGV[2], ARGV[3])
+        redis.call('XADD', KEYS[3], '*', 'record', ARGV[4])
+        redis.call('XADD', KEYS[4], '*', 'record', ARGV[4])
+        return 1
+        ",
+    );
+    /// Stores the driver as `set_audited` does, unless it changed since it
+    /// was read.
+    static ref REPLACE: redis::Script = redis::Script::new(
+        r"
+        if redis.call('JSON.GET', KEYS[1], '$') ~= ARGV[1] then
+            return 0
+        end
+        redis.call('JSON.SET', KEYS[1], '$', ARGV[2])
+        redis.call('ZADD', KEYS[2], ARGV[3], ARGV[4])
+        redis.call('XADD', KEYS[3], '*', 'record', ARGV[5])
+        redis.call('XADD', KEYS[4], '*', 'record', ARGV[5])
+        return 1
+        ",
+    );
+    /// Stores the driver rewritten, unless it changed since it was read.
+    static ref REWRITE: redis::Script = redis::Script::new(
+        r"
+        if redis.call('JSON.GET', KEYS[1], '$') ~= ARGV[1] then
+            return 0
+        end
+        redis.call('JSON.SET', KEYS[1], '$', ARGV[2])
+        return 1
+        ",
+    );
+    /// Stores the erased driver, and replaces the audit records given by
+    /// pseudonymized ones, as `erase` does, unless the driver, or its last
+    /// audit record, changed since they were read.
+    static ref ERASE: redis::Script = redis::Script::new(
+        r"
+        if redis.call('JSON.GET', KEYS[1], '$') ~= ARGV[1] then
+            return 0
+        end
+        local last = redis.call('XREVRANGE', KEYS[4], '+', '-', 'COUNT', 1)
+        if (last[1] and last[1][1] or '') ~= ARGV[2] then
+            return 0
+        end
+        redis.call('JSON.SET', KEYS[1], '$', ARGV[3])
+        redis.call('ZADD', KEYS[2], ARGV[4], ARGV[5])
+        redis.call('DEL', KEYS[4])
+        local i = 8
+        for _ = 1, tonumber(ARGV[7]) do
+            redis.call('XDEL', KEYS[3], ARGV[i])
+            redis.call('XADD', KEYS[3], '*', 'record', ARGV[i + 1])
+            i = i + 2
+        end
+        while i < #ARGV do
+            redis.call('XADD', KEYS[4], ARGV[i], 'record', ARGV[i + 1])
+            i = i + 2
+        end
+        redis.call('XADD', KEYS[3], '*', 'record', ARGV[6])
+        redis.call('XADD', KEYS[4], '*', 'record', ARGV[6])
+        return 1
+        ",
+    );
+}
+
+/// Driver fields encrypted at rest, as the top level field and the JSON
+/// pointer within it.
+const SENSITIVE: &[(&str, &str)] = &[
+    ("license", "/number"),
+    ("attributes", "/email"),
+    ("attributes", "/medical-examination-remarks"),
+];
 
 #[async_trait]
 pub(crate) trait Repository {
-    async fn exists(&mut self, key: &str) -> Result<bool>;
-
-    async fn list(&mut self, page: &Page) -> Result<Vec<ID<Driver>>>;
+    async fn list(&mut self, page: &Page) -> Result<Listing>;
 
     async fn get(&mut self, id: &Identifier) -> Result<Driver>;
 
     async fn count(&mut self) -> Result<isize>;
 
-    async fn set(&mut self, drv: &ID<Driver>) -> Result<()>;
+    /// Stores the driver along with the audit record of its mutation.
+    async fn set_audited(
+        &mut self,
+        drv: &ID<Driver>,
+        rec: &Record,
+    ) -> Result<()>;
+
+    /// Stores the new driver along with the audit record of its creation,
+    /// unless a driver with its ID already exists. Tells whether it was
+    /// stored.
+    async fn create(&mut self, drv: &ID<Driver>, rec: &Record) -> Result<bool>;
+
+    /// Stores the driver along with the audit record of its mutation, unless
+    /// the driver changed since it was read as `curr`. Tells whether it was
+    /// stored.
+    async fn replace(
+        &mut self,
+        drv: &ID<Driver>,
+        curr: &Driver,
+        rec: &Record,
+    ) -> Result<bool>;
+
+    /// Lists the audit records of the driver, oldest first.
+    async fn audit_of(&mut self, id: &Identifier) -> Result<Vec<Record>>;
+
+    /// Lists the audit records of all the drivers, made since the given
+    /// time, oldest first.
+    async fn audit_since(
+        &mut self,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<Record>>;
+
+    /// Stores the erased driver along with the record of the erasure, and
+    /// pseudonymizes the audit records of the driver, unless the driver
+    /// changed since it was read as `curr`. Tells whether it was erased.
+    async fn erase(
+        &mut self,
+        drv: &ID<Driver>,
+        curr: &Driver,
+        rec: &Record,
+    ) -> Result<bool>;
 }
 
 struct RedisRepository {
-    conn: ConnectionManager,
+    conn:   Connection,
+    cipher: Cipher,
 }
 
 #[async_trait]
 impl Repository for RedisRepository {
-    async fn exists(&mut self, key: &str) -> Result<bool> {
-        redis::Cmd::exists(key)
-            .query_async(&mut self.conn)
-            .await
-            .map_err(error::ErrorInternalServerError)
-    }
-
-    async fn list(&mut self, page: &Page) -> Result<Vec<ID<Driver>>> {
+    async fn list(&mut self, page: &Page) -> Result<Listing> {
         let query =
             redis::Cmd::zrange("drivers-idx", page.start(), page.stop());
 
@@ -57,48 +191,46 @@ impl Repository for RedisRepository {
         log::debug!("keys: {:?}", keys);
 
         if keys.is_empty() {
-            return Ok(vec![]);
+            return Ok(Listing::default());
         }
 
-        let query = redis::Cmd::json_get(keys, "$")
-            .map_err(error::ErrorInternalServerError)?;
-
-        let drvs: Vec<String> = query
+        let drvs: Vec<Option<String>> = redis::cmd("JSON.MGET")
+            .arg(&keys)
+            .arg("$")
             .query_async(&mut self.conn)
             .await
             .map_err(error::ErrorInternalServerError)?;
 
         log::trace!("drvs: {:?}", drvs);
 
-        let drvs: Result<Vec<Vec<Driver>>> = drvs
-            .into_iter()
-            .map(|drv| {
-                serde_json::from_str(&drv)
-                    .map_err(error::ErrorInternalServerError)
-            })
-            .collect();
-
-        let drvs = drvs?;
-
-        let drvs: Vec<Driver> = drvs.into_iter().flatten().collect();
-
-        log::trace!("drvs: {:?}", drvs);
-
-        let drvs: Vec<ID<Driver>> = drvs
-            .iter()
-            .zip(ids.iter())
-            .map(|(drv, id)| ID {
-                id:     Identifier::from(id),
-                entity: drv.clone(),
-            })
-            .collect();
+        // A corrupt driver is skipped, so the rest of the page is still
+        // listed, and told to the caller.
+        let mut listing = Listing::default();
+        for (id, drv) in ids.iter().zip(drvs) {
+            let drv = drv
+                .ok_or(error::ErrorNotFound("Driver not found"))
+                .and_then(|drv| parse(&self.cipher, &drv))
+                .and_then(|drv| {
+                    Identifier::decode(id)
+                        .map(|id| (id, drv))
+                        .map_err(error::ErrorInternalServerError)
+                });
+            match drv {
+                Ok((id, drv)) => listing.drivers.push(ID { id, entity: drv }),
+                Err(err) => {
+                    log::error!("Skipping corrupt driver {}: {}", id, err);
+                    metrics::corrupt_driver();
+                    listing.skipped.push(id.clone());
+                }
+            }
+        }
 
-        log::debug!("drvs: {:?}", drvs);
-        Ok(drvs)
+        log::debug!("listing: {:?}", listing);
+        Ok(listing)
     }
 
     async fn get(&mut self, id: &Identifier) -> Result<Driver> {
-        let key = format!("drivers:{}", id.to_string());
+        let key = format!("drivers:{}", id);
         let query = redis::Cmd::json_get(key, "$")
             .map_err(error::ErrorInternalServerError)?;
         let drvs: Option<String> = query
@@ -108,23 +240,10 @@ impl Repository for RedisRepository {
 
         log::trace!("drvs: {:?}", drvs);
 
-        let drvs: Vec<Driver> = match drvs {
-            Some(drvs) => serde_json::from_str(&drvs)
-                .map_err(error::ErrorInternalServerError)?,
-            None => return Err(error::ErrorNotFound("Driver not found")),
-        };
-
-        if drvs.len() != 1 {
-            log::error!("Invalid driver: {:?}", drvs);
-            return Err(error::ErrorInternalServerError("Invalid driver"));
+        match drvs {
+            Some(drvs) => parse(&self.cipher, &drvs),
+            None => Err(error::ErrorNotFound("Driver not found")),
         }
-
-        let drv = drvs
-            .into_iter()
-            .next()
-            .ok_or(error::ErrorInternalServerError("Invalid driver"))?;
-
-        Ok(drv)
     }
 
     async fn count(&mut self) -> Result<isize> {
@@ -134,32 +253,796 @@ impl Repository for RedisRepository {
             .map_err(error::ErrorInternalServerError)
     }
 
-    async fn set(&mut self, drv: &ID<Driver>) -> Result<()> {
+    async fn set_audited(
+        &mut self,
+        drv: &ID<Driver>,
+        rec: &Record,
+    ) -> Result<()> {
+        let id = drv.id.to_string();
+        let key = format!("drivers:{}", &id);
+        let json = seal_record(&self.cipher, rec)?;
+        let set =
+            redis::Cmd::json_set(key, "$", &seal(&self.cipher, &drv.entity)?)
+                .map_err(error::ErrorInternalServerError)?;
+        redis::pipe()
+            .atomic()
+            .add_command(set)
+            .ignore()
+            .zadd("drivers-idx", &id, drv.id.int())
+            .ignore()
+            .xadd(AUDIT, "*", &[("record", &json)])
+            .ignore()
+            .xadd(audit_key(&drv.id), "*", &[("record", &json)])
+            .ignore()
+            .query_async::<_, ()>(&mut self.conn)
+            .await
+            .map_err(error::ErrorInternalServerError)
+    }
+
+    async fn create(&mut self, drv: &ID<Driver>, rec: &Record) -> Result<bool> {
+        let id = drv.id.to_string();
+        let drv_json = seal(&self.cipher, &drv.entity)?.to_string();
+        let rec_json = seal_record(&self.cipher, rec)?;
+        CREATE
+            .key(format!("drivers:{}", &id))
+            .key("drivers-idx")
+            .key(AUDIT)
+            .key(audit_key(&drv.id))
+            .arg(drv_json)
+            .arg(drv.id.int())
+            .arg(&id)
+            .arg(rec_json)
+            .invoke_async(&mut self.conn)
+            .await
+            .map_err(error::ErrorInternalServerError)
+    }
+
+    async fn replace(
+        &mut self,
+        drv: &ID<Driver>,
+        curr: &Driver,
+        rec: &Record,
+    ) -> Result<bool> {
         let id = drv.id.to_string();
         let key = format!("drivers:{}", &id);
-        let query = redis::Cmd::json_set(key, "$", &drv.entity)
+        let raw = match self.read_unchanged(&key, curr).await? {
+            Some(raw) => raw,
+            None => return Ok(false),
+        };
+        let drv_json = seal(&self.cipher, &drv.entity)?.to_string();
+        let rec_json = seal_record(&self.cipher, rec)?;
+        REPLACE
+            .key(&key)
+            .key("drivers-idx")
+            .key(AUDIT)
+            .key(audit_key(&drv.id))
+            .arg(raw)
+            .arg(drv_json)
+            .arg(drv.id.int())
+            .arg(&id)
+            .arg(rec_json)
+            .invoke_async(&mut self.conn)
+            .await
+            .map_err(error::ErrorInternalServerError)
+    }
+
+    async fn audit_of(&mut self, id: &Identifier) -> Result<Vec<Record>> {
+        let key = audit_key(id);
+        self.read_audit(&key, DateTime::<Utc>::MIN_UTC).await
+    }
+
+    async fn audit_since(
+        &mut self,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<Record>> {
+        self.read_audit(AUDIT, since).await
+    }
+
+    async fn erase(
+        &mut self,
+        drv: &ID<Driver>,
+        curr: &Driver,
+        rec: &Record,
+    ) -> Result<bool> {
+        let id = drv.id.to_string();
+        let key = format!("drivers:{}", &id);
+        let raw = match self.read_unchanged(&key, curr).await? {
+            Some(raw) => raw,
+            None => return Ok(false),
+        };
+
+        // Stream entries can't be changed, so the records of the driver are
+        // replaced by pseudonymized ones. In the stream of all the drivers,
+        // they go to its end, as IDs must grow.
+        let audit = audit_key(&drv.id);
+        let all = self
+            .scan_entries(AUDIT, "-", |r| r.driver_id == drv.id)
+            .await?;
+        let own = self.scan_entries(&audit, "-", |_| true).await?;
+        let last = own.last().map(|(entry, _)| entry.clone());
+
+        let mut script = ERASE.key(&key);
+        script
+            .key("drivers-idx")
+            .key(AUDIT)
+            .key(&audit)
+            .arg(raw)
+            .arg(last.unwrap_or_default())
+            .arg(seal(&self.cipher, &drv.entity)?.to_string())
+            .arg(drv.id.int())
+            .arg(&id)
+            .arg(seal_record(&self.cipher, rec)?)
+            .arg(all.len());
+        for (entry, r) in all.into_iter().chain(own) {
+            script
+                .arg(entry)
+                .arg(seal_record(&self.cipher, &r.erased())?);
+        }
+        script
+            .invoke_async(&mut self.conn)
+            .await
+            .map_err(error::ErrorInternalServerError)
+    }
+}
+
+impl RedisRepository {
+    /// Reads the driver as stored, unless it changed since it was read as
+    /// `curr`, to be compared with as is in scripts.
+    async fn read_unchanged(
+        &mut self,
+        key: &str,
+        curr: &Driver,
+    ) -> Result<Option<String>> {
+        let query = redis::Cmd::json_get(key, "$")
             .map_err(error::ErrorInternalServerError)?;
-        query
+        let raw: Option<String> = query
             .query_async(&mut self.conn)
             .await
             .map_err(error::ErrorInternalServerError)?;
+        let raw = raw.ok_or(error::ErrorNotFound("Driver not found"))?;
+        let unchanged = serde_json::to_value(parse(&self.cipher, &raw)?)?
+            == serde_json::to_value(curr)?;
+        Ok(unchanged.then_some(raw))
+    }
 
-        redis::Cmd::zadd("drivers-idx", &id, drv.id.int())
+    async fn read_audit(
+        &mut self,
+        key: &str,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<Record>> {
+        // Entry IDs start with the time they were appended at, in ms.
+        let start = format!("{}", since.timestamp_millis().max(0));
+        let mut recs: Vec<Record> = self
+            .scan_entries(key, &start, |rec| rec.at >= since)
+            .await?
+            .into_iter()
+            .map(|(_, rec)| rec)
+            .collect();
+        // Records of erased drivers are appended again, out of order.
+        recs.sort_by_key(|rec| rec.at);
+        Ok(recs)
+    }
+
+    /// Reads the stream from the start to its end, in batches, keeping the
+    /// records matching, by their entry ID.
+    async fn scan_entries(
+        &mut self,
+        key: &str,
+        start: &str,
+        keep: impl Fn(&Record) -> bool + Send,
+    ) -> Result<Vec<(String, Record)>> {
+        let mut kept = vec![];
+        let mut start = start.to_string();
+        loop {
+            let reply = self.read_range(key, &start, AUDIT_BATCH).await?;
+            let full = reply.ids.len() >= AUDIT_BATCH;
+            for entry in reply.ids {
+                start = format!("({}", entry.id);
+                let rec = match entry.get::<String>("record") {
+                    Some(rec) => unseal_record(&self.cipher, &rec)?,
+                    None => continue,
+                };
+                if keep(&rec) {
+                    kept.push((entry.id, rec));
+                }
+            }
+            if !full {
+                return Ok(kept);
+            }
+        }
+    }
+
+    async fn read_range(
+        &mut self,
+        key: &str,
+        start: &str,
+        count: usize,
+    ) -> Result<redis::streams::StreamRangeReply> {
+        redis::Cmd::xrange_count(key, start, "+", count)
             .query_async(&mut self.conn)
             .await
+            .map_err(error::ErrorInternalServerError)
+    }
+
+    /// Rewrites the stored driver in its current form, if it's stale. Read
+    /// again if it changes meanwhile, as it may then be current already.
+    async fn rewrite(
+        &mut self,
+        id: &str,
+        stale: &impl Fn(&Cipher, &Value) -> bool,
+    ) -> Result<bool> {
+        let key = format!("drivers:{}", id);
+        for _ in 0..REWRITE_ATTEMPTS {
+            let query = redis::Cmd::json_get(&key, "$")
+                .map_err(error::ErrorInternalServerError)?;
+            let raw: Option<String> =
+                query
+                    .query_async(&mut self.conn)
+                    .await
+                    .map_err(error::ErrorInternalServerError)?;
+            let raw = match raw {
+                Some(raw) => raw,
+                None => return Ok(false),
+            };
+            let drv = stored(&raw)?;
+            if !stale(&self.cipher, &drv) {
+                return Ok(false);
+            }
+
+            let drv = seal(&self.cipher, &unseal(&self.cipher, drv)?)?;
+            let swapped: bool = REWRITE
+                .key(&key)
+                .arg(raw)
+                .arg(drv.to_string())
+                .invoke_async(&mut self.conn)
+                .await
+                .map_err(error::ErrorInternalServerError)?;
+            if swapped {
+                return Ok(true);
+            }
+        }
+        Err(error::ErrorConflict("Driver changed while rewritten"))
+    }
+}
+
+/// Parses the driver, as stored and returned by `JSON.GET` with a path.
+fn parse(cipher: &Cipher, raw: &str) -> Result<Driver> {
+    unseal(cipher, stored(raw)?)
+}
+
+fn stored(raw: &str) -> Result<Value> {
+    let drvs: Vec<Value> =
+        serde_json::from_str(raw).map_err(error::ErrorInternalServerError)?;
+    match <[Value; 1]>::try_from(drvs) {
+        Ok([drv]) => Ok(drv),
+        Err(drvs) => {
+            log::error!("Invalid driver: {:?}", drvs);
+            Err(error::ErrorInternalServerError("Invalid driver"))
+        }
+    }
+}
+
+/// The driver as stored, with the sensitive fields encrypted.
+fn seal(cipher: &Cipher, drv: &Driver) -> Result<Value> {
+    let mut val = serde_json::to_value(drv)?;
+    map_sensitive(&mut val, &driver_pointers(), |v| cipher.encrypt(v))?;
+    migration::stamp(&mut val);
+    Ok(val)
+}
+
+/// The driver as read, decrypted and migrated to the current schema.
+fn unseal(cipher: &Cipher, mut val: Value) -> Result<Driver> {
+    map_sensitive(&mut val, &driver_pointers(), |v| cipher.decrypt(v))?;
+    migration::migrate(&mut val).map_err(error::ErrorInternalServerError)?;
+    serde_json::from_value(val).map_err(error::ErrorInternalServerError)
+}
+
+fn seal_record(cipher: &Cipher, rec: &Record) -> Result<String> {
+    let mut val = serde_json::to_value(rec)?;
+    map_sensitive(&mut val, &record_pointers(), |v| cipher.encrypt(v))?;
+    Ok(val.to_string())
+}
+
+fn unseal_record(cipher: &Cipher, json: &str) -> Result<Record> {
+    let mut val: Value =
+        serde_json::from_str(json).map_err(error::ErrorInternalServerError)?;
+    map_sensitive(&mut val, &record_pointers(), |v| cipher.decrypt(v))?;
+    serde_json::from_value(val).map_err(error::ErrorInternalServerError)
+}
+
+fn driver_pointers() -> Vec<String> {
+    SENSITIVE
+        .iter()
+        .map(|(field, ptr)| format!("/{}{}", field, ptr))
+        .collect()
+}
+
+fn record_pointers() -> Vec<String> {
+    SENSITIVE
+        .iter()
+        .flat_map(|(field, ptr)| {
+            ["before", "after"]
+                .map(|side| format!("/changes/{}/{}{}", field, side, ptr))
+        })
+        .collect()
+}
+
+fn map_sensitive(
+    val: &mut Value,
+    pointers: &[String],
+    mut f: impl FnMut(&str) -> std::result::Result<String, String>,
+) -> Result<()> {
+    for ptr in pointers {
+        if let Some(Value::String(s)) = val.pointer_mut(ptr) {
+            *s = f(s).map_err(error::ErrorInternalServerError)?;
+        }
+    }
+    Ok(())
+}
+
+/// A page of the stored drivers, along with the IDs of those skipped as they
+/// couldn't be read.
+#[derive(Debug, Default)]
+pub(crate) struct Listing {
+    pub(crate) drivers: Vec<ID<Driver>>,
+    pub(crate) skipped: Vec<String>,
+}
+
+impl Listing {
+    /// Tells whether the page is past the last driver.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.drivers.is_empty() && self.skipped.is_empty()
+    }
+}
+
+/// Outcome of rewriting the stored drivers.
+#[derive(Debug, Default, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) struct Report {
+    pub(crate) rewritten: usize,
+    /// Drivers which couldn't be read, with the reason, by ID.
+    pub(crate) corrupt:   Vec<(String, String)>,
+}
+
+/// Most attempts at rewriting a driver changed meanwhile.
+const REWRITE_ATTEMPTS: usize = 3;
+
+/// Rewrites the stored drivers which are stale, as told from their stored
+/// form. Corrupt drivers are reported, and left as they are.
+async fn rewrite(
+    db: Db,
+    stale: impl Fn(&Cipher, &Value) -> bool,
+) -> Result<Report> {
+    let mut repo = connect(db).await?;
+    let mut report = Report::default();
+    let mut page = Page { num: 1, per: 100 };
+    loop {
+        let ids: Vec<String> =
+            redis::Cmd::zrange("drivers-idx", page.start(), page.stop())
+                .query_async(&mut repo.conn)
+                .await
+                .map_err(error::ErrorInternalServerError)?;
+        if ids.is_empty() {
+            return Ok(report);
+        }
+        for id in ids {
+            match repo.rewrite(&id, &stale).await {
+                Ok(true) => report.rewritten += 1,
+                Ok(false) => (),
+                Err(err) => report.corrupt.push((id, err.to_string())),
+            }
+        }
+        page.num += 1;
+    }
+}
+
+/// Re-encrypts the sensitive fields of all the drivers still encrypted with
+/// other keys than the current one, or not encrypted at all. Audit records
+/// are kept as written, so the keys they were written with must be kept.
+pub(crate) async fn reencrypt(db: Db) -> Result<Report> {
+    if !db.cipher.enabled() {
+        return Ok(Report::default());
+    }
+    rewrite(db, |cipher, drv| {
+        driver_pointers().iter().any(|ptr| match drv.pointer(ptr) {
+            Some(Value::String(v)) => !cipher.is_current(v),
+            _ => false,
+        })
+    })
+    .await
+}
+
+/// Migrates all the drivers stored with older schemas to the current one.
+pub(crate) async fn migrate(db: Db) -> Result<Report> {
+    rewrite(db, |_, drv| migration::version(drv) < migration::CURRENT).await
+}
+
+/// Most IDs tried when creating a driver, in case they're taken by other
+/// nodes.
+const CREATE_ATTEMPTS: usize = 3;
+
+/// Creates the driver under a newly generated ID, retrying with another one
+/// if it's taken, with the audit record made for the ID.
+pub(crate) async fn create(
+    repo: &mut dyn Repository,
+    ids: &Generator,
+    clock: &Clock,
+    mut entity: Driver,
+    record: impl Fn(&Identifier, &Driver) -> Result<Record>,
+) -> Result<ID<Driver>> {
+    for _ in 0..CREATE_ATTEMPTS {
+        let drv = ID {
+            id: ids.next(clock),
+            entity,
+        };
+        if repo.create(&drv, &record(&drv.id, &drv.entity)?).await? {
+            return Ok(drv);
+        }
+        log::warn!("Driver ID {} taken, generating another", drv.id);
+        entity = drv.entity;
+    }
+    Err(error::ErrorServiceUnavailable("No free driver ID"))
+}
+
+/// Rebuilds the index of the drivers from the stored drivers, returning how
+/// many were indexed.
+pub(crate) async fn reindex(db: Db) -> Result<usize> {
+    let mut repo = connect(db).await?;
+    let mut keys: Vec<String> = vec![];
+    let mut cursor = 0;
+    loop {
+        let (next, batch): (u64, Vec<String>) = redis::cmd("SCAN")
+            .arg(cursor)
+            .arg("MATCH")
+            .arg("drivers:*")
+            .arg("COUNT")
+            .arg(1000)
+            .query_async(&mut repo.conn)
+            .await
             .map_err(error::ErrorInternalServerError)?;
+        keys.extend(batch);
+        if next == 0 {
+            break;
+        }
+        cursor = next;
+    }
+    let ids: Vec<Identifier> = keys
+        .iter()
+        .filter_map(|key| key.strip_prefix("drivers:"))
+        .filter(|id| !id.contains(':'))
+        .filter_map(|id| match Identifier::decode(id) {
+            Ok(id) => Some(id),
+            Err(err) => {
+                log::warn!("Not indexing drivers:{}: {}", id, err);
+                None
+            }
+        })
+        .collect();
 
-        Ok(())
+    let mut pipe = redis::pipe();
+    pipe.atomic().del("drivers-idx").ignore();
+    for id in &ids {
+        pipe.zadd("drivers-idx", id.to_string(), id.int()).ignore();
+    }
+    pipe.query_async::<_, ()>(&mut repo.conn)
+        .await
+        .map_err(error::ErrorInternalServerError)?;
+    Ok(ids.len())
+}
+
+fn audit_key(id: &Identifier) -> String {
+    format!("drivers:{}:audit", id)
+}
+
+/// Records a span for each of the calls of the wrapped repository.
+struct Traced<R: Repository + Send> {
+    inner: R,
+}
+
+#[async_trait]
+impl<R: Repository + Send> Repository for Traced<R> {
+    async fn list(&mut self, page: &Page) -> Result<Listing> {
+        trace::in_span("Repository::list", self.inner.list(page)).await
+    }
+
+    async fn get(&mut self, id: &Identifier) -> Result<Driver> {
+        trace::in_span("Repository::get", self.inner.get(id)).await
+    }
+
+    async fn count(&mut self) -> Result<isize> {
+        trace::in_span("Repository::count", self.inner.count()).await
+    }
+
+    async fn set_audited(
+        &mut self,
+        drv: &ID<Driver>,
+        rec: &Record,
+    ) -> Result<()> {
+        trace::in_span(
+            "Repository::set_audited",
+            self.inner.set_audited(drv, rec),
+        )
+        .await
+    }
+
+    async fn create(&mut self, drv: &ID<Driver>, rec: &Record) -> Result<bool> {
+        trace::in_span("Repository::create", self.inner.create(drv, rec)).await
+    }
+
+    async fn replace(
+        &mut self,
+        drv: &ID<Driver>,
+        curr: &Driver,
+        rec: &Record,
+    ) -> Result<bool> {
+        trace::in_span(
+            "Repository::replace",
+            self.inner.replace(drv, curr, rec),
+        )
+        .await
+    }
+
+    async fn audit_of(&mut self, id: &Identifier) -> Result<Vec<Record>> {
+        trace::in_span("Repository::audit_of", self.inner.audit_of(id)).await
+    }
+
+    async fn audit_since(
+        &mut self,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<Record>> {
+        trace::in_span("Repository::audit_since", self.inner.audit_since(since))
+            .await
+    }
+
+    async fn erase(
+        &mut self,
+        drv: &ID<Driver>,
+        curr: &Driver,
+        rec: &Record,
+    ) -> Result<bool> {
+        trace::in_span("Repository::erase", self.inner.erase(drv, curr, rec))
+            .await
     }
 }
 
 pub(crate) async fn new(db: Db) -> Result<Box<dyn Repository>> {
-    let client = db
-        .client
+    Ok(Box::new(Traced {
+        inner: connect(db).await?,
+    }))
+}
+
+async fn connect(db: Db) -> Result<RedisRepository> {
+    let pool = db
+        .pool
         .ok_or(error::ErrorInternalServerError("No redis client"))?;
-    let conn = client
-        .get_tokio_connection_manager()
-        .await
-        .map_err(error::ErrorInternalServerError)?;
-    Ok(Box::new(RedisRepository { conn }))
+    let conn = pool.get().await.map_err(error::ErrorServiceUnavailable)?;
+    Ok(RedisRepository {
+        conn,
+        cipher: db.cipher,
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod memory {
+    use std::{
+        collections::BTreeMap,
+        sync::{
+            Arc,
+            Mutex,
+        },
+    };
+
+    use super::*;
+
+    /// Keeps the drivers in memory, so tests can run without Redis.
+    #[derive(Clone, Default)]
+    pub(crate) struct InMemory {
+        pub(crate) drivers: Arc<Mutex<BTreeMap<i64, Driver>>>,
+        pub(crate) audit:   Arc<Mutex<Vec<Record>>>,
+        /// Drivers listed as if they couldn't be read.
+        pub(crate) corrupt: Arc<Mutex<Vec<i64>>>,
+    }
+
+    #[async_trait]
+    impl Repository for InMemory {
+        async fn list(&mut self, page: &Page) -> Result<Listing> {
+            let drivers = self.drivers.lock().unwrap();
+            let corrupt = self.corrupt.lock().unwrap();
+            let mut listing = Listing::default();
+            for (id, drv) in drivers
+                .iter()
+                .skip(page.start() as usize)
+                .take(page.per as usize)
+            {
+                let id = Identifier::from(*id);
+                if corrupt.contains(&id.int()) {
+                    listing.skipped.push(id.to_string());
+                } else {
+                    listing.drivers.push(ID {
+                        id,
+                        entity: drv.clone(),
+                    });
+                }
+            }
+            Ok(listing)
+        }
+
+        async fn get(&mut self, id: &Identifier) -> Result<Driver> {
+            let drivers = self.drivers.lock().unwrap();
+            drivers
+                .get(&id.int())
+                .cloned()
+                .ok_or(error::ErrorNotFound("Driver not found"))
+        }
+
+        async fn count(&mut self) -> Result<isize> {
+            Ok(self.drivers.lock().unwrap().len() as isize)
+        }
+
+        async fn set_audited(
+            &mut self,
+            drv: &ID<Driver>,
+            rec: &Record,
+        ) -> Result<()> {
+            let mut drivers = self.drivers.lock().unwrap();
+            drivers.insert(drv.id.int(), drv.entity.clone());
+            self.audit.lock().unwrap().push(rec.clone());
+            Ok(())
+        }
+
+        async fn create(
+            &mut self,
+            drv: &ID<Driver>,
+            rec: &Record,
+        ) -> Result<bool> {
+            if self.drivers.lock().unwrap().contains_key(&drv.id.int()) {
+                return Ok(false);
+            }
+            self.set_audited(drv, rec).await?;
+            Ok(true)
+        }
+
+        async fn replace(
+            &mut self,
+            drv: &ID<Driver>,
+            curr: &Driver,
+            rec: &Record,
+        ) -> Result<bool> {
+            if serde_json::to_value(self.get(&drv.id).await?)?
+                != serde_json::to_value(curr)?
+            {
+                return Ok(false);
+            }
+            self.set_audited(drv, rec).await?;
+            Ok(true)
+        }
+
+        async fn audit_of(&mut self, id: &Identifier) -> Result<Vec<Record>> {
+            let audit = self.audit.lock().unwrap();
+            Ok(audit
+                .iter()
+                .filter(|r| &r.driver_id == id)
+                .cloned()
+                .collect())
+        }
+
+        async fn audit_since(
+            &mut self,
+            since: DateTime<Utc>,
+        ) -> Result<Vec<Record>> {
+            let audit = self.audit.lock().unwrap();
+            Ok(audit.iter().filter(|r| r.at >= since).cloned().collect())
+        }
+
+        async fn erase(
+            &mut self,
+            drv: &ID<Driver>,
+            curr: &Driver,
+            rec: &Record,
+        ) -> Result<bool> {
+            if serde_json::to_value(self.get(&drv.id).await?)?
+                != serde_json::to_value(curr)?
+            {
+                return Ok(false);
+            }
+            let mut drivers = self.drivers.lock().unwrap();
+            drivers.insert(drv.id.int(), drv.entity.clone());
+            let mut audit = self.audit.lock().unwrap();
+            *audit = audit
+                .drain(..)
+                .map(|r| match r.driver_id == drv.id {
+                    true => r.erased(),
+                    false => r,
+                })
+                .collect();
+            audit.push(rec.clone());
+            Ok(true)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::drivers::{
+        audit::Change,
+        entity::{
+            Attribute,
+            License,
+        },
+    };
+
+    fn cipher() -> Cipher {
+        let keys = HashMap::from([("k1".to_string(), vec![1; 32])]);
+        Cipher::new(&keys, None).unwrap()
+    }
+
+    #[test]
+    fn seals_sensitive_fields() {
+        let drv = Driver {
+            name: "Jan".to_string(),
+            license: Some(License {
+                number:  "FARME100165AB5EW".to_string(),
+                expires: None,
+            }),
+            attributes: HashMap::from([
+                (Attribute::Email, "jan@example.com".to_string()),
+                (Attribute::Nationality, "Polish".to_string()),
+            ]),
+            ..Driver::default()
+        };
+
+        let sealed = seal(&cipher(), &drv).unwrap();
+        let repr = sealed.to_string();
+        assert!(!repr.contains("FARME100165AB5EW"));
+        assert!(!repr.contains("jan@example.com"));
+        assert_eq!(sealed["attributes"]["nationality"], "Polish");
+        assert_eq!(sealed["name"], "Jan");
+
+        let unsealed = unseal(&cipher(), sealed).unwrap();
+        assert_eq!(
+            serde_json::to_value(&unsealed).unwrap(),
+            serde_json::to_value(&drv).unwrap()
+        );
+        assert!(
+            unseal(&Cipher::default(), seal(&cipher(), &drv).unwrap()).is_err()
+        );
+    }
+
+    #[test]
+    fn seals_audit_records() {
+        let rec = Record {
+            driver_id:  100.into(),
+            action:     "update".to_string(),
+            actor:      "jane".to_string(),
+            at:         Utc::now(),
+            request_id: None,
+            changes:    [
+                (
+                    "license".to_string(),
+                    Change {
+                        before: None,
+                        after:  Some(json!({"number": "FARME100165AB5EW"})),
+                    },
+                ),
+                (
+                    "attributes".to_string(),
+                    Change {
+                        before: Some(json!({"email": "jan@example.com"})),
+                        after:  None,
+                    },
+                ),
+            ]
+            .into(),
+        };
+
+        let sealed = seal_record(&cipher(), &rec).unwrap();
+        assert!(!sealed.contains("FARME100165AB5EW"));
+        assert!(!sealed.contains("jan@example.com"));
+        assert_eq!(unseal_record(&cipher(), &sealed).unwrap(), rec);
+    }
 }
@@ -11,12 +11,27 @@ use actix_web::{
     HttpResponse,
     Result,
 };
+use chrono::{
+    DateTime,
+    Utc,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
 
-use crate::app::config::State;
-use crate::drivers::entity::{
-    NewDriver,
-    Type,
+use crate::app::{
+    auth::{
+        self,
+        Role,
+    },
+    config::State,
+    logging,
 };
+use crate::drivers::audit::Record;
+use crate::drivers::entity::NewDriver;
+use crate::drivers::lifecycle;
+use crate::drivers::service;
 use crate::drivers::Binding;
 use crate::support::id::{
     Identifier,
@@ -26,6 +41,15 @@ use crate::support::page::Page;
 
 use super::entity::Driver;
 
+use Role::*;
+
+const ANYONE: &[Role] = &[Dispatcher, Hr, Finance, ReadOnly];
+
+/// Lists the IDs of the drivers left out of the page, as they couldn't be
+/// read.
+pub const SKIPPED: header::HeaderName =
+    header::HeaderName::from_static("x-skipped-drivers");
+
 pub(crate) fn new() -> impl HttpServiceFactory + 'static {
     web::scope("/drivers")
         .service(
@@ -43,14 +67,38 @@ pub(crate) fn new() -> impl HttpServiceFactory + 'static {
             web::resource("/{id}/deactivate").route(web::put().to(deactivate)),
         )
         .service(web::resource("/{id}/graduate").route(web::put().to(graduate)))
+        .service(web::resource("/{id}/audit").route(web::get().to(audit_of)))
+        .service(
+            web::resource("/{id}/personal-data")
+                .route(web::get().to(personal_data)),
+        )
+        .service(web::resource("/{id}/erase").route(web::post().to(erase)))
+}
+
+pub(crate) fn audit() -> impl HttpServiceFactory + 'static {
+    web::resource("/audit").route(web::get().to(audit_since))
+}
+
+/// Parses the ID of the driver in the path, as a number or in its textual
+/// form.
+fn driver_id(path: &str) -> Result<Identifier> {
+    path.parse().map_err(error::ErrorBadRequest)
+}
+
+/// Answers with the body, its IDs represented as configured.
+fn ok(state: &State, body: &impl Serialize) -> Result<HttpResponse> {
+    Ok(HttpResponse::Ok().json(state.config.ids.repr.to_value(body)?))
 }
 
 async fn get(
-    path: web::Path<i64>,
+    req: HttpRequest,
+    path: web::Path<String>,
     state: web::Data<State>,
     binding: web::Data<Binding>,
 ) -> Result<HttpResponse> {
-    let id = Identifier::from(path.into_inner());
+    state.auth.require(&req, ANYONE, "get")?;
+    let id = driver_id(&path)?;
+    logging::driver(&id);
     log::debug!("id: {:?}", id);
 
     let db = state.db.clone();
@@ -66,6 +114,7 @@ async fn list(
     state: web::Data<State>,
     binding: web::Data<Binding>,
 ) -> Result<HttpResponse> {
+    state.auth.require(&req, ANYONE, "list")?;
     let db = state.db.clone();
     let mut repo = binding.repo_factory.call(db).await?;
 
@@ -77,131 +126,253 @@ async fn list(
 
     let list = repo.list(&pagin.page).await?;
 
-    let mut res = HttpResponse::Ok().json(&list);
+    let mut res = ok(&state, &list.drivers)?;
     pagin.onto_response(&mut res)?;
+    if !list.skipped.is_empty() {
+        res.headers_mut().insert(
+            SKIPPED,
+            header::HeaderValue::from_str(&list.skipped.join(","))?,
+        );
+    }
 
     Ok(res)
 }
 
 async fn add(
+    req: HttpRequest,
     drv: web::Json<NewDriver>,
     state: web::Data<State>,
     binding: web::Data<Binding>,
 ) -> Result<HttpResponse> {
+    state.auth.require(&req, &[Hr], "add")?;
+    if drv.fee.is_some() {
+        state.auth.require(&req, &[Finance], "set fee")?;
+    }
     log::debug!("drv: {:?}", drv);
 
     let db = state.db.clone();
     let mut repo = binding.repo_factory.call(db).await?;
 
-    drv.validate(&state.clock).map_err(error::ErrorBadRequest)?;
-    let id = ID {
-        id:     Identifier::new(&state.clock),
-        entity: drv.into_inner().onto(&Driver::default()),
-    };
-
-    repo.set(&id).await?;
-
-    log::debug!("new id: {:?}", id.id);
-
-    Ok(HttpResponse::Ok().json(&id))
+    let id = lifecycle::register(
+        repo.as_mut(),
+        &state.ids,
+        &state.clock,
+        &auth::actor(&req),
+        drv.into_inner(),
+    )
+    .await?;
+    logging::driver(&id.id);
+
+    ok(&state, &id)
 }
 
 async fn update(
-    path: web::Path<i64>,
+    req: HttpRequest,
+    path: web::Path<String>,
     drv: web::Json<NewDriver>,
     state: web::Data<State>,
     binding: web::Data<Binding>,
 ) -> Result<HttpResponse> {
-    let id = Identifier::from(path.into_inner());
+    let id = driver_id(&path)?;
+    logging::driver(&id);
     log::debug!("id: {:?}", id);
 
     log::debug!("drv: {:?}", drv);
-    drv.validate(&state.clock).map_err(error::ErrorBadRequest)?;
 
+    // Checked before the driver is read, so the ones who can't update any
+    // don't learn which exist.
+    state.auth.require(&req, &[Hr, Finance], "update")?;
     let db = state.db.clone();
     let mut repo = binding.repo_factory.call(db).await?;
 
-    let curr = repo.get(&id).await?;
-    let upd = drv.into_inner().onto(&curr);
-    let inst = ID {
+    let upd = lifecycle::update(
+        repo.as_mut(),
+        &state.clock,
+        &auth::actor(&req),
         id,
-        entity: upd.clone(),
-    };
-    log::debug!("to update: {:?}", &inst);
-    repo.set(&inst).await?;
+        drv.into_inner(),
+        |fee, others| {
+            if fee {
+                state.auth.require(&req, &[Finance], "update fee")?;
+            }
+            if others || !fee {
+                state.auth.require(&req, &[Hr], "update")?;
+            }
+            Ok(())
+        },
+    )
+    .await?;
 
-    Ok(HttpResponse::Ok().json(&upd))
+    Ok(HttpResponse::Ok().json(&upd.entity))
 }
 
 async fn activate(
-    path: web::Path<i64>,
+    req: HttpRequest,
+    path: web::Path<String>,
     state: web::Data<State>,
     binding: web::Data<Binding>,
 ) -> Result<HttpResponse> {
-    let id = Identifier::from(path.into_inner());
+    state.auth.require(&req, &[Dispatcher, Hr], "activate")?;
+    let id = driver_id(&path)?;
+    logging::driver(&id);
     log::debug!("id: {:?}", id);
 
     let db = state.db.clone();
     let mut repo = binding.repo_factory.call(db).await?;
 
-    let curr = repo.get(&id).await?;
-    let upd = curr
-        .activate(&state.clock)
-        .map_err(error::ErrorBadRequest)?;
-    let inst = ID {
+    let upd = lifecycle::activate(
+        repo.as_mut(),
+        &state.clock,
+        &auth::actor(&req),
         id,
-        entity: upd.clone(),
-    };
-    log::debug!("to update: {:?}", &inst);
-    repo.set(&inst).await?;
+    )
+    .await?;
 
-    Ok(HttpResponse::Ok().json(&upd))
+    Ok(HttpResponse::Ok().json(&upd.entity))
 }
 
 async fn deactivate(
-    path: web::Path<i64>,
+    req: HttpRequest,
+    path: web::Path<String>,
     state: web::Data<State>,
     binding: web::Data<Binding>,
 ) -> Result<HttpResponse> {
-    let id = Identifier::from(path.into_inner());
+    state.auth.require(&req, &[Dispatcher, Hr], "deactivate")?;
+    let id = driver_id(&path)?;
+    logging::driver(&id);
     log::debug!("id: {:?}", id);
 
     let db = state.db.clone();
     let mut repo = binding.repo_factory.call(db).await?;
 
-    let curr = repo.get(&id).await?;
-    let upd = curr.deactivate();
-    let inst = ID {
+    let upd = lifecycle::deactivate(
+        repo.as_mut(),
+        &state.clock,
+        &auth::actor(&req),
         id,
-        entity: upd.clone(),
-    };
-    log::debug!("to update: {:?}", &inst);
-    repo.set(&inst).await?;
+    )
+    .await?;
 
-    Ok(HttpResponse::Ok().json(&upd))
+    Ok(HttpResponse::Ok().json(&upd.entity))
 }
 
 async fn graduate(
-    path: web::Path<i64>,
+    req: HttpRequest,
+    path: web::Path<String>,
     state: web::Data<State>,
     binding: web::Data<Binding>,
 ) -> Result<HttpResponse> {
-    let id = Identifier::from(path.into_inner());
+    state.auth.require(&req, &[Hr], "graduate")?;
+    let id = driver_id(&path)?;
+    logging::driver(&id);
     log::debug!("id: {:?}", id);
 
     let db = state.db.clone();
     let mut repo = binding.repo_factory.call(db).await?;
 
-    let curr = repo.get(&id).await?;
-    let upd = curr.with_type(Type::Regular);
-    let inst = ID {
+    let upd = lifecycle::graduate(
+        repo.as_mut(),
+        &state.clock,
+        &auth::actor(&req),
         id,
-        entity: upd.clone(),
-    };
-    log::debug!("to update: {:?}", &inst);
-    repo.set(&inst).await?;
+    )
+    .await?;
+
+    Ok(HttpResponse::Ok().json(&upd.entity))
+}
+
+async fn audit_of(
+    req: HttpRequest,
+    path: web::Path<String>,
+    state: web::Data<State>,
+    binding: web::Data<Binding>,
+) -> Result<HttpResponse> {
+    state.auth.require(&req, &[Hr, Finance], "audit")?;
+    let id = driver_id(&path)?;
+    logging::driver(&id);
+
+    let db = state.db.clone();
+    let mut repo = binding.repo_factory.call(db).await?;
+    let recs = repo.audit_of(&id).await?;
+
+    ok(&state, &recs)
+}
+
+#[derive(Deserialize)]
+struct Since {
+    since: Option<DateTime<Utc>>,
+}
+
+async fn audit_since(
+    req: HttpRequest,
+    query: web::Query<Since>,
+    state: web::Data<State>,
+    binding: web::Data<Binding>,
+) -> Result<HttpResponse> {
+    state.auth.require(&req, &[Hr, Finance], "audit")?;
+    let since = query.since.unwrap_or(DateTime::<Utc>::MIN_UTC);
+    log::debug!("since: {:?}", since);
+
+    let db = state.db.clone();
+    let mut repo = binding.repo_factory.call(db).await?;
+    let recs = repo.audit_since(since).await?;
+
+    ok(&state, &recs)
+}
+
+/// Everything held about a driver.
+#[derive(Serialize)]
+struct PersonalData {
+    driver: ID<Driver>,
+    audit:  Vec<Record>,
+}
+
+async fn personal_data(
+    req: HttpRequest,
+    path: web::Path<String>,
+    state: web::Data<State>,
+    binding: web::Data<Binding>,
+) -> Result<HttpResponse> {
+    state.auth.require(&req, &[Hr], "export personal data")?;
+    let id = driver_id(&path)?;
+    logging::driver(&id);
+
+    let db = state.db.clone();
+    let mut repo = binding.repo_factory.call(db).await?;
+    let entity = repo.get(&id).await?;
+    let audit = repo.audit_of(&id).await?;
+
+    ok(
+        &state,
+        &PersonalData {
+            driver: ID { id, entity },
+            audit,
+        },
+    )
+}
+
+async fn erase(
+    req: HttpRequest,
+    path: web::Path<String>,
+    state: web::Data<State>,
+    binding: web::Data<Binding>,
+) -> Result<HttpResponse> {
+    state.auth.require(&req, &[Hr], "erase")?;
+    let id = driver_id(&path)?;
+    logging::driver(&id);
+    log::debug!("id: {:?}", id);
+
+    let db = state.db.clone();
+    let mut repo = binding.repo_factory.call(db).await?;
+
+    let inst =
+        lifecycle::erase(repo.as_mut(), &state.clock, &auth::actor(&req), id)
+            .await?;
+    log::info!("Erased driver {}", inst.id);
 
-    Ok(HttpResponse::Ok().json(&upd))
+    service::driver_erased(&state, &inst.id).await?;
+
+    Ok(HttpResponse::Ok().json(&inst.entity))
 }
 
 fn expects_json() -> impl Guard + Sized {
@@ -210,6 +381,11 @@ fn expects_json() -> impl Guard + Sized {
 
 #[cfg(test)]
 mod tests {
+    use std::sync::{
+        Arc,
+        Mutex,
+    };
+
     use actix_web::{
         body::to_bytes,
         http::{
@@ -220,31 +396,27 @@ mod tests {
             self,
             TestRequest,
         },
-        web::{
-            Bytes,
-            Data,
-        },
+        web::Data,
         App,
         Result,
     };
 
+    use cloudevents::AttributesReader;
+
+    use crate::app::auth::{
+        self,
+        Auth,
+    };
+    use crate::app::config::AuthConfig;
+    use crate::drivers::repository::memory::InMemory;
     use crate::support::{
+        cloudevents::Sender,
         id::ID,
         page::Pagination,
     };
 
     use super::*;
 
-    trait BodyTest {
-        fn as_str(&self) -> &str;
-    }
-
-    impl BodyTest for Bytes {
-        fn as_str(&self) -> &str {
-            std::str::from_utf8(self).unwrap()
-        }
-    }
-
     #[test_log::test(actix_web::test)]
     async fn test_drivers_get() -> Result<()> {
         let state = State::default();
@@ -281,6 +453,238 @@ mod tests {
         assert_list_response(res).await
     }
 
+    #[actix_web::test]
+    async fn lists_skipped_drivers() -> Result<()> {
+        let repo = InMemory::default();
+        repo.drivers
+            .lock()
+            .unwrap()
+            .extend([(1, Driver::default()), (2, Driver::default())]);
+        repo.corrupt.lock().unwrap().push(2);
+        let req = TestRequest::get().uri("/drivers").to_http_request();
+        let res = list(
+            req,
+            Data::new(State::default()),
+            Data::new(Binding::in_memory(repo)),
+        )
+        .await?;
+
+        assert_eq!(
+            res.headers().get(SKIPPED).unwrap(),
+            Identifier::from(2).to_string().as_str()
+        );
+        let body = to_bytes(res.into_body()).await?;
+        let drvs: Vec<ID<Driver>> = serde_json::from_slice(&body)?;
+        assert_eq!(drvs.len(), 1);
+        assert_eq!(drvs[0].id, Identifier::from(1));
+        Ok(())
+    }
+
+    async fn call(req: TestRequest, key: &str) -> StatusCode {
+        let repo = InMemory::default();
+        repo.drivers.lock().unwrap().insert(
+            100,
+            Driver {
+                name: "John".to_string(),
+                surname: "Doe".to_string(),
+                ..Driver::default()
+            },
+        );
+        let auth = Auth::new(&AuthConfig {
+            api_keys: auth::parse_api_keys(
+                "k-ro:read-only;k-hr:hr;k-fin:finance",
+            )
+            .unwrap(),
+            ..AuthConfig::default()
+        })
+        .unwrap();
+        let state = State {
+            auth,
+            ..State::default()
+        };
+        let app = test::init_service(
+            App::new()
+                .wrap(actix_web::middleware::from_fn(auth::middleware))
+                .app_data(Data::new(state))
+                .app_data(Data::new(Binding::in_memory(repo)))
+                .service(new()),
+        )
+        .await;
+        let req = req.insert_header((auth::API_KEY, key)).to_request();
+        test::call_service(&app, req).await.status()
+    }
+
+    #[actix_web::test]
+    async fn graduate_requires_hr() {
+        let req = || TestRequest::put().uri("/drivers/100/graduate");
+        assert_eq!(call(req(), "k-ro").await, StatusCode::FORBIDDEN);
+        assert_eq!(call(req(), "k-hr").await, StatusCode::OK);
+        let req = TestRequest::get().uri("/drivers/100");
+        assert_eq!(call(req, "k-ro").await, StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn accepts_textual_ids() {
+        let req = |uri| TestRequest::get().uri(uri);
+        assert_eq!(call(req("/drivers/gk"), "k-ro").await, StatusCode::OK);
+        assert_eq!(call(req("/drivers/~gk"), "k-ro").await, StatusCode::OK);
+        assert_eq!(
+            call(req("/drivers/gk!"), "k-ro").await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(
+            call(req("/drivers/zzzzzzzzzzzzzzzz/audit"), "k-hr").await,
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[actix_web::test]
+    async fn fee_change_requires_finance() {
+        let req = |body: serde_json::Value| {
+            TestRequest::put().uri("/drivers/100").set_json(body)
+        };
+        let renamed = serde_json::json!({"name": "Jim", "surname": "Doe"});
+        let with_fee = serde_json::json!({
+            "name": "John",
+            "surname": "Doe",
+            "fee": {"type": "flat", "amount": 10},
+        });
+        assert_eq!(call(req(renamed.clone()), "k-hr").await, StatusCode::OK);
+        assert_eq!(call(req(renamed), "k-fin").await, StatusCode::FORBIDDEN);
+        assert_eq!(
+            call(req(with_fee.clone()), "k-hr").await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(call(req(with_fee), "k-fin").await, StatusCode::OK);
+
+        // Whether the driver exists or not.
+        let missing = TestRequest::put()
+            .uri("/drivers/101")
+            .set_json(serde_json::json!({"name": "Jim", "surname": "Doe"}));
+        assert_eq!(call(missing, "k-ro").await, StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn audits_mutations() {
+        let repo = InMemory::default();
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(State::default()))
+                .app_data(Data::new(Binding::in_memory(repo.clone())))
+                .service(crate::drivers::routes()),
+        )
+        .await;
+
+        let req = TestRequest::post()
+            .uri("/drivers")
+            .set_json(serde_json::json!({"name": "John", "surname": "Doe"}))
+            .to_request();
+        let drv: ID<Driver> = test::call_and_read_body_json(&app, req).await;
+        let uri = format!("/drivers/{}/graduate", drv.id.int());
+        let req = TestRequest::put().uri(&uri).to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::OK
+        );
+
+        let uri = format!("/drivers/{}/audit", drv.id.int());
+        let req = TestRequest::get().uri(&uri).to_request();
+        let recs: Vec<Record> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(recs.len(), 2);
+        assert_eq!(recs[0].action, "add");
+        assert_eq!(recs[1].action, "graduate");
+        assert_eq!(
+            recs[1].changes["type"].after,
+            Some(serde_json::json!("Regular"))
+        );
+
+        let req = TestRequest::get()
+            .uri("/audit?since=2999-01-01T00:00:00Z")
+            .to_request();
+        let recs: Vec<Record> = test::call_and_read_body_json(&app, req).await;
+        assert!(recs.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn erases_personal_data() {
+        let sent = Arc::new(Mutex::new(vec![]));
+        let sink = {
+            let sent = sent.clone();
+            actix_web::HttpServer::new(move || {
+                let sent = sent.clone();
+                App::new().route(
+                    "/",
+                    web::post().to(move |ce: cloudevents::Event| {
+                        sent.lock().unwrap().push(ce);
+                        async { HttpResponse::Accepted().finish() }
+                    }),
+                )
+            })
+            .bind(("127.0.0.1", 0))
+            .unwrap()
+        };
+        let addr = sink.addrs()[0];
+        let sink = sink.run();
+        let handle = sink.handle();
+        actix_web::rt::spawn(sink);
+
+        let mut state = State::default();
+        state.config.knative.sink = format!("http://{}/", addr);
+        state.sender = Sender::new(&state.config.knative, None);
+        let repo = InMemory::default();
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(state))
+                .app_data(Data::new(Binding::in_memory(repo.clone())))
+                .service(crate::drivers::routes()),
+        )
+        .await;
+
+        let req = TestRequest::post()
+            .uri("/drivers")
+            .set_json(serde_json::json!({
+                "name": "John",
+                "surname": "Doe",
+                "attributes": {"email": "john@example.com"},
+                "fee": {"type": "flat", "amount": 10},
+            }))
+            .to_request();
+        let drv: ID<Driver> = test::call_and_read_body_json(&app, req).await;
+
+        let uri = format!("/drivers/{}/erase", drv.id.int());
+        let req = TestRequest::post().uri(&uri).to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::OK
+        );
+
+        let uri = format!("/drivers/{}", drv.id.int());
+        let req = TestRequest::put()
+            .uri(&uri)
+            .set_json(serde_json::json!({"name": "John", "surname": "Doe"}))
+            .to_request();
+        assert_eq!(
+            test::call_service(&app, req).await.status(),
+            StatusCode::CONFLICT
+        );
+
+        let uri = format!("/drivers/{}/personal-data", drv.id.int());
+        let req = TestRequest::get().uri(&uri).to_request();
+        let data: serde_json::Value =
+            test::call_and_read_body_json(&app, req).await;
+        let repr = data.to_string();
+        assert!(!repr.contains("John"), "{}", repr);
+        assert!(!repr.contains("john@example.com"), "{}", repr);
+        assert_eq!(data["driver"]["id"], drv.id.int());
+        assert_eq!(data["driver"]["entity"]["fee"]["amount"], 10);
+        assert_eq!(data["audit"][1]["action"], "erase");
+
+        handle.stop(false).await;
+        let sent = sent.lock().unwrap();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].ty(), "cabs.drivers.driver-erased");
+    }
+
     async fn assert_list_response(res: HttpResponse) -> Result<()> {
         assert_eq!(res.status(), StatusCode::OK);
         assert_eq!(
@@ -1,19 +1,46 @@
 use crate::{
-    app::config::{
-        Config,
-        State,
+    app::{
+        auth::{
+            self,
+            Auth,
+            Principal,
+            Role,
+        },
+        config::{
+            Reply,
+            State,
+        },
+        logging,
+        metrics,
     },
     drivers::{
+        entity::{
+            Driver,
+            NewDriver,
+            Status,
+            Type,
+        },
+        lifecycle,
+        projection::{
+            self,
+            LegacyDriver,
+        },
         repository::Repository,
         Binding,
     },
     support::{
+        clock::Clock,
         cloudevents::Sender,
+        dispatch::Dispatcher,
         id::{
+            Generator,
             Identifier,
+            Repr,
             Subject,
+            ID,
         },
         money::Money,
+        trace,
     },
 };
 use actix_web::{
@@ -21,6 +48,10 @@ use actix_web::{
     web,
     Result,
 };
+use chrono::{
+    DateTime,
+    Utc,
+};
 use cloudevents::{
     AttributesReader,
     Data,
@@ -28,20 +59,137 @@ use cloudevents::{
     EventBuilder,
     EventBuilderV10,
 };
+use schemars::{
+    schema::RootSchema,
+    schema_for,
+    JsonSchema,
+};
 use serde::{
     Deserialize,
     Serialize,
 };
 
+use Role::*;
+
+pub(crate) const CALCULATE_FEE: &str = "cabs.drivers.calculate-fee";
+pub(crate) const CALCULATE_FEE_V2: &str = "cabs.drivers.calculate-fee.v2";
+pub(crate) const DRIVER_FEE: &str = "cabs.drivers.driver-fee";
+pub(crate) const DRIVER_FEE_V2: &str = "cabs.drivers.driver-fee.v2";
+pub(crate) const DRIVER_ERASED: &str = "cabs.drivers.driver-erased";
+
+pub(crate) const REGISTER: &str = "cabs.drivers.register";
+pub(crate) const UPDATE: &str = "cabs.drivers.update";
+pub(crate) const ACTIVATE: &str = "cabs.drivers.activate";
+pub(crate) const DEACTIVATE: &str = "cabs.drivers.deactivate";
+pub(crate) const GRADUATE: &str = "cabs.drivers.graduate";
+pub(crate) const REGISTERED: &str = "cabs.drivers.registered";
+pub(crate) const UPDATED: &str = "cabs.drivers.updated";
+pub(crate) const ACTIVATED: &str = "cabs.drivers.activated";
+pub(crate) const DEACTIVATED: &str = "cabs.drivers.deactivated";
+pub(crate) const GRADUATED: &str = "cabs.drivers.graduated";
+pub(crate) const COMMAND_FAILED: &str = "cabs.drivers.command-failed";
+pub(crate) const LEGACY_DRIVER_CHANGED: &str = "cabs.legacy.driver-changed";
+
+/// Types of the events received and sent, in all their versions.
+pub(crate) const TYPES: [&str; 17] = [
+    CALCULATE_FEE,
+    CALCULATE_FEE_V2,
+    DRIVER_FEE,
+    DRIVER_FEE_V2,
+    DRIVER_ERASED,
+    REGISTER,
+    UPDATE,
+    ACTIVATE,
+    DEACTIVATE,
+    GRADUATE,
+    REGISTERED,
+    UPDATED,
+    ACTIVATED,
+    DEACTIVATED,
+    GRADUATED,
+    COMMAND_FAILED,
+    LEGACY_DRIVER_CHANGED,
+];
+
+/// Extension attribute of the replies, giving the ID of the event they
+/// answer.
+pub(crate) const CORRELATION_ID: &str = "correlationid";
+
+/// Currency of the amounts of the events of the first version, which
+/// don't give it.
+const DEFAULT_CURRENCY: &str = "PLN";
+
+lazy_static! {
+    /// Handlers of the events received, by type.
+    pub(crate) static ref HANDLERS: Dispatcher<Service> = Dispatcher::new()
+        .on(CALCULATE_FEE, calculate_fee_v1)
+        .on(CALCULATE_FEE_V2, calculate_fee_v2)
+        .on(REGISTER, register)
+        .on(UPDATE, update)
+        .on(ACTIVATE, |svc, cmd| transition(svc, cmd, ACTIVATE))
+        .on(DEACTIVATE, |svc, cmd| transition(svc, cmd, DEACTIVATE))
+        .on(GRADUATE, |svc, cmd| transition(svc, cmd, GRADUATE))
+        .on(LEGACY_DRIVER_CHANGED, project);
+}
+
+/// Version of the data of an event, as resolved by the dispatcher.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Version {
+    V1,
+    V2,
+}
+
+impl Version {
+    /// The type of the events of this version.
+    fn ty(self, ty: &str) -> String {
+        match self {
+            Version::V1 => ty.to_string(),
+            Version::V2 => format!("{}.v2", ty),
+        }
+    }
+}
+
+/// Handles an event, replying to it.
 pub struct Service {
-    config: Config,
-    repo:   Box<dyn Repository>,
+    sender:  Sender,
+    reply:   Reply,
+    schemas: String,
+    clock:   Clock,
+    ids:     Generator,
+    /// How the IDs in the replies are represented.
+    repr:    Repr,
+    /// Source of the event, recorded as the actor of the changes.
+    actor:   String,
+    auth:    Auth,
+    /// Source of the event as authenticated, if its origin is verified.
+    from:    Option<Principal>,
+    /// ID of the event, to correlate the replies.
+    cause:   String,
+    repo:    Box<dyn Repository>,
 }
 
 impl Service {
-    pub async fn calculate_fee(&mut self, ce: Event) -> Result<()> {
-        let calc_fee_intent = Self::unwrap_calculatefee(ce)?;
+    /// Computes the fee, and either sends the resulting event or returns it,
+    /// as the reply is configured, in the version it was asked in.
+    async fn calculate_fee(
+        &mut self,
+        cmd: Subject<CalculateFeeEventV2>,
+        version: Version,
+    ) -> Result<Option<Event>> {
+        trace::in_span(
+            "Service::calculate_fee",
+            self.do_calculate_fee(cmd, version),
+        )
+        .await
+    }
+
+    async fn do_calculate_fee(
+        &mut self,
+        calc_fee_intent: Subject<CalculateFeeEventV2>,
+        version: Version,
+    ) -> Result<Option<Event>> {
         let subject = calc_fee_intent.id.clone();
+        logging::driver(&calc_fee_intent.entity.driver_id);
 
         log::debug!("calculate fee for: {:?}", calc_fee_intent);
         let drv = self.repo.get(&calc_fee_intent.entity.driver_id).await?;
@@ -49,88 +197,491 @@ impl Service {
         let fee = drv.calculate_fee(&calc_fee_intent.entity.transit_price);
 
         log::debug!("fee value: {:?}", fee);
+        metrics::fee_computed(fee.as_f64());
 
-        let driverfee_event = DriverFeeEvent {
+        let driverfee_event = DriverFeeEventV2 {
             driver_id: calc_fee_intent.entity.driver_id,
             fee,
+            currency: calc_fee_intent.entity.currency,
         };
 
-        let mut builder = driverfee_event.to_builder();
+        let builder =
+            driverfee_event.to_builder(version, &self.schemas, self.repr)?;
+        self.reply(builder, subject).await
+    }
+
+    /// Replies to the command with the driver as it became, or with why it
+    /// was refused. Failures of the service itself are returned instead, for
+    /// the command to be retried.
+    async fn outcome(
+        &mut self,
+        command: &str,
+        subject: Option<String>,
+        res: Result<ID<Driver>>,
+    ) -> Result<Option<Event>> {
+        let (ty, data) = match res {
+            Ok(drv) => {
+                logging::driver(&drv.id);
+                let ty = match command {
+                    REGISTER => REGISTERED,
+                    UPDATE => UPDATED,
+                    ACTIVATE => ACTIVATED,
+                    DEACTIVATE => DEACTIVATED,
+                    _ => GRADUATED,
+                };
+                (ty, self.repr.to_value(&DriverChangedEvent::from(drv))?)
+            }
+            Err(err) => {
+                let status = err.as_response_error().status_code();
+                if !status.is_client_error() {
+                    return Err(err);
+                }
+                log::warn!("Refused {} command: {}", command, err);
+                let failed = CommandFailedEvent {
+                    command: command.to_string(),
+                    status:  status.as_u16(),
+                    error:   err.to_string(),
+                };
+                (COMMAND_FAILED, serde_json::to_value(failed)?)
+            }
+        };
+        let builder = EventBuilderV10::default()
+            .source("usvc://cabs/drivers")
+            .ty(ty)
+            .data_with_schema(
+                "application/json",
+                schema_url(&self.schemas, ty),
+                data,
+            );
+        self.reply(builder, subject).await
+    }
+
+    /// Either sends the reply or returns it, as the reply is configured,
+    /// correlated to the event it answers.
+    async fn reply(
+        &mut self,
+        mut builder: EventBuilderV10,
+        subject: Option<String>,
+    ) -> Result<Option<Event>> {
         if let Some(id) = subject {
             builder = builder.subject(id);
         }
-        let ce = builder.build().map_err(error::ErrorInternalServerError)?;
-
-        Sender::new(&self.config).send(ce).await?;
+        let mut ce = builder
+            .extension(CORRELATION_ID, self.cause.as_str())
+            .build()
+            .map_err(error::ErrorInternalServerError)?;
 
-        Ok(())
+        match self.reply {
+            Reply::Sink => self.sender.send(ce).await.map(|_| None),
+            Reply::Response => {
+                trace::inject(&mut ce);
+                Ok(Some(ce))
+            }
+        }
     }
+}
 
-    fn unwrap_calculatefee(ce: Event) -> Result<Subject<CalculateFeeEvent>> {
-        let ct = ce.datacontenttype();
-        if ct != Some("application/json") {
-            return Err(error::ErrorBadRequest(format!(
-                "unsupported content type: {:#?}",
-                ct
-            )));
+async fn register(
+    mut svc: Service,
+    cmd: Subject<NewDriver>,
+) -> Result<Option<Event>> {
+    let res = async {
+        svc.auth.require_of(svc.from.as_ref(), &[Hr], "register")?;
+        if cmd.entity.fee.is_some() {
+            svc.auth
+                .require_of(svc.from.as_ref(), &[Finance], "set fee")?;
         }
+        lifecycle::register(
+            svc.repo.as_mut(),
+            &svc.ids,
+            &svc.clock,
+            &svc.actor,
+            cmd.entity,
+        )
+        .await
+    }
+    .await;
+    svc.outcome(REGISTER, cmd.id, res).await
+}
 
-        let data = match ce.data() {
-            Some(data) => serde_json::Value::try_from(data.clone())?,
-            None => return Err(error::ErrorBadRequest("missing data")),
-        };
+async fn update(
+    mut svc: Service,
+    cmd: Subject<UpdateDriverCommand>,
+) -> Result<Option<Event>> {
+    logging::driver(&cmd.entity.driver_id);
+    let (auth, from) = (&svc.auth, svc.from.as_ref());
+    let res = lifecycle::update(
+        svc.repo.as_mut(),
+        &svc.clock,
+        &svc.actor,
+        cmd.entity.driver_id,
+        cmd.entity.driver,
+        |fee, others| {
+            if fee {
+                auth.require_of(from, &[Finance], "update fee")?;
+            }
+            if others || !fee {
+                auth.require_of(from, &[Hr], "update")?;
+            }
+            Ok(())
+        },
+    )
+    .await;
+    svc.outcome(UPDATE, cmd.id, res).await
+}
 
-        let entity: CalculateFeeEvent =
-            match serde_json::from_value(data.clone()) {
-                Ok(event) => event,
-                Err(err) => {
-                    return Err(error::ErrorBadRequest(format!(
-                        "failed to parse event: {}",
-                        err
-                    )))
-                }
-            };
+/// Activates, deactivates or graduates the driver.
+async fn transition(
+    mut svc: Service,
+    cmd: Subject<DriverCommand>,
+    command: &'static str,
+) -> Result<Option<Event>> {
+    let id = cmd.entity.driver_id;
+    logging::driver(&id);
+    let roles: &[Role] = match command {
+        GRADUATE => &[Hr],
+        _ => &[Dispatcher, Hr],
+    };
+    if let Err(err) = svc.auth.require_of(svc.from.as_ref(), roles, command) {
+        return svc.outcome(command, cmd.id, Err(err)).await;
+    }
+    let (repo, clock, actor) = (svc.repo.as_mut(), &svc.clock, &svc.actor);
+    let res = match command {
+        ACTIVATE => lifecycle::activate(repo, clock, actor, id).await,
+        DEACTIVATE => lifecycle::deactivate(repo, clock, actor, id).await,
+        _ => lifecycle::graduate(repo, clock, actor, id).await,
+    };
+    svc.outcome(command, cmd.id, res).await
+}
 
-        let id = ce.subject().map(|s| s.to_string());
+/// Keeps the driver as changed in the legacy app, without replying.
+async fn project(
+    mut svc: Service,
+    cmd: Subject<LegacyDriver>,
+) -> Result<Option<Event>> {
+    let id = cmd.entity.driver_id.clone();
+    logging::driver(&id);
+    // The legacy app is the authority on drivers, if it's verified to be.
+    svc.auth.require_of(svc.from.as_ref(), &[], "project")?;
+    let projected =
+        projection::project(svc.repo.as_mut(), &svc.clock, cmd.entity).await?;
+    log::info!("Projected legacy driver {}: {:?}", id, projected);
+    Ok(None)
+}
 
-        Ok(Subject { id, entity })
+async fn calculate_fee_v1(
+    mut svc: Service,
+    cmd: Subject<CalculateFeeEvent>,
+) -> Result<Option<Event>> {
+    svc.calculate_fee(cmd.upcast(), Version::V1).await
+}
+
+async fn calculate_fee_v2(
+    mut svc: Service,
+    cmd: Subject<CalculateFeeEventV2>,
+) -> Result<Option<Event>> {
+    svc.calculate_fee(cmd, Version::V2).await
+}
+
+/// Announces the driver was erased, so their personal data is forgotten
+/// everywhere else too.
+pub(crate) async fn driver_erased(
+    state: &State,
+    id: &Identifier,
+) -> Result<()> {
+    let ce = EventBuilderV10::default()
+        .source("usvc://cabs/drivers")
+        .ty(DRIVER_ERASED)
+        .data_with_schema(
+            "application/json",
+            schema_url(&state.config.knative.schemas, DRIVER_ERASED),
+            state.config.ids.repr.to_value(&DriverErasedEvent {
+                driver_id: id.clone(),
+            })?,
+        )
+        .build()
+        .map_err(error::ErrorInternalServerError)?;
+
+    state.sender.send(ce).await
+}
+
+/// The schema of the data of the events of the type.
+pub(crate) fn schema(ty: &str) -> Option<RootSchema> {
+    match ty {
+        CALCULATE_FEE => Some(schema_for!(CalculateFeeEvent)),
+        CALCULATE_FEE_V2 => Some(schema_for!(CalculateFeeEventV2)),
+        DRIVER_FEE => Some(schema_for!(DriverFeeEvent)),
+        DRIVER_FEE_V2 => Some(schema_for!(DriverFeeEventV2)),
+        DRIVER_ERASED => Some(schema_for!(DriverErasedEvent)),
+        REGISTER => Some(schema_for!(NewDriver)),
+        UPDATE => Some(schema_for!(UpdateDriverCommand)),
+        ACTIVATE | DEACTIVATE | GRADUATE => Some(schema_for!(DriverCommand)),
+        REGISTERED | UPDATED | ACTIVATED | DEACTIVATED | GRADUATED => {
+            Some(schema_for!(DriverChangedEvent))
+        }
+        COMMAND_FAILED => Some(schema_for!(CommandFailedEvent)),
+        LEGACY_DRIVER_CHANGED => Some(schema_for!(LegacyDriver)),
+        _ => None,
     }
 }
 
+pub(crate) fn schema_url(base: &str, ty: &str) -> String {
+    format!("{}/{}", base.trim_end_matches('/'), ty)
+}
+
+/// The service handling the event.
 pub(crate) async fn new(
     state: web::Data<State>,
     binding: web::Data<Binding>,
+    ce: &Event,
 ) -> Result<Service> {
     let repo = binding.repo_factory.call(state.db.clone()).await?;
-    let config = state.config.clone();
-    Ok(Service { repo, config })
+    let sender = state.sender.clone();
+    let reply = state.config.knative.reply;
+    let schemas = state.config.knative.schemas.clone();
+    Ok(Service {
+        repo,
+        sender,
+        reply,
+        schemas,
+        clock: state.clock.clone(),
+        ids: state.ids.clone(),
+        repr: state.config.ids.repr,
+        actor: ce.source().to_string(),
+        auth: state.auth.clone(),
+        from: auth::event_principal(&state.config.events, ce),
+        cause: ce.id().to_string(),
+    })
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+/// Asks for the fee of the driver for a transit.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, JsonSchema)]
 #[serde(rename_all = "kebab-case")]
 struct CalculateFeeEvent {
     driver_id:     Identifier,
     transit_price: Money,
 }
 
-#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+/// Asks for the fee of the driver for a transit, in its currency.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+struct CalculateFeeEventV2 {
+    driver_id:     Identifier,
+    transit_price: Money,
+    /// ISO 4217 code of the currency of the price.
+    #[schemars(regex(pattern = r"^[A-Z]{3}$"))]
+    currency:      String,
+    /// When the transit took place.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    transit_time:  Option<DateTime<Utc>>,
+}
+
+impl Subject<CalculateFeeEvent> {
+    /// Upcasts the data to the latest version.
+    fn upcast(self) -> Subject<CalculateFeeEventV2> {
+        Subject {
+            id:     self.id,
+            entity: self.entity.into(),
+        }
+    }
+}
+
+impl From<CalculateFeeEvent> for CalculateFeeEventV2 {
+    fn from(ev: CalculateFeeEvent) -> Self {
+        Self {
+            driver_id:     ev.driver_id,
+            transit_price: ev.transit_price,
+            currency:      DEFAULT_CURRENCY.to_string(),
+            transit_time:  None,
+        }
+    }
+}
+
+/// The fee of the driver for a transit, answering `calculate-fee`.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, JsonSchema)]
 #[serde(rename_all = "kebab-case")]
 struct DriverFeeEvent {
     driver_id: Identifier,
     fee:       Money,
 }
 
-impl Into<Data> for &DriverFeeEvent {
-    fn into(self) -> Data {
-        Data::Json(serde_json::to_value(self).unwrap())
+/// The fee of the driver for a transit, in the currency of its price,
+/// answering `calculate-fee.v2`.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+struct DriverFeeEventV2 {
+    driver_id: Identifier,
+    fee:       Money,
+    /// ISO 4217 code of the currency of the fee.
+    #[schemars(regex(pattern = r"^[A-Z]{3}$"))]
+    currency:  String,
+}
+
+impl From<DriverFeeEventV2> for DriverFeeEvent {
+    fn from(ev: DriverFeeEventV2) -> Self {
+        Self {
+            driver_id: ev.driver_id,
+            fee:       ev.fee,
+        }
     }
 }
 
-impl DriverFeeEvent {
-    fn to_builder(&self) -> EventBuilderV10 {
-        EventBuilderV10::default()
+impl DriverFeeEventV2 {
+    /// Builds the event in the version, downcast if need be.
+    fn to_builder(
+        &self,
+        version: Version,
+        schemas: &str,
+        repr: Repr,
+    ) -> Result<EventBuilderV10> {
+        let data = match version {
+            Version::V1 => {
+                repr.to_value(&DriverFeeEvent::from(self.clone()))?
+            }
+            Version::V2 => repr.to_value(self)?,
+        };
+        let ty = version.ty(DRIVER_FEE);
+        Ok(EventBuilderV10::default()
             .source("usvc://cabs/drivers")
-            .ty("cabs.drivers.driver-fee")
-            .data("application/json", self)
+            .ty(ty.as_str())
+            .data_with_schema(
+                "application/json",
+                schema_url(schemas, &ty),
+                Data::Json(data),
+            ))
+    }
+}
+
+/// Updates the driver, as `PUT /drivers/{id}` does.
+#[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+struct UpdateDriverCommand {
+    driver_id: Identifier,
+    #[serde(flatten)]
+    driver:    NewDriver,
+}
+
+/// Activates, deactivates or graduates the driver.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+struct DriverCommand {
+    driver_id: Identifier,
+}
+
+/// The driver as it became by a command, without their personal data.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+struct DriverChangedEvent {
+    driver_id: Identifier,
+    status:    Status,
+    r#type:    Type,
+}
+
+impl From<ID<Driver>> for DriverChangedEvent {
+    fn from(drv: ID<Driver>) -> Self {
+        Self {
+            driver_id: drv.id,
+            status:    drv.entity.status,
+            r#type:    drv.entity.r#type,
+        }
+    }
+}
+
+/// The command was refused, with the HTTP status it would have been
+/// answered with.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+struct CommandFailedEvent {
+    command: String,
+    status:  u16,
+    error:   String,
+}
+
+/// The driver was erased, and their personal data is to be forgotten.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+struct DriverErasedEvent {
+    driver_id: Identifier,
+}
+
+#[cfg(test)]
+mod tests {
+    use jsonschema::JSONSchema;
+
+    use super::*;
+    use crate::support::dispatch::{
+        compile,
+        validate,
+    };
+
+    lazy_static! {
+        static ref CALCULATE_FEE_SCHEMA: JSONSchema =
+            compile::<CalculateFeeEvent>();
+    }
+
+    fn invalid(data: serde_json::Value) -> String {
+        validate(&CALCULATE_FEE_SCHEMA, &data)
+            .unwrap_err()
+            .to_string()
+    }
+
+    #[test]
+    fn validates_data() {
+        let data = serde_json::json!({"driver-id": 1, "transit-price": 1000});
+        assert!(validate(&CALCULATE_FEE_SCHEMA, &data).is_ok());
+        let data =
+            serde_json::json!({"driver-id": "gei4p52", "transit-price": 1});
+        assert!(validate(&CALCULATE_FEE_SCHEMA, &data).is_ok());
+
+        let err = invalid(serde_json::json!({"driver-id": 1}));
+        assert_eq!(
+            err,
+            r#"invalid data: "transit-price" is a required property"#
+        );
+        let err = invalid(serde_json::json!({
+            "driver-id": 1,
+            "transit-price": "10.00",
+        }));
+        assert_eq!(
+            err,
+            r#"invalid data: /transit-price: "10.00" is not of type "integer""#
+        );
+        let err = invalid(serde_json::json!({
+            "driver-id": -1,
+            "transit-price": 10.5,
+        }));
+        assert!(err.contains("/driver-id: "), "{}", err);
+        assert!(err.contains("; /transit-price: "), "{}", err);
+    }
+
+    #[test]
+    fn versions() {
+        assert_eq!(Version::V1.ty(DRIVER_FEE), DRIVER_FEE);
+        assert_eq!(Version::V2.ty(DRIVER_FEE), DRIVER_FEE_V2);
+    }
+
+    #[test]
+    fn upcasts() {
+        let cmd = Subject {
+            id:     Some("1".to_string()),
+            entity: CalculateFeeEvent {
+                driver_id:     Identifier::from(1),
+                transit_price: Money::new(1000),
+            },
+        }
+        .upcast();
+        assert_eq!(cmd.id.as_deref(), Some("1"));
+        assert_eq!(cmd.entity.currency, DEFAULT_CURRENCY);
+        assert_eq!(cmd.entity.transit_price, Money::new(1000));
+        assert_eq!(cmd.entity.transit_time, None);
+
+        // The currency is required from the second version on.
+        let data = serde_json::json!({"driver-id": 1, "transit-price": 1000});
+        let err =
+            validate(&compile::<CalculateFeeEventV2>(), &data).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            r#"invalid data: "currency" is a required property"#
+        );
     }
 }
@@ -1,28 +1,79 @@
+#![recursion_limit = "256"]
+
 #[macro_use]
 extern crate lazy_static;
 
 mod app;
+mod cli;
 mod drivers;
 mod support;
 
+use std::io;
+
 use actix_web::{
     dev::ServerHandle,
     HttpServer,
 };
+use clap::Parser;
 use tokio::sync::mpsc;
 
 use app::{
     config::setup_logger,
+    config::setup_tracer,
     config::State,
 };
+use cli::{
+    Cli,
+    Command,
+};
+
+fn main() -> io::Result<()> {
+    run(Cli::parse().command.unwrap_or_default())
+}
 
 #[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    let state = State::default();
+async fn run(command: Command) -> io::Result<()> {
+    let mut state = State::default();
+    state.config.logging.stderr = command != Command::Serve;
+
+    setup_logger(&state.config);
+    setup_tracer(&state.config.tracing);
+
+    let res = match command {
+        Command::Serve => serve(state).await,
+        command => cli::run(command, state).await,
+    };
 
-    setup_logger(&state.config.environment);
+    opentelemetry::global::shutdown_tracer_provider();
+    res
+}
+
+async fn serve(state: State) -> io::Result<()> {
     log::debug!("Starting server: {:#?}", state.config);
 
+    let report = app::health::readiness(&state).await;
+    for check in report.failed() {
+        log::warn!(
+            "Starting not ready, {} check failed: {}",
+            check.name,
+            check.error.as_deref().unwrap_or_default()
+        );
+    }
+
+    drivers::reencrypt_every(
+        state.db.clone(),
+        state.config.db.encryption.reencrypt,
+    );
+
+    app::metrics::count_drivers_every(
+        state.clone(),
+        drivers::Binding::default(),
+        state.config.metrics.refresh,
+    );
+
+    app::events::subscribe(&state, drivers::Binding::default())
+        .map_err(|err| io::Error::other(err.to_string()))?;
+
     let port = app::config::get_port();
 
     // Create the HTTP server
@@ -34,7 +85,7 @@ async fn main() -> std::io::Result<()> {
 
     // Send the server handle to the test
     unsafe {
-        if let Some(tx) = &TX {
+        if let Some(tx) = &*std::ptr::addr_of!(TX) {
             tx.send(hnd).await.unwrap();
         }
     }
@@ -48,7 +99,6 @@ static mut TX: Option<mpsc::Sender<ServerHandle>> = None;
 #[cfg(test)]
 mod tests {
     use std::{
-        io,
         task::Poll,
         thread::sleep,
     };
@@ -68,13 +118,13 @@ mod tests {
         // Create a channel to send the server handle to the test
         let rx = create_server_handle_channel();
 
-        let handle = tokio::task::spawn_blocking(move || main());
+        let handle = tokio::task::spawn_blocking(|| run(Command::Serve));
 
         let hnd = receive_server_handle(rx).await;
 
         tokio::task::spawn_blocking(move || {
-            // Await the server to be ready
-            await_health(port)
+            // Await the server to be live, readiness depends on Redis
+            await_live(port)
         })
         .await??;
 
@@ -106,12 +156,12 @@ mod tests {
         rx
     }
 
-    fn await_health(port: u16) -> io::Result<()> {
+    fn await_live(port: u16) -> io::Result<()> {
         let times = Times {
             max:  60,
             step: tokio::time::Duration::from_secs(1),
         };
-        await_for("health", times, || poll_health(port))
+        await_for("liveness", times, || poll_live(port))
     }
 
     struct Times {
@@ -130,10 +180,10 @@ mod tests {
                 Poll::Ready(()) => return Ok(()),
                 Poll::Pending => {
                     if count >= times.max {
-                        return Err(io::Error::new(
-                            io::ErrorKind::Other,
-                            format!("Timed out waiting for {}", desc),
-                        ));
+                        return Err(io::Error::other(format!(
+                            "Timed out waiting for {}",
+                            desc
+                        )));
                     }
                     count += 1;
                     sleep(times.step)
@@ -142,14 +192,14 @@ mod tests {
         }
     }
 
-    fn poll_health(port: u16) -> Poll<()> {
-        get_health(port)
+    fn poll_live(port: u16) -> Poll<()> {
+        get_live(port)
             .map(|_| Poll::Ready(()))
             .unwrap_or(Poll::Pending)
     }
 
-    fn get_health(port: u16) -> Result<()> {
-        let url = format!("http://localhost:{}/health/ready", port);
+    fn get_live(port: u16) -> Result<()> {
+        let url = format!("http://localhost:{}/health/live", port);
         let client = reqwest::blocking::Client::new();
         log::info!("Checking health: {}", url);
         let res = client
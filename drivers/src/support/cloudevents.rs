@@ -1,47 +1,727 @@
-use crate::app::config::Config;
+use std::{
+    sync::Arc,
+    time::Duration,
+};
+
+use crate::{
+    app::{
+        config::{
+            Backoff,
+            ContentMode,
+            Delivery,
+            Events,
+            Knative,
+            Transport,
+        },
+        metrics,
+    },
+    support::{
+        deadletter::{
+            Letter,
+            Store,
+        },
+        kafka,
+        pool::Pool,
+        streams,
+        trace,
+    },
+};
 use actix_web::{
     error,
+    rt,
     Result,
 };
+use async_trait::async_trait;
+use chrono::{
+    DateTime,
+    Utc,
+};
 use cloudevents::{
-    binding::reqwest::RequestBuilderExt,
+    binding::reqwest::{
+        RequestBuilderExt,
+        RequestSerializer,
+    },
+    event::ExtensionValue,
+    message::StructuredDeserializer,
     AttributesReader,
+    Data,
     Event,
 };
+use hmac::{
+    Hmac,
+    Mac,
+};
+use reqwest::{
+    header,
+    StatusCode,
+};
+use serde::Deserialize;
+use serde_json::value::RawValue;
+use sha2::Sha256;
 
+/// Extension carrying the signature of the event data, as
+/// `sha256=<hex HMAC>` of its bytes as sent: the body in binary mode, or the
+/// `data` member as it is in the envelope in structured mode.
+pub const SIGNATURE: &str = "signature";
+
+/// Sends events with the configured transport, retrying failed attempts
+/// with backoff in the background, and keeping the events which couldn't be
+/// delivered as dead letters.
+#[derive(Clone, Debug)]
 pub struct Sender {
-    client: reqwest::Client,
-    sink:   String,
+    publisher: Arc<dyn Publisher>,
+    delivery:  Delivery,
+    dead:      Store,
+}
+
+/// Makes one attempt at delivering an event.
+#[async_trait]
+pub trait Publisher: std::fmt::Debug + Send + Sync {
+    async fn publish(&self, ce: Event) -> std::result::Result<(), Failure>;
+}
+
+/// A failed attempt at delivering an event.
+#[derive(Debug)]
+pub struct Failure {
+    pub error: String,
+    pub retry: bool,
+    /// Delay the receiver asked for before the next attempt.
+    pub after: Option<Duration>,
 }
 
 impl Sender {
-    pub fn new(cfg: &Config) -> Self {
-        let client = reqwest::Client::new();
-        let url = cfg.knative.sink.clone();
-        Self { client, sink: url }
+    pub fn new(cfg: &Knative, pool: Option<Pool>) -> Self {
+        let publisher: Arc<dyn Publisher> = match &cfg.transport {
+            Transport::Http => Arc::new(Http::new(cfg)),
+            Transport::Kafka(kafka) => Arc::new(
+                kafka::Producer::new(kafka, cfg.content, &cfg.delivery)
+                    .expect("invalid Kafka config"),
+            ),
+            Transport::Redis(streams) => Arc::new(streams::Producer::new(
+                streams,
+                pool.clone().expect("invalid Redis config"),
+            )),
+        };
+        Self {
+            publisher,
+            delivery: cfg.delivery.clone(),
+            dead: Store::new(&cfg.delivery.dead_letter, pool),
+        }
     }
 
+    /// The store of the events which couldn't be delivered.
+    pub fn dead_letters(&self) -> &Store {
+        &self.dead
+    }
+
+    /// Delivers the event, or keeps it as a dead letter if it can't be, in
+    /// which case it's only an error if it can't be kept either. Failed
+    /// attempts are retried in the background, so the caller isn't held up
+    /// by the backoff; the event is then kept if all of them fail. Without
+    /// a store to keep it, they're retried before returning instead, so
+    /// the caller learns of the failure.
     pub async fn send(&self, ce: Event) -> Result<()> {
-        log::debug!("sending {} event to {}:\n{:?}", ce.ty(), &self.sink, ce,);
+        log::debug!(
+            "sending {} event {} with {:?}",
+            ce.ty(),
+            ce.id(),
+            self.publisher
+        );
+
+        trace::in_span("Sender::send", async {
+            let mut ce = ce;
+            trace::inject(&mut ce);
+            let failure = match self.publisher.publish(ce.clone()).await {
+                Ok(()) => return self.settle(ce, Ok(())).await,
+                Err(failure) => failure,
+            };
+            if !failure.retry || self.delivery.backoff.retries == 0 {
+                return self.settle(ce, Err(failure.error)).await;
+            }
+            if matches!(self.dead, Store::None) {
+                let res = self.retry(&ce, failure).await;
+                return self.settle(ce, res).await;
+            }
+            let sender = self.clone();
+            rt::spawn(async move {
+                let res = sender.retry(&ce, failure).await;
+                if let Err(err) = sender.settle(ce, res).await {
+                    log::error!("failed to keep dead letter: {}", err);
+                }
+            });
+            Ok(())
+        })
+        .await
+    }
+
+    /// Delivers the event, as is, without keeping it if it can't be.
+    pub async fn resend(&self, ce: &Event) -> Result<()> {
+        let res = match self.publisher.publish(ce.clone()).await {
+            Ok(()) => Ok(()),
+            Err(failure) => self.retry(ce, failure).await,
+        };
+        metrics::event_sent(ce.ty(), res.as_ref().ok().map(|_| true));
+        res.map_err(error::ErrorServiceUnavailable)
+    }
+
+    /// Keeps the event as a dead letter if its delivery failed.
+    async fn settle(
+        &self,
+        ce: Event,
+        res: std::result::Result<(), String>,
+    ) -> Result<()> {
+        let ty = ce.ty().to_string();
+        let res = match res {
+            Ok(()) => Ok(true),
+            Err(error) => {
+                log::error!("failed to send {} event: {}", ty, error);
+                let letter = Letter {
+                    id: String::new(),
+                    at: Utc::now(),
+                    error,
+                    event: ce,
+                };
+                self.dead.put(&letter).await.map(|_| false)
+            }
+        };
+        metrics::event_sent(&ty, res.as_ref().ok().copied());
+        res.map(|_| ())
+    }
+
+    /// Retries delivering the event after the first attempt failed.
+    async fn retry(
+        &self,
+        ce: &Event,
+        failure: Failure,
+    ) -> std::result::Result<(), String> {
+        let mut failure = failure;
+        let mut attempt = 0;
+        loop {
+            if !failure.retry || attempt >= self.delivery.backoff.retries {
+                return Err(failure.error);
+            }
+            let delay = delay(&self.delivery, attempt, failure.after);
+            log::warn!(
+                "retrying {} event in {:?}: {}",
+                ce.ty(),
+                delay,
+                failure.error
+            );
+            metrics::event_retried(ce.ty());
+            rt::time::sleep(delay).await;
+            attempt += 1;
+            failure = match self.publisher.publish(ce.clone()).await {
+                Ok(()) => return Ok(()),
+                Err(failure) => failure,
+            };
+        }
+    }
+}
+
+/// Posts events to the sink.
+#[derive(Debug)]
+struct Http {
+    client:  reqwest::Client,
+    sink:    String,
+    content: ContentMode,
+}
+
+impl Http {
+    fn new(cfg: &Knative) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(cfg.delivery.timeout)
+            .connect_timeout(cfg.delivery.connect_timeout)
+            .build()
+            .expect("invalid HTTP client");
+        Self {
+            client,
+            sink: cfg.sink.clone(),
+            content: cfg.content,
+        }
+    }
+}
 
-        let response = self
-            .client
-            .post(&self.sink)
-            .event(ce)
-            .map_err(error::ErrorInternalServerError)?
+#[async_trait]
+impl Publisher for Http {
+    async fn publish(&self, ce: Event) -> std::result::Result<(), Failure> {
+        let request = self.client.post(&self.sink);
+        let request = match self.content {
+            ContentMode::Binary => request.event(ce),
+            ContentMode::Structured => {
+                ce.deserialize_structured(RequestSerializer::new(request))
+            }
+        };
+        let response = request
+            .map_err(|err| Failure {
+                error: err.to_string(),
+                retry: false,
+                after: None,
+            })?
             .send()
             .await
-            .map_err(error::ErrorInternalServerError)?;
-
-        match response.status().is_success() {
-            true => Ok(()),
-            false => {
-                log::error!("failed to send event: {:#?}", response);
-                Err(error::ErrorInternalServerError(format!(
-                    "failed to send event: {}",
-                    response.status()
-                )))
+            .map_err(|err| Failure {
+                error: err.to_string(),
+                retry: err.is_connect() || err.is_timeout() || err.is_request(),
+                after: None,
+            })?;
+
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        log::debug!("failed to send event: {:#?}", response);
+        Err(Failure {
+            error: status.to_string(),
+            retry: status.is_server_error()
+                || status == StatusCode::TOO_MANY_REQUESTS,
+            after: response
+                .headers()
+                .get(header::RETRY_AFTER)
+                .and_then(|val| val.to_str().ok())
+                .and_then(|val| retry_after(val, Utc::now())),
+        })
+    }
+}
+
+/// A random delay, up to `factor * base ^ attempt`.
+fn backoff(cfg: &Backoff, attempt: usize) -> Duration {
+    let exp = cfg
+        .base
+        .saturating_pow(attempt.try_into().unwrap_or(u32::MAX));
+    let max = cfg
+        .factor
+        .saturating_mul(exp.try_into().unwrap_or(u32::MAX));
+    max.mul_f64(rand::random::<f64>())
+}
+
+/// The delay before retrying the attempt, as the receiver asked for, if it
+/// did, but never longer than the configured maximum.
+pub(crate) fn delay(
+    cfg: &Delivery,
+    attempt: usize,
+    after: Option<Duration>,
+) -> Duration {
+    after
+        .unwrap_or_else(|| backoff(&cfg.backoff, attempt))
+        .min(cfg.max_delay)
+}
+
+/// Parses the `Retry-After` header, given in seconds or as an HTTP date.
+fn retry_after(val: &str, now: DateTime<Utc>) -> Option<Duration> {
+    match val.trim().parse::<u64>() {
+        Ok(secs) => Some(Duration::from_secs(secs)),
+        Err(_) => {
+            let at = DateTime::parse_from_rfc2822(val.trim()).ok()?;
+            Some((at.with_timezone(&Utc) - now).to_std().unwrap_or_default())
+        }
+    }
+}
+
+/// Why an incoming event was refused.
+#[derive(Debug, PartialEq)]
+pub enum Rejection {
+    Source,
+    Signature,
+}
+
+impl Rejection {
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Rejection::Source => "source",
+            Rejection::Signature => "signature",
+        }
+    }
+}
+
+/// Verifies the event comes from a source allowed for its type, and carries
+/// a valid signature, if a secret is configured.
+pub fn verify(cfg: &Events, ce: &Event) -> std::result::Result<(), Rejection> {
+    if !cfg.sources.is_empty() {
+        let source = ce.source().to_string();
+        let allowed = [ce.ty(), "*"]
+            .iter()
+            .filter_map(|ty| cfg.sources.get(*ty))
+            .any(|sources| sources.contains(&source));
+        if !allowed {
+            return Err(Rejection::Source);
+        }
+    }
+    let secret = match &cfg.secret {
+        Some(secret) => secret,
+        None => return Ok(()),
+    };
+    let sig = match ce.extension(SIGNATURE) {
+        Some(ExtensionValue::String(sig)) => sig.strip_prefix("sha256="),
+        _ => None,
+    };
+    let sig = sig
+        .and_then(|sig| hex::decode(sig).ok())
+        .ok_or(Rejection::Signature)?;
+    mac(secret, ce)
+        .ok_or(Rejection::Signature)?
+        .verify_slice(&sig)
+        .map_err(|_| Rejection::Signature)
+}
+
+/// Tells whether the origin of the event is verified at all: by its
+/// signature, or as coming from a source allowed for its type.
+pub fn verified(cfg: &Events, ce: &Event) -> bool {
+    (cfg.secret.is_some() || !cfg.sources.is_empty()) && verify(cfg, ce).is_ok()
+}
+
+/// The HMAC of the event data, as the bytes received, see [`received`].
+/// JSON data not kept as received isn't signed, as it would have to be
+/// serialized anew, maybe not as it was sent.
+fn mac(secret: &str, ce: &Event) -> Option<Hmac<Sha256>> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes())
+        .expect("HMAC takes keys of any size");
+    match ce.data() {
+        Some(Data::Binary(bytes)) => mac.update(bytes),
+        Some(Data::String(s)) => mac.update(s.as_bytes()),
+        Some(Data::Json(_)) => return None,
+        None => (),
+    }
+    Some(mac)
+}
+
+/// Keeps the JSON data of the event received as the bytes it was received
+/// as, which are what its signature is of, instead of as parsed. The data is
+/// still parsed as JSON when handled.
+pub fn received(mut ce: Event, data: Option<Vec<u8>>) -> Event {
+    if let (Some(Data::Json(_)), Some(data)) = (ce.data(), data) {
+        ce.set_data_unchecked(data);
+    }
+    ce
+}
+
+/// The data of the event in structured mode, as it is in the envelope, see
+/// [`received`].
+pub fn structured_data(envelope: &[u8]) -> Option<Vec<u8>> {
+    #[derive(Deserialize)]
+    struct Envelope<'a> {
+        #[serde(borrow)]
+        data: Option<&'a RawValue>,
+    }
+    let envelope: Envelope = serde_json::from_slice(envelope).ok()?;
+    envelope.data.map(|data| data.get().as_bytes().to_vec())
+}
+
+/// Reads the event in structured mode, keeping its data as received.
+pub fn from_structured(envelope: &[u8]) -> serde_json::Result<Event> {
+    let ce = serde_json::from_slice(envelope)?;
+    Ok(received(ce, structured_data(envelope)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{
+            HashMap,
+            HashSet,
+        },
+        sync::{
+            atomic::{
+                AtomicUsize,
+                Ordering,
+            },
+            Arc,
+        },
+    };
+
+    use actix_web::{
+        web,
+        App,
+        HttpResponse,
+        HttpServer,
+    };
+    use chrono::TimeZone;
+    use cloudevents::{
+        EventBuilder,
+        EventBuilderV10,
+    };
+
+    use crate::app::config::DeadLetter;
+
+    use super::*;
+
+    const TYPE: &str = "cabs.drivers.calculate-fee";
+
+    fn event(source: &str, sig: Option<String>) -> Event {
+        let mut builder = EventBuilderV10::new()
+            .id("1")
+            .source(source)
+            .ty(TYPE)
+            .data("application/json", br#"{"driver-id": 1}"#.to_vec());
+        if let Some(sig) = sig {
+            builder = builder.extension(SIGNATURE, sig);
+        }
+        builder.build().unwrap()
+    }
+
+    fn sign(secret: &str, ce: &Event) -> String {
+        let sig = mac(secret, ce).unwrap().finalize().into_bytes();
+        format!("sha256={}", hex::encode(sig))
+    }
+
+    fn sources(ty: &str, source: &str) -> HashMap<String, HashSet<String>> {
+        HashMap::from([(ty.to_string(), HashSet::from([source.to_string()]))])
+    }
+
+    #[test]
+    fn any_source_by_default() {
+        let cfg = Events::default();
+        assert_eq!(verify(&cfg, &event("usvc://anyone", None)), Ok(()));
+    }
+
+    #[test]
+    fn allowed_sources() {
+        let cfg = Events {
+            sources: sources(TYPE, "usvc://cabs/legacy"),
+            ..Events::default()
+        };
+        assert_eq!(verify(&cfg, &event("usvc://cabs/legacy", None)), Ok(()));
+        assert_eq!(
+            verify(&cfg, &event("usvc://anyone", None)),
+            Err(Rejection::Source)
+        );
+
+        let cfg = Events {
+            sources: sources("*", "usvc://cabs/legacy"),
+            ..Events::default()
+        };
+        assert_eq!(verify(&cfg, &event("usvc://cabs/legacy", None)), Ok(()));
+    }
+
+    #[test]
+    fn signature() {
+        let cfg = Events {
+            secret: Some("s3cr3t".to_string()),
+            ..Events::default()
+        };
+        let unsigned = event("usvc://cabs/legacy", None);
+        assert_eq!(verify(&cfg, &unsigned), Err(Rejection::Signature));
+
+        let sig = sign("s3cr3t", &unsigned);
+        let signed = event("usvc://cabs/legacy", Some(sig));
+        assert_eq!(verify(&cfg, &signed), Ok(()));
+
+        let forged =
+            event("usvc://cabs/legacy", Some(sign("guess", &unsigned)));
+        assert_eq!(verify(&cfg, &forged), Err(Rejection::Signature));
+    }
+
+    #[test]
+    fn signs_data_as_received() {
+        let cfg = Events {
+            secret: Some("s3cr3t".to_string()),
+            ..Events::default()
+        };
+        let data = r#"{"driver-id": 1, "currency":"PLN"}"#;
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"s3cr3t").unwrap();
+        mac.update(data.as_bytes());
+        let sig = hex::encode(mac.finalize().into_bytes());
+        let envelope = format!(
+            r#"{{"specversion": "1.0", "id": "1", "type": "{}",
+                "source": "usvc://cabs/legacy", "signature": "sha256={}",
+                "datacontenttype": "application/json", "data": {}}}"#,
+            TYPE, sig, data
+        );
+
+        let ce = from_structured(envelope.as_bytes()).unwrap();
+        assert_eq!(verify(&cfg, &ce), Ok(()));
+        let json = serde_json::Value::try_from(ce.data().unwrap().clone());
+        assert_eq!(json.unwrap()["currency"], "PLN");
+
+        // Parsed, the data would have to be serialized anew.
+        let ce: Event = serde_json::from_str(&envelope).unwrap();
+        assert_eq!(verify(&cfg, &ce), Err(Rejection::Signature));
+    }
+
+    #[test]
+    fn parses_retry_after() {
+        let now = Utc.with_ymd_and_hms(2015, 10, 21, 7, 28, 0).unwrap();
+        assert_eq!(retry_after("120", now), Some(Duration::from_secs(120)));
+        assert_eq!(
+            retry_after("Wed, 21 Oct 2015 07:28:30 GMT", now),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            retry_after("Wed, 21 Oct 2015 07:27:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(retry_after("soon", now), None);
+        assert_eq!(retry_after("-1", now), None);
+    }
+
+    #[test]
+    fn backs_off_exponentially() {
+        let cfg = Backoff {
+            base:    2,
+            factor:  Duration::from_millis(100),
+            retries: 5,
+        };
+        for attempt in 0..5 {
+            let max = Duration::from_millis(100 << attempt);
+            for _ in 0..100 {
+                assert!(backoff(&cfg, attempt) <= max);
+            }
+        }
+    }
+
+    #[test]
+    fn caps_delays() {
+        let mut cfg = crate::app::config::Config::default().knative.delivery;
+        cfg.backoff = Backoff {
+            base:    2,
+            factor:  Duration::from_secs(1),
+            retries: 5,
+        };
+        cfg.max_delay = Duration::from_secs(30);
+        for _ in 0..100 {
+            assert!(delay(&cfg, usize::MAX, None) <= cfg.max_delay);
+        }
+        let asked = Some(Duration::from_secs(3600));
+        assert_eq!(delay(&cfg, 0, asked), cfg.max_delay);
+        let asked = Some(Duration::from_secs(2));
+        assert_eq!(delay(&cfg, 0, asked), Duration::from_secs(2));
+    }
+
+    /// A sink answering with the given statuses in turn, then with the last
+    /// one, counting the attempts.
+    fn sink(statuses: &'static [u16]) -> (String, Arc<AtomicUsize>) {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let server = {
+            let attempts = attempts.clone();
+            HttpServer::new(move || {
+                let attempts = attempts.clone();
+                App::new().default_service(web::to(move || {
+                    let n = attempts.fetch_add(1, Ordering::SeqCst);
+                    let status = statuses[n.min(statuses.len() - 1)];
+                    async move {
+                        HttpResponse::build(
+                            actix_web::http::StatusCode::from_u16(status)
+                                .unwrap(),
+                        )
+                        .finish()
+                    }
+                }))
+            })
+            .workers(1)
+            .bind(("127.0.0.1", 0))
+            .unwrap()
+        };
+        let addr = server.addrs()[0];
+        actix_web::rt::spawn(server.run());
+        (format!("http://{}/", addr), attempts)
+    }
+
+    fn sender(sink: String, dead_letter: DeadLetter) -> Sender {
+        let mut cfg = crate::app::config::Config::default().knative;
+        cfg.sink = sink;
+        cfg.delivery.backoff = Backoff {
+            base:    2,
+            factor:  Duration::from_millis(1),
+            retries: 3,
+        };
+        cfg.delivery.dead_letter = dead_letter;
+        Sender::new(&cfg, None)
+    }
+
+    /// Waits for the retries in the background to be done.
+    async fn settled(done: impl Fn() -> bool) {
+        for _ in 0..200 {
+            if done() {
+                return;
             }
+            rt::time::sleep(Duration::from_millis(10)).await;
         }
+        panic!("retries not done");
+    }
+
+    #[actix_web::test]
+    async fn retries_until_delivered() {
+        let (url, attempts) = sink(&[503, 429, 202]);
+        let sender = sender(url, DeadLetter::None);
+        sender
+            .send(event("usvc://cabs/drivers", None))
+            .await
+            .unwrap();
+        settled(|| attempts.load(Ordering::SeqCst) == 3).await;
+    }
+
+    #[actix_web::test]
+    async fn retries_in_background() {
+        let (url, attempts) = sink(&[503]);
+        let path = std::env::temp_dir()
+            .join(format!("dead-letters-{}.ndjson", uuid::Uuid::new_v4()));
+        let mut sender = sender(url, DeadLetter::File(path));
+        sender.delivery.backoff.factor = Duration::from_secs(3600);
+        sender.delivery.max_delay = Duration::from_secs(3600);
+        rt::time::timeout(
+            Duration::from_secs(5),
+            sender.send(event("usvc://cabs/drivers", None)),
+        )
+        .await
+        .expect("held up by the retries")
+        .unwrap();
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[actix_web::test]
+    async fn dead_letters_undeliverable() {
+        let path = std::env::temp_dir()
+            .join(format!("dead-letters-{}.ndjson", uuid::Uuid::new_v4()));
+
+        let (url, attempts) = sink(&[400]);
+        let refused = sender(url, DeadLetter::File(path.clone()));
+        refused
+            .send(event("usvc://cabs/drivers", None))
+            .await
+            .unwrap();
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+
+        let (url, attempts) = sink(&[500]);
+        let failing = sender(url, DeadLetter::File(path.clone()));
+        failing
+            .send(event("usvc://cabs/drivers", None))
+            .await
+            .unwrap();
+        let kept = || {
+            std::fs::read_to_string(&path)
+                .map(|letters| letters.lines().count() == 2)
+                .unwrap_or(false)
+        };
+        settled(kept).await;
+        assert_eq!(attempts.load(Ordering::SeqCst), 4);
+
+        let letters = failing.dead_letters().list().await.unwrap();
+        assert_eq!(letters.len(), 2);
+        assert_eq!(letters[0].error, "400 Bad Request");
+        assert_eq!(letters[1].error, "500 Internal Server Error");
+        assert_eq!(letters[1].event.ty(), TYPE);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn fails_without_dead_letters() {
+        let (url, _) = sink(&[400]);
+        let refused = sender(url, DeadLetter::None);
+        assert!(refused
+            .send(event("usvc://cabs/drivers", None))
+            .await
+            .is_err());
+        assert!(refused
+            .resend(&event("usvc://cabs/drivers", None))
+            .await
+            .is_err());
+
+        // Retried before failing, as it can't be kept.
+        let (url, attempts) = sink(&[503]);
+        let failing = sender(url, DeadLetter::None);
+        assert!(failing
+            .send(event("usvc://cabs/drivers", None))
+            .await
+            .is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 4);
     }
 }
@@ -0,0 +1,203 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::Arc,
+};
+
+use aes_gcm::{
+    aead::{
+        Aead,
+        AeadCore,
+        KeyInit,
+        OsRng,
+    },
+    Aes256Gcm,
+    Nonce,
+};
+use data_encoding::BASE64;
+
+/// Prefix of encrypted values, followed by the key ID and the base64 of the
+/// nonce and ciphertext: `enc:<key-id>:<base64>`.
+const PREFIX: &str = "enc:";
+
+const NONCE_LEN: usize = 12;
+
+/// Encrypts values with AES-256-GCM, under the current key, and decrypts
+/// them with whichever of the keys they were encrypted with. Without keys,
+/// values are kept as they are.
+#[derive(Clone, Default)]
+pub struct Cipher {
+    current: Option<String>,
+    keys:    Arc<HashMap<String, Aes256Gcm>>,
+}
+
+impl Cipher {
+    pub fn new(
+        keys: &HashMap<String, Vec<u8>>,
+        current: Option<&str>,
+    ) -> Result<Self, String> {
+        let current = match (current, keys.len()) {
+            (Some(kid), _) if keys.contains_key(kid) => Some(kid.to_string()),
+            (Some(kid), _) => return Err(format!("unknown key ID: {}", kid)),
+            (None, 0) => None,
+            (None, 1) => keys.keys().next().cloned(),
+            (None, _) => return Err("current key ID not given".to_string()),
+        };
+        let keys = keys
+            .iter()
+            .map(|(kid, key)| {
+                Aes256Gcm::new_from_slice(key)
+                    .map(|cipher| (kid.clone(), cipher))
+                    .map_err(|_| format!("key {} isn't 256 bits long", kid))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Cipher {
+            current,
+            keys: Arc::new(keys),
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.current.is_some()
+    }
+
+    pub fn encrypt(&self, plain: &str) -> Result<String, String> {
+        let kid = match &self.current {
+            Some(kid) => kid,
+            None => return Ok(plain.to_string()),
+        };
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let mut data = nonce.to_vec();
+        data.extend(
+            self.keys[kid]
+                .encrypt(&nonce, plain.as_bytes())
+                .map_err(|e| e.to_string())?,
+        );
+        Ok(format!("{}{}:{}", PREFIX, kid, BASE64.encode(&data)))
+    }
+
+    /// Decrypts the value, if it is encrypted.
+    pub fn decrypt(&self, val: &str) -> Result<String, String> {
+        let (kid, data) = match split(val) {
+            Some(parts) => parts,
+            None => return Ok(val.to_string()),
+        };
+        let cipher = self
+            .keys
+            .get(kid)
+            .ok_or(format!("unknown key ID: {}", kid))?;
+        let data = BASE64.decode(data.as_bytes()).map_err(|e| e.to_string())?;
+        if data.len() < NONCE_LEN {
+            return Err("ciphertext too short".to_string());
+        }
+        let (nonce, ct) = data.split_at(NONCE_LEN);
+        let plain = cipher
+            .decrypt(Nonce::from_slice(nonce), ct)
+            .map_err(|_| format!("can't decrypt with key {}", kid))?;
+        String::from_utf8(plain).map_err(|e| e.to_string())
+    }
+
+    /// Tells whether the value is as it would be encrypted now: under the
+    /// current key, or in plain text without keys.
+    pub fn is_current(&self, val: &str) -> bool {
+        split(val).map(|(kid, _)| kid) == self.current.as_deref()
+    }
+}
+
+fn split(val: &str) -> Option<(&str, &str)> {
+    val.strip_prefix(PREFIX)?.split_once(':')
+}
+
+impl fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut kids: Vec<&String> = self.keys.keys().collect();
+        kids.sort();
+        f.debug_struct("Cipher")
+            .field("current", &self.current)
+            .field("keys", &kids)
+            .finish()
+    }
+}
+
+/// Parses keys given as `key-id:base64;key-id:base64`.
+pub fn parse_keys(spec: &str) -> Result<HashMap<String, Vec<u8>>, String> {
+    spec.split(';')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(|entry| {
+            let (kid, key) = entry
+                .split_once(':')
+                .ok_or(format!("key without ID: {}", entry))?;
+            let key = BASE64
+                .decode(key.trim().as_bytes())
+                .map_err(|e| format!("invalid key {}: {}", kid, e))?;
+            Ok((kid.trim().to_string(), key))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys() -> HashMap<String, Vec<u8>> {
+        HashMap::from([
+            ("k1".to_string(), vec![1; 32]),
+            ("k2".to_string(), vec![2; 32]),
+        ])
+    }
+
+    #[test]
+    fn round_trip() {
+        let cipher = Cipher::new(&keys(), Some("k1")).unwrap();
+        let enc = cipher.encrypt("FARME100165AB5EW").unwrap();
+        assert!(enc.starts_with("enc:k1:"));
+        assert!(!enc.contains("FARME100165AB5EW"));
+        assert_ne!(enc, cipher.encrypt("FARME100165AB5EW").unwrap());
+        assert_eq!(cipher.decrypt(&enc).unwrap(), "FARME100165AB5EW");
+        assert!(cipher.is_current(&enc));
+        assert_eq!(cipher.decrypt("plain").unwrap(), "plain");
+        assert!(!cipher.is_current("plain"));
+    }
+
+    #[test]
+    fn rotation() {
+        let old = Cipher::new(&keys(), Some("k1")).unwrap();
+        let enc = old.encrypt("jan@example.com").unwrap();
+
+        let new = Cipher::new(&keys(), Some("k2")).unwrap();
+        assert!(!new.is_current(&enc));
+        assert_eq!(new.decrypt(&enc).unwrap(), "jan@example.com");
+
+        let retired = Cipher::new(
+            &HashMap::from([("k2".to_string(), vec![2; 32])]),
+            None,
+        )
+        .unwrap();
+        assert!(retired.decrypt(&enc).is_err());
+    }
+
+    #[test]
+    fn disabled() {
+        let cipher = Cipher::default();
+        assert!(!cipher.enabled());
+        assert_eq!(cipher.encrypt("plain").unwrap(), "plain");
+        assert!(cipher.is_current("plain"));
+    }
+
+    #[test]
+    fn invalid_keys() {
+        assert!(Cipher::new(&keys(), None).is_err());
+        assert!(Cipher::new(&keys(), Some("k3")).is_err());
+        let short = HashMap::from([("k1".to_string(), vec![1; 16])]);
+        assert!(Cipher::new(&short, None).is_err());
+        assert!(parse_keys("k1").is_err());
+        assert_eq!(
+            parse_keys("k1:AQID; k2:BAUG").unwrap(),
+            HashMap::from([
+                ("k1".to_string(), vec![1, 2, 3]),
+                ("k2".to_string(), vec![4, 5, 6]),
+            ])
+        );
+    }
+}
@@ -0,0 +1,210 @@
+use std::path::PathBuf;
+
+use actix_web::{
+    error,
+    Result,
+};
+use chrono::{
+    DateTime,
+    Utc,
+};
+use cloudevents::Event;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use tokio::{
+    fs,
+    io::AsyncWriteExt,
+};
+
+use crate::{
+    app::config::DeadLetter,
+    support::pool::Pool,
+};
+
+/// An event which couldn't be delivered, with why.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Letter {
+    /// ID of the letter in the store, to remove it once replayed.
+    #[serde(skip_serializing_if = "String::is_empty", default)]
+    pub id:    String,
+    pub at:    DateTime<Utc>,
+    pub error: String,
+    pub event: Event,
+}
+
+/// Keeps the events which couldn't be delivered, so they can be inspected
+/// and replayed.
+#[derive(Clone, Debug)]
+pub enum Store {
+    None,
+    Redis { pool: Pool, stream: String },
+    File(PathBuf),
+}
+
+impl Store {
+    pub fn new(config: &DeadLetter, pool: Option<Pool>) -> Self {
+        match (config, pool) {
+            (DeadLetter::Redis(stream), Some(pool)) => Store::Redis {
+                pool,
+                stream: stream.clone(),
+            },
+            (DeadLetter::File(path), _) => Store::File(path.clone()),
+            _ => Store::None,
+        }
+    }
+
+    pub async fn put(&self, letter: &Letter) -> Result<()> {
+        let json = serde_json::to_string(letter)?;
+        match self {
+            Store::None => {
+                Err(error::ErrorInternalServerError("no dead letter store"))
+            }
+            Store::Redis { pool, stream } => {
+                let mut conn =
+                    pool.get().await.map_err(error::ErrorServiceUnavailable)?;
+                redis::Cmd::xadd(stream, "*", &[("letter", json)])
+                    .query_async::<_, ()>(&mut conn)
+                    .await
+                    .map_err(error::ErrorInternalServerError)
+            }
+            Store::File(path) => {
+                let mut file = fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .await?;
+                file.write_all(format!("{}\n", json).as_bytes()).await?;
+                Ok(file.flush().await?)
+            }
+        }
+    }
+
+    /// Lists the letters, oldest first. Letters of the file are identified
+    /// by their line, from 1.
+    pub async fn list(&self) -> Result<Vec<Letter>> {
+        let entries: Vec<(String, String)> = match self {
+            Store::None => vec![],
+            Store::Redis { pool, stream } => {
+                let mut conn =
+                    pool.get().await.map_err(error::ErrorServiceUnavailable)?;
+                let reply: redis::streams::StreamRangeReply =
+                    redis::Cmd::xrange_all(stream)
+                        .query_async(&mut conn)
+                        .await
+                        .map_err(error::ErrorInternalServerError)?;
+                reply
+                    .ids
+                    .into_iter()
+                    .filter_map(|entry| {
+                        let json = entry.get::<String>("letter")?;
+                        Some((entry.id, json))
+                    })
+                    .collect()
+            }
+            Store::File(path) => match fs::read_to_string(path).await {
+                Ok(text) => lines(&text),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                    vec![]
+                }
+                Err(err) => return Err(err.into()),
+            },
+        };
+        entries
+            .into_iter()
+            .map(|(id, json)| {
+                let letter: Letter = serde_json::from_str(&json)
+                    .map_err(error::ErrorInternalServerError)?;
+                Ok(Letter { id, ..letter })
+            })
+            .collect()
+    }
+
+    /// Removes the letters, once replayed.
+    pub async fn remove(&self, ids: &[String]) -> Result<()> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+        match self {
+            Store::None => Ok(()),
+            Store::Redis { pool, stream } => {
+                let mut conn =
+                    pool.get().await.map_err(error::ErrorServiceUnavailable)?;
+                redis::Cmd::xdel(stream, ids)
+                    .query_async::<_, ()>(&mut conn)
+                    .await
+                    .map_err(error::ErrorInternalServerError)
+            }
+            Store::File(path) => {
+                // Letters put while replaying may be lost, so the file is
+                // best replayed with the server stopped.
+                let text = fs::read_to_string(path).await?;
+                let kept: String = lines(&text)
+                    .into_iter()
+                    .filter(|(id, _)| !ids.contains(id))
+                    .map(|(_, json)| json + "\n")
+                    .collect();
+                Ok(fs::write(path, kept).await?)
+            }
+        }
+    }
+}
+
+fn lines(text: &str) -> Vec<(String, String)> {
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(n, line)| ((n + 1).to_string(), line.to_string()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use cloudevents::{
+        AttributesReader,
+        EventBuilder,
+        EventBuilderV10,
+    };
+
+    use super::*;
+
+    #[actix_web::test]
+    async fn file() {
+        let path = std::env::temp_dir()
+            .join(format!("dead-letters-{}.ndjson", uuid::Uuid::new_v4()));
+        let store = Store::new(&DeadLetter::File(path.clone()), None);
+        assert!(store.list().await.unwrap().is_empty());
+
+        for id in ["1", "2"] {
+            let event = EventBuilderV10::new()
+                .id(id)
+                .source("usvc://cabs/drivers")
+                .ty("cabs.drivers.driver-fee")
+                .data("application/json", serde_json::json!({"fee": 1}))
+                .build()
+                .unwrap();
+            let letter = Letter {
+                id: String::new(),
+                at: Utc::now(),
+                error: "503 Service Unavailable".to_string(),
+                event,
+            };
+            store.put(&letter).await.unwrap();
+        }
+
+        let letters = store.list().await.unwrap();
+        assert_eq!(
+            letters.iter().map(|l| l.id.as_str()).collect::<Vec<_>>(),
+            ["1", "2"]
+        );
+        assert_eq!(letters[1].event.id(), "2");
+
+        store.remove(&["1".to_string()]).await.unwrap();
+        let letters = store.list().await.unwrap();
+        assert_eq!(letters.len(), 1);
+        assert_eq!(letters[0].event.id(), "2");
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,350 @@
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    future::Future,
+    marker::PhantomData,
+};
+
+use actix_web::{
+    error,
+    Result,
+};
+use cloudevents::{
+    AttributesReader,
+    Event,
+};
+use futures::future::{
+    self,
+    LocalBoxFuture,
+};
+use jsonschema::JSONSchema;
+use schemars::{
+    schema_for,
+    JsonSchema,
+};
+use serde::{
+    de::DeserializeOwned,
+    Serialize,
+};
+
+use crate::support::id::Subject;
+
+/// Dispatches the events received to the handlers registered for their
+/// types, given their data checked and deserialized, and a context `C`,
+/// e.g. a service.
+pub struct Dispatcher<C> {
+    handlers: HashMap<String, Box<dyn Handler<C>>>,
+}
+
+trait Handler<C>: Send + Sync {
+    fn handle(
+        &self,
+        ctx: C,
+        ce: Event,
+    ) -> LocalBoxFuture<'static, Result<Option<Event>>>;
+}
+
+/// A handler of the events whose data is of type `T`.
+struct Typed<T, F> {
+    schema:  JSONSchema,
+    handler: F,
+    data:    PhantomData<fn() -> T>,
+}
+
+impl<C, T, F, Fut> Handler<C> for Typed<T, F>
+where
+    T: DeserializeOwned + Debug + Clone + Serialize,
+    F: Fn(C, Subject<T>) -> Fut + Send + Sync,
+    Fut: Future<Output = Result<Option<Event>>> + 'static,
+{
+    fn handle(
+        &self,
+        ctx: C,
+        ce: Event,
+    ) -> LocalBoxFuture<'static, Result<Option<Event>>> {
+        match unwrap(&self.schema, ce) {
+            Ok(subject) => Box::pin((self.handler)(ctx, subject)),
+            Err(err) => Box::pin(future::ready(Err(err))),
+        }
+    }
+}
+
+impl<C> Default for Dispatcher<C> {
+    fn default() -> Self {
+        Self {
+            handlers: HashMap::new(),
+        }
+    }
+}
+
+impl<C> Dispatcher<C> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers the handler of the events of the type, whose data is
+    /// validated against the JSON Schema of `T` before being deserialized.
+    /// Types are versioned as resolved by [`resolve`].
+    pub fn on<T, F, Fut>(mut self, ty: &str, handler: F) -> Self
+    where
+        T: DeserializeOwned + Debug + Clone + Serialize + JsonSchema + 'static,
+        F: Fn(C, Subject<T>) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<Option<Event>>> + 'static,
+    {
+        let typed = Typed {
+            schema: compile::<T>(),
+            handler,
+            data: PhantomData,
+        };
+        let prev = self.handlers.insert(ty.to_string(), Box::new(typed));
+        assert!(prev.is_none(), "handler of {} registered twice", ty);
+        self
+    }
+
+    /// Handles the event, returning the event in reply, if any. Events with
+    /// no handler, or whose data doesn't conform, are refused.
+    pub async fn dispatch(&self, ctx: C, ce: Event) -> Result<Option<Event>> {
+        let ty = resolve(&ce);
+        match self.handlers.get(&ty) {
+            Some(handler) => handler.handle(ctx, ce).await,
+            None => Err(error::ErrorBadRequest(format!(
+                "unsupported event type: {}",
+                ty
+            ))),
+        }
+    }
+}
+
+/// The versioned type of the event. Types are suffixed by `.v<n>` from the
+/// second version on, the first keeping the unversioned type, and an
+/// unversioned type may be versioned by its `dataschema` instead, whose last
+/// segment is then the versioned type.
+pub fn resolve(ce: &Event) -> String {
+    let (ty, version) = match split(ce.ty()) {
+        (ty, Some(version)) => (ty, Some(version)),
+        (ty, None) => {
+            let schema = ce.dataschema().map(|url| url.path()).unwrap_or("");
+            match split(schema.rsplit('/').next().unwrap_or("")) {
+                (name, Some(version)) if name == ty => (ty, Some(version)),
+                _ => (ty, None),
+            }
+        }
+    };
+    match version {
+        None | Some("v1") => ty.to_string(),
+        Some(version) => format!("{}.{}", ty, version),
+    }
+}
+
+fn split(ty: &str) -> (&str, Option<&str>) {
+    match ty.rsplit_once('.') {
+        Some((name, version))
+            if version.len() > 1
+                && version.starts_with('v')
+                && version[1..].bytes().all(|b| b.is_ascii_digit()) =>
+        {
+            (name, Some(version))
+        }
+        _ => (ty, None),
+    }
+}
+
+/// Unwraps the data of the event, and its subject.
+fn unwrap<T>(schema: &JSONSchema, ce: Event) -> Result<Subject<T>>
+where
+    T: DeserializeOwned + Debug + Clone + Serialize,
+{
+    if let Some(ct) = ce.datacontenttype().filter(|ct| !is_json(ct)) {
+        return Err(error::ErrorUnsupportedMediaType(format!(
+            "unsupported content type: {}",
+            ct
+        )));
+    }
+
+    let data = match ce.data() {
+        Some(data) => serde_json::Value::try_from(data.clone())
+            .map_err(error::ErrorBadRequest)?,
+        None => return Err(error::ErrorBadRequest("missing data")),
+    };
+
+    validate(schema, &data)?;
+    let entity = serde_json::from_value(data).map_err(|err| {
+        error::ErrorBadRequest(format!("failed to parse event: {}", err))
+    })?;
+
+    let id = ce.subject().map(|s| s.to_string());
+
+    Ok(Subject { id, entity })
+}
+
+/// Whether the content type is JSON, as events without one are taken to be.
+fn is_json(ct: &str) -> bool {
+    let essence = ct.split(';').next().unwrap_or("").trim();
+    essence.eq_ignore_ascii_case("application/json")
+        || essence.eq_ignore_ascii_case("text/json")
+        || essence.to_ascii_lowercase().ends_with("+json")
+}
+
+pub(crate) fn compile<T: JsonSchema>() -> JSONSchema {
+    let schema = serde_json::to_value(schema_for!(T)).expect("valid schema");
+    JSONSchema::compile(&schema).expect("valid schema")
+}
+
+/// Validates the data against the schema, listing where it doesn't conform,
+/// and why.
+pub(crate) fn validate(
+    schema: &JSONSchema,
+    data: &serde_json::Value,
+) -> Result<()> {
+    schema.validate(data).map_err(|errors| {
+        let errors: Vec<String> = errors
+            .map(|err| match err.instance_path.to_string() {
+                path if path.is_empty() => err.to_string(),
+                path => format!("{}: {}", path, err),
+            })
+            .collect();
+        error::ErrorBadRequest(format!("invalid data: {}", errors.join("; ")))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use cloudevents::{
+        EventBuilder,
+        EventBuilderV10,
+    };
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Clone, Debug, Deserialize, Serialize, JsonSchema)]
+    struct Greet {
+        name: String,
+    }
+
+    fn event(ty: &str, ct: &str, data: serde_json::Value) -> Event {
+        EventBuilderV10::new()
+            .id("1")
+            .source("usvc://cabs/legacy")
+            .ty(ty)
+            .subject("42")
+            .data(ct, data)
+            .build()
+            .unwrap()
+    }
+
+    fn dispatcher() -> Dispatcher<String> {
+        Dispatcher::new()
+            .on(
+                "greet",
+                |greeting: String, cmd: Subject<Greet>| async move {
+                    assert_eq!(cmd.id.as_deref(), Some("42"));
+                    let reply = EventBuilderV10::new()
+                        .id("2")
+                        .source("usvc://cabs/drivers")
+                        .ty("greeted")
+                        .data(
+                            "text/plain",
+                            format!("{}, {}", greeting, cmd.entity.name),
+                        )
+                        .build()
+                        .unwrap();
+                    Ok(Some(reply))
+                },
+            )
+            .on("greet.v2", |_, _: Subject<Greet>| async { Ok(None) })
+    }
+
+    async fn dispatch(ce: Event) -> Result<Option<Event>> {
+        dispatcher().dispatch("Hello".to_string(), ce).await
+    }
+
+    #[actix_web::test]
+    async fn dispatches() {
+        let data = serde_json::json!({"name": "John"});
+        let reply = dispatch(event("greet", "application/json", data.clone()))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(reply.ty(), "greeted");
+        assert_eq!(
+            reply.data(),
+            Some(&cloudevents::Data::String("Hello, John".to_string()))
+        );
+
+        for ct in ["application/json; charset=utf-8", "application/ld+json"] {
+            let ce = event("greet", ct, data.clone());
+            assert!(dispatch(ce).await.unwrap().is_some(), "{}", ct);
+        }
+        let ce = event("greet.v2", "application/json", data.clone());
+        assert!(dispatch(ce).await.unwrap().is_none());
+    }
+
+    #[actix_web::test]
+    async fn refuses() {
+        let refused = |ce| async {
+            let err = dispatch(ce).await.unwrap_err();
+            let status = err.as_response_error().status_code().as_u16();
+            (status, err.to_string())
+        };
+        let data = serde_json::json!({"name": "John"});
+        assert_eq!(
+            refused(event("wave", "application/json", data.clone())).await,
+            (400, "unsupported event type: wave".to_string())
+        );
+        assert_eq!(
+            refused(event("greet.v3", "application/json", data.clone())).await,
+            (400, "unsupported event type: greet.v3".to_string())
+        );
+        assert_eq!(
+            refused(event("greet", "text/plain", data)).await,
+            (415, "unsupported content type: text/plain".to_string())
+        );
+        assert_eq!(
+            refused(event(
+                "greet",
+                "application/json",
+                serde_json::json!({"name": 1})
+            ))
+            .await,
+            (
+                400,
+                r#"invalid data: /name: 1 is not of type "string""#.to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn resolves_versions() {
+        let resolved = |ty, schema: Option<&str>| {
+            let builder = EventBuilderV10::new()
+                .id("1")
+                .source("usvc://cabs/legacy")
+                .ty(ty);
+            let data = serde_json::json!({});
+            match schema {
+                Some(url) => builder.data_with_schema(
+                    "application/json",
+                    url.to_string(),
+                    data,
+                ),
+                None => builder.data("application/json", data),
+            }
+            .build()
+            .map(|ce| resolve(&ce))
+            .unwrap()
+        };
+        assert_eq!(resolved("a.fee", None), "a.fee");
+        assert_eq!(resolved("a.fee.v1", None), "a.fee");
+        assert_eq!(resolved("a.fee.v2", None), "a.fee.v2");
+        assert_eq!(resolved("a.fee.vx", None), "a.fee.vx");
+        assert_eq!(
+            resolved("a.fee", Some("http://drivers/schemas/a.fee.v2")),
+            "a.fee.v2"
+        );
+        assert_eq!(
+            resolved("a.fee", Some("http://drivers/schemas/other.v2")),
+            "a.fee"
+        );
+    }
+}
@@ -1,19 +1,40 @@
-use std::fmt::Debug;
+use std::cell::Cell;
+use std::fmt::{
+    self,
+    Debug,
+    Display,
+};
 use std::ops::Sub;
+use std::str::FromStr;
+use std::sync::{
+    Arc,
+    Mutex,
+};
 
 use chrono::{
     DateTime,
-    Local,
     TimeZone,
+    Utc,
 };
 use data_encoding::Encoding;
 use num::{
     BigInt,
     ToPrimitive,
 };
+use schemars::{
+    gen::SchemaGenerator,
+    schema::Schema,
+    JsonSchema,
+};
 use serde::{
+    de::{
+        self,
+        Visitor,
+    },
     Deserialize,
+    Deserializer,
     Serialize,
+    Serializer,
 };
 
 use crate::support::clock::{
@@ -36,36 +57,236 @@ where
     pub entity: T,
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Identifier(i64);
 
-impl Identifier {
-    pub fn new(clock: &Clock) -> Self {
-        let n = clock.now();
-        let diff = n.sub(EPOCH.clone());
-        let ms = diff.num_milliseconds();
-        Identifier(ms)
+/// Representation of identifiers in JSON: the number, or its textual form.
+/// Both are accepted when deserializing.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Repr {
+    #[default]
+    Int,
+    Base32,
+}
+
+impl FromStr for Repr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "int" => Ok(Repr::Int),
+            "base32" => Ok(Repr::Base32),
+            _ => Err(format!("unknown ID representation: {}", s)),
+        }
+    }
+}
+
+impl Repr {
+    /// Serializes the value with the identifiers within in this
+    /// representation. Elsewhere, as when stored, they are numbers.
+    pub fn to_value<T: Serialize + ?Sized>(
+        self,
+        value: &T,
+    ) -> serde_json::Result<serde_json::Value> {
+        let _scope = ReprScope(REPR.with(|r| r.replace(self)));
+        serde_json::to_value(value)
+    }
+}
+
+thread_local! {
+    /// The representation of the serialization in progress, on this thread.
+    static REPR: Cell<Repr> = const { Cell::new(Repr::Int) };
+}
+
+/// Restores the representation serialized in before, even on panic.
+struct ReprScope(Repr);
+
+impl Drop for ReprScope {
+    fn drop(&mut self) {
+        REPR.with(|r| r.set(self.0));
+    }
+}
+
+/// Optional prefix of the textual form of identifiers in paths, for the
+/// ones made of digits only, like `~22`, which are otherwise numbers.
+pub const TEXT: &str = "~";
+
+#[derive(Debug, PartialEq)]
+pub struct ParseError(String);
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid identifier: {}", self.0)
+    }
+}
+
+/// Bits of the identifiers for the node which generated them, and for the
+/// sequence of the ones generated within the same millisecond.
+const NODE_BITS: u32 = 10;
+const SEQ_BITS: u32 = 12;
+
+pub const MAX_NODE: u16 = (1 << NODE_BITS) - 1;
+const MAX_SEQ: i64 = (1 << SEQ_BITS) - 1;
+/// Last millisecond identifiers can be generated for, about 69 years after
+/// the epoch.
+const MAX_MS: i64 = i64::MAX >> (NODE_BITS + SEQ_BITS);
+
+/// Generates identifiers Snowflake-style: the milliseconds since the epoch,
+/// then the node, then a sequence within the millisecond. Identifiers are
+/// unique across the nodes given distinct node IDs, and grow as generated,
+/// even if the clock goes back. Clocks before the epoch generate identifiers
+/// as if at the epoch, so identifiers are never negative.
+#[derive(Clone, Debug)]
+pub struct Generator {
+    node: i64,
+    /// The millisecond and sequence of the last generated identifier.
+    last: Arc<Mutex<(i64, i64)>>,
+}
+
+impl Default for Generator {
+    fn default() -> Self {
+        Generator::new(0).unwrap()
     }
+}
 
+impl Generator {
+    pub fn new(node: u16) -> Result<Self, String> {
+        if node > MAX_NODE {
+            return Err(format!("node {} above {}", node, MAX_NODE));
+        }
+        Ok(Generator {
+            node: node.into(),
+            last: Arc::new(Mutex::new((i64::MIN, 0))),
+        })
+    }
+
+    pub fn next(&self, clock: &Clock) -> Identifier {
+        let ms = clock
+            .now()
+            .with_timezone(&Utc)
+            .sub(*EPOCH)
+            .num_milliseconds()
+            .max(0);
+        let mut last = self.last.lock().unwrap();
+        // Once the sequence of the millisecond runs out, identifiers are
+        // taken from the next millisecond, ahead of the clock.
+        *last = match *last {
+            (prev, seq) if ms <= prev && seq < MAX_SEQ => (prev, seq + 1),
+            (prev, _) if ms <= prev => (prev + 1, 0),
+            _ => (ms, 0),
+        };
+        let (ms, seq) = *last;
+        assert!(ms <= MAX_MS, "identifiers ran out after {}", *EPOCH);
+        Identifier(ms << (NODE_BITS + SEQ_BITS) | self.node << SEQ_BITS | seq)
+    }
+}
+
+/// Identifiers are the non-negative numbers, which is checked wherever they
+/// are parsed.
+impl Identifier {
     pub fn int(&self) -> i64 {
         self.0
     }
+
+    /// The identifier in the given representation.
+    pub fn json(&self, repr: Repr) -> serde_json::Value {
+        match repr {
+            Repr::Int => self.0.into(),
+            Repr::Base32 => self.to_string().into(),
+        }
+    }
+
+    /// Parses the textual form of the identifier only, as in the keys of the
+    /// database. Forms other than the one the identifier is displayed in,
+    /// like with leading zeros, are rejected, so each identifier has one.
+    pub fn decode(id: &str) -> Result<Self, ParseError> {
+        let invalid = || ParseError(id.to_string());
+        let decoded = B32.decode(id.as_bytes()).map_err(|_| invalid())?;
+        let sign = num::bigint::Sign::Plus;
+        let bi = BigInt::from_bytes_be(sign, decoded.as_slice());
+        match bi.to_i64().map(Identifier) {
+            Some(parsed) if parsed.to_string() == id => Ok(parsed),
+            _ => Err(invalid()),
+        }
+    }
 }
 
-impl ToString for Identifier {
-    fn to_string(&self) -> String {
+impl Display for Identifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let ts_bi = BigInt::from(self.0);
         let input = ts_bi.to_bytes_be().1;
-        B32.encode(input.as_slice())
+        write!(f, "{}", B32.encode(input.as_slice()))
     }
 }
 
-impl From<&String> for Identifier {
-    fn from(id: &String) -> Self {
-        let decoded = B32.decode(id.as_bytes()).unwrap();
-        let sign = num::bigint::Sign::Plus;
-        let bi = BigInt::from_bytes_be(sign, decoded.as_slice());
-        Identifier(bi.to_i64().unwrap())
+/// Parses either the number, or the textual form of the identifier, as in
+/// paths. Digits only are the number, unless prefixed with [`TEXT`].
+impl FromStr for Identifier {
+    type Err = ParseError;
+
+    fn from_str(id: &str) -> Result<Self, Self::Err> {
+        let invalid = || ParseError(id.to_string());
+        if !id.is_empty() && id.bytes().all(|b| b.is_ascii_digit()) {
+            return id.parse().map(Identifier).map_err(|_| invalid());
+        }
+        Identifier::decode(id.strip_prefix(TEXT).unwrap_or(id))
+            .map_err(|_| invalid())
+    }
+}
+
+impl Serialize for Identifier {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        self.json(REPR.with(Cell::get)).serialize(s)
+    }
+}
+
+/// Either representation, whichever is used.
+impl JsonSchema for Identifier {
+    fn schema_name() -> String {
+        "Identifier".to_string()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        serde_json::from_value(serde_json::json!({
+            "anyOf": [
+                {"type": "integer", "minimum": 0},
+                {"type": "string", "pattern": "^~?[2-7a-z]{1,13}$"},
+            ],
+        }))
+        .expect("valid schema")
+    }
+}
+
+impl<'de> Deserialize<'de> for Identifier {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        d.deserialize_any(IdentifierVisitor)
+    }
+}
+
+struct IdentifierVisitor;
+
+impl<'de> Visitor<'de> for IdentifierVisitor {
+    type Value = Identifier;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an identifier, as a number or in its textual form")
+    }
+
+    fn visit_i64<E: de::Error>(self, v: i64) -> Result<Identifier, E> {
+        match v {
+            0.. => Ok(Identifier(v)),
+            _ => Err(E::custom(ParseError(v.to_string()))),
+        }
+    }
+
+    fn visit_u64<E: de::Error>(self, v: u64) -> Result<Identifier, E> {
+        i64::try_from(v).map(Identifier).map_err(E::custom)
+    }
+
+    /// Strings are the textual form, as numbers are given as such, with the
+    /// prefix or without.
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Identifier, E> {
+        Identifier::decode(v.strip_prefix(TEXT).unwrap_or(v)).map_err(E::custom)
     }
 }
 
@@ -82,17 +303,35 @@ lazy_static! {
         spec.encoding().unwrap()
     };
 
-    /// Epoch designed for app to have as shorter IDs.
-    static ref EPOCH: DateTime<Local> = {
-        Local.with_ymd_and_hms(2023, 4, 12, 12, 43, 56).unwrap()
+    /// Epoch designed for app to have as shorter IDs. It's in UTC, so hosts
+    /// in any timezone generate the same IDs at the same instant.
+    static ref EPOCH: DateTime<Utc> = {
+        Utc.with_ymd_and_hms(2023, 4, 12, 12, 43, 56).unwrap()
     };
 }
 
 #[cfg(test)]
 mod tests {
+    use chrono::{
+        Duration,
+        FixedOffset,
+        Local,
+    };
+    use proptest::{
+        collection::vec,
+        prelude::*,
+    };
+
     use super::*;
     use crate::support::clock::Fixed;
 
+    /// A clock at the given milliseconds since the epoch.
+    fn at(ms: i64) -> Clock {
+        Clock::FixedClock(Fixed {
+            time: (*EPOCH + Duration::milliseconds(ms)).with_timezone(&Local),
+        })
+    }
+
     #[test]
     fn test_interoperation_with_lexicoid() {
         let cases = [
@@ -112,31 +351,158 @@ mod tests {
 
     #[test]
     fn test_epoch() {
-        let clk = Clock::FixedClock(Fixed {
-            time: EPOCH.clone(),
-        });
-        let id = Identifier::new(&clk);
+        let id = Generator::default().next(&at(0));
         assert_eq!(id.int(), 0);
         assert_eq!(id.to_string(), "22");
+        assert_eq!(EPOCH.to_rfc3339(), "2023-04-12T12:43:56+00:00");
+
+        // The same instant, whatever the timezone it's told in.
+        let east = FixedOffset::east_opt(5 * 3600).unwrap();
+        let clk = Clock::FixedClock(Fixed {
+            time: EPOCH.with_timezone(&east).with_timezone(&Local),
+        });
+        assert_eq!(Generator::default().next(&clk).int(), 0);
+    }
+
+    #[test]
+    fn test_before_epoch() {
+        let gen = Generator::default();
+        assert_eq!(gen.next(&at(-86_400_000)).int(), 0);
+        assert_eq!(gen.next(&at(-1)).int(), 1);
+        assert_eq!(gen.next(&at(1)).int(), 1 << 22);
+    }
+
+    #[test]
+    fn test_generator() {
+        let clk = at(5);
+        let node = Generator::new(3).unwrap();
+        let ids: Vec<i64> =
+            (0..=MAX_SEQ + 1).map(|_| node.next(&clk).int()).collect();
+        assert_eq!(ids[0], 5 << 22 | 3 << 12);
+        assert_eq!(ids[1], ids[0] + 1);
+        assert!(ids.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(ids[MAX_SEQ as usize + 1], 6 << 22 | 3 << 12);
+
+        let other = Generator::new(4).unwrap().next(&clk);
+        assert!(!ids.contains(&other.int()));
+
+        assert!(node.next(&at(0)).int() > ids[MAX_SEQ as usize + 1]);
+        assert!(Generator::new(MAX_NODE + 1).is_err());
     }
 
     #[test]
     fn test_from_string() {
         let id = Identifier(1);
         assert_eq!(id.to_string(), "26");
-        let id = Identifier::from(&"26".to_string());
+        let id = Identifier::decode("26").unwrap();
         assert_eq!(id.int(), 1);
         assert_eq!(id.to_string(), "26");
 
-        let id = Identifier::from(&"gk".to_string());
+        let id: Identifier = "gk".parse().unwrap();
         assert_eq!(id.int(), 100);
+        assert_eq!("~gk".parse(), Ok(id.clone()));
         assert_eq!(id.to_string(), "gk");
 
-        let id = Identifier::from(&"5bse2".to_string());
+        let id: Identifier = "5bse2".parse().unwrap();
         assert_eq!(id.int(), 1700000);
         assert_eq!(id.to_string(), "5bse2");
 
-        let id = Identifier::from(&"biykars".to_string());
+        let id: Identifier = "itg2xos".parse().unwrap();
+        assert_eq!(id.to_string(), "itg2xos");
+
+        let id: Identifier = "biykars".parse().unwrap();
         assert_eq!(id.to_string(), "biykars");
+
+        let id: Identifier = "1700000".parse().unwrap();
+        assert_eq!(id.int(), 1700000);
+    }
+
+    #[test]
+    fn test_digits_only() {
+        let id: Identifier = "~22".parse().unwrap();
+        assert_eq!(id.int(), 0);
+        let id: Identifier = "22".parse().unwrap();
+        assert_eq!(id.int(), 22);
+        assert_eq!(Identifier(0).json(Repr::Base32), serde_json::json!("22"));
+        let parsed: Identifier = serde_json::from_str("\"22\"").unwrap();
+        assert_eq!(parsed.int(), 0);
+    }
+
+    #[test]
+    fn test_invalid_string() {
+        assert!("".parse::<Identifier>().is_err());
+        assert!("itg2xo!".parse::<Identifier>().is_err());
+        assert!("1".parse::<Identifier>().is_ok());
+        assert!("~g".parse::<Identifier>().is_err());
+        assert!("g".parse::<Identifier>().is_err());
+        assert!("2222".parse::<Identifier>().is_ok());
+        assert!("~2222".parse::<Identifier>().is_err());
+        assert!("99999999999999999999".parse::<Identifier>().is_err());
+        assert!("~1".parse::<Identifier>().is_err());
+        assert!("~zzzzzzzzzzzzzzzz".parse::<Identifier>().is_err());
+        assert!("-1".parse::<Identifier>().is_err());
+        assert!(Identifier::decode("2222").is_err());
+        assert!(Identifier::decode("").is_err());
+    }
+
+    #[test]
+    fn test_json() {
+        let id = Identifier(100);
+        assert_eq!(id.json(Repr::Int), serde_json::json!(100));
+        assert_eq!(id.json(Repr::Base32), serde_json::json!("gk"));
+        for json in ["100", "\"~gk\"", "\"gk\""] {
+            let parsed: Identifier = serde_json::from_str(json).unwrap();
+            assert_eq!(parsed, id);
+        }
+        assert!(serde_json::from_str::<Identifier>("\"!\"").is_err());
+        assert!(serde_json::from_str::<Identifier>("1.5").is_err());
+        assert!(serde_json::from_str::<Identifier>("-100").is_err());
+        assert!(serde_json::from_str::<Identifier>("\"100\"").is_err());
+    }
+
+    #[test]
+    fn test_represented() {
+        let ids = vec![Identifier(100)];
+        assert_eq!(
+            Repr::Base32.to_value(&ids).unwrap(),
+            serde_json::json!(["gk"])
+        );
+        assert_eq!(
+            serde_json::to_value(&ids).unwrap(),
+            serde_json::json!([100])
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn round_trips(n in 0..=i64::MAX) {
+            let id = Identifier(n);
+            prop_assert_eq!(Identifier::decode(&id.to_string()), Ok(id.clone()));
+            prop_assert_eq!(n.to_string().parse(), Ok(id.clone()));
+            prop_assert_eq!(format!("{}{}", TEXT, id).parse(), Ok(id.clone()));
+            if id.to_string().bytes().any(|b| b.is_ascii_alphabetic()) {
+                prop_assert_eq!(id.to_string().parse(), Ok(id.clone()));
+            }
+            for repr in [Repr::Int, Repr::Base32] {
+                let json = id.json(repr);
+                prop_assert_eq!(serde_json::from_value::<Identifier>(json).ok(), Some(id.clone()));
+            }
+        }
+
+        #[test]
+        fn decodes_one_form(s in "[2-7a-z]{0,16}") {
+            if let Ok(id) = Identifier::decode(&s) {
+                prop_assert!(id.int() >= 0);
+                prop_assert_eq!(id.to_string(), s);
+            }
+        }
+
+        #[test]
+        fn generates_growing(offsets in vec(-10_000i64..10_000, 1..100)) {
+            let gen = Generator::new(7).unwrap();
+            let ids: Vec<i64> = offsets.iter().map(|ms| gen.next(&at(*ms)).int()).collect();
+            prop_assert!(ids[0] >= 0);
+            prop_assert!(ids.windows(2).all(|w| w[0] < w[1]));
+        }
     }
 }
@@ -0,0 +1,648 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    time::Duration,
+};
+
+use actix_web::rt::{
+    self,
+    task::JoinHandle,
+};
+use async_trait::async_trait;
+use chrono::Utc;
+use cloudevents::{
+    event::SpecVersion,
+    message::{
+        BinaryDeserializer,
+        BinarySerializer,
+        Encoding,
+        Error,
+        MessageAttributeValue,
+        MessageDeserializer,
+        Result,
+        StructuredDeserializer,
+        StructuredSerializer,
+    },
+    AttributesReader,
+    Event,
+};
+use rdkafka::{
+    consumer::{
+        Consumer,
+        StreamConsumer,
+    },
+    error::{
+        KafkaError,
+        KafkaResult,
+        RDKafkaErrorCode,
+    },
+    message::{
+        Header,
+        Headers,
+        Message,
+        OwnedHeaders,
+    },
+    producer::{
+        FutureProducer,
+        FutureRecord,
+    },
+    util::Timeout,
+    ClientConfig,
+};
+
+use crate::{
+    app::config::{
+        ContentMode,
+        Delivery,
+        Kafka,
+    },
+    support::{
+        cloudevents::{
+            delay,
+            received,
+            structured_data,
+            Failure,
+            Publisher,
+        },
+        deadletter::{
+            Letter,
+            Store,
+        },
+    },
+};
+
+const PREFIX: &str = "ce_";
+const SPEC_VERSION: &str = "ce_specversion";
+const CONTENT_TYPE: &str = "content-type";
+const STRUCTURED: &str = "application/cloudevents+json";
+
+/// An event as a Kafka record, following the CloudEvents Kafka protocol
+/// binding: attributes in `ce_` headers in binary mode, or the whole event
+/// in the value in structured mode.
+#[derive(Debug, Default)]
+pub struct Record {
+    headers: HashMap<String, Vec<u8>>,
+    payload: Option<Vec<u8>>,
+}
+
+impl Record {
+    pub fn from_event(ce: Event, content: ContentMode) -> Result<Self> {
+        match content {
+            ContentMode::Binary => ce.deserialize_binary(Record::default()),
+            ContentMode::Structured => {
+                ce.deserialize_structured(Record::default())
+            }
+        }
+    }
+
+    pub fn from_message(msg: &impl Message) -> Self {
+        let headers = msg
+            .headers()
+            .map(|headers| {
+                headers
+                    .iter()
+                    .filter_map(|h| {
+                        Some((h.key.to_string(), h.value?.to_vec()))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        Record {
+            headers,
+            payload: msg.payload().map(Vec::from),
+        }
+    }
+
+    pub fn headers(&self) -> OwnedHeaders {
+        self.headers.iter().fold(
+            OwnedHeaders::new(),
+            |headers, (key, value)| {
+                headers.insert(Header {
+                    key,
+                    value: Some(value),
+                })
+            },
+        )
+    }
+
+    fn set(mut self, key: String, value: impl Into<Vec<u8>>) -> Self {
+        self.headers.insert(key, value.into());
+        self
+    }
+}
+
+impl BinarySerializer<Record> for Record {
+    fn set_spec_version(self, sv: SpecVersion) -> Result<Self> {
+        Ok(self.set(SPEC_VERSION.to_string(), sv.to_string()))
+    }
+
+    fn set_attribute(
+        self,
+        name: &str,
+        value: MessageAttributeValue,
+    ) -> Result<Self> {
+        let key = match name {
+            "datacontenttype" => CONTENT_TYPE.to_string(),
+            _ => format!("{}{}", PREFIX, name),
+        };
+        Ok(self.set(key, value.to_string()))
+    }
+
+    fn set_extension(
+        self,
+        name: &str,
+        value: MessageAttributeValue,
+    ) -> Result<Self> {
+        self.set_attribute(name, value)
+    }
+
+    fn end_with_data(mut self, bytes: Vec<u8>) -> Result<Record> {
+        self.payload = Some(bytes);
+        Ok(self)
+    }
+
+    fn end(self) -> Result<Record> {
+        Ok(self)
+    }
+}
+
+impl StructuredSerializer<Record> for Record {
+    fn set_structured_event(mut self, bytes: Vec<u8>) -> Result<Record> {
+        self.payload = Some(bytes);
+        Ok(self.set(CONTENT_TYPE.to_string(), STRUCTURED))
+    }
+}
+
+impl BinaryDeserializer for Record {
+    fn deserialize_binary<R: Sized, V: BinarySerializer<R>>(
+        mut self,
+        mut visitor: V,
+    ) -> Result<R> {
+        if self.encoding() != Encoding::BINARY {
+            return Err(Error::WrongEncoding {});
+        }
+        let sv = self.headers.remove(SPEC_VERSION).unwrap_or_default();
+        let sv = SpecVersion::try_from(text(sv)?.as_str())?;
+        let attributes = sv.attribute_names();
+        visitor = visitor.set_spec_version(sv.clone())?;
+
+        if let Some(ct) = self.headers.remove(CONTENT_TYPE) {
+            visitor = visitor.set_attribute(
+                "datacontenttype",
+                MessageAttributeValue::String(text(ct)?),
+            )?;
+        }
+        for (key, value) in self.headers {
+            let name = match key.strip_prefix(PREFIX) {
+                Some(name) => name,
+                None => continue,
+            };
+            let value = MessageAttributeValue::String(text(value)?);
+            visitor = match attributes.contains(&name) {
+                true => visitor.set_attribute(name, value)?,
+                false => visitor.set_extension(name, value)?,
+            };
+        }
+
+        match self.payload {
+            Some(bytes) => visitor.end_with_data(bytes),
+            None => visitor.end(),
+        }
+    }
+}
+
+impl StructuredDeserializer for Record {
+    fn deserialize_structured<R: Sized, V: StructuredSerializer<R>>(
+        self,
+        visitor: V,
+    ) -> Result<R> {
+        if self.encoding() != Encoding::STRUCTURED {
+            return Err(Error::WrongEncoding {});
+        }
+        visitor.set_structured_event(self.payload.unwrap_or_default())
+    }
+}
+
+impl MessageDeserializer for Record {
+    fn encoding(&self) -> Encoding {
+        let structured = self
+            .headers
+            .get(CONTENT_TYPE)
+            .is_some_and(|ct| ct.starts_with(STRUCTURED.as_bytes()));
+        match (structured, self.headers.contains_key(SPEC_VERSION)) {
+            (true, _) => Encoding::STRUCTURED,
+            (_, true) => Encoding::BINARY,
+            _ => Encoding::UNKNOWN,
+        }
+    }
+}
+
+fn text(bytes: Vec<u8>) -> Result<String> {
+    String::from_utf8(bytes).map_err(|err| Error::Other {
+        source: Box::new(err),
+    })
+}
+
+/// The event the record carries, with its data as received.
+pub fn event(msg: &impl Message) -> Result<Event> {
+    let record = Record::from_message(msg);
+    let data = match record.encoding() {
+        Encoding::STRUCTURED => {
+            record.payload.as_deref().and_then(structured_data)
+        }
+        _ => record.payload.clone(),
+    };
+    let ce = MessageDeserializer::into_event(record)?;
+    Ok(received(ce, data))
+}
+
+/// Produces events to the topic, keyed by their subject, so the events of a
+/// driver stay in order.
+pub struct Producer {
+    producer: FutureProducer,
+    topic:    String,
+    content:  ContentMode,
+}
+
+impl Producer {
+    pub fn new(
+        cfg: &Kafka,
+        content: ContentMode,
+        delivery: &Delivery,
+    ) -> KafkaResult<Self> {
+        let producer = ClientConfig::new()
+            .set("bootstrap.servers", &cfg.brokers)
+            .set(
+                "message.timeout.ms",
+                delivery.timeout.as_millis().to_string(),
+            )
+            .create()?;
+        Ok(Self {
+            producer,
+            topic: cfg.topic.clone(),
+            content,
+        })
+    }
+}
+
+impl std::fmt::Debug for Producer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Producer")
+            .field("topic", &self.topic)
+            .field("content", &self.content)
+            .finish()
+    }
+}
+
+#[async_trait]
+impl Publisher for Producer {
+    async fn publish(&self, ce: Event) -> std::result::Result<(), Failure> {
+        let key = ce.subject().unwrap_or(ce.id()).to_string();
+        let record =
+            Record::from_event(ce, self.content).map_err(|err| Failure {
+                error: err.to_string(),
+                retry: false,
+                after: None,
+            })?;
+        let mut rec = FutureRecord::to(&self.topic)
+            .key(&key)
+            .headers(record.headers());
+        if let Some(payload) = &record.payload {
+            rec = rec.payload(payload);
+        }
+        self.producer
+            .send(rec, Timeout::Never)
+            .await
+            .map(|_| ())
+            .map_err(|(err, _)| Failure {
+                error: err.to_string(),
+                retry: retriable(&err),
+                after: None,
+            })
+    }
+}
+
+/// Whether producing may succeed on another attempt, unlike with records
+/// the brokers refuse.
+fn retriable(err: &KafkaError) -> bool {
+    !matches!(
+        err.rdkafka_error_code(),
+        Some(
+            RDKafkaErrorCode::MessageSizeTooLarge
+                | RDKafkaErrorCode::InvalidMessage
+                | RDKafkaErrorCode::InvalidMessageSize
+                | RDKafkaErrorCode::TopicAuthorizationFailed
+        )
+    )
+}
+
+/// Consumes the events of the topics in the background, in order, handing
+/// each one over before moving past it. Records which aren't events are
+/// skipped, and events failing are retried with the backoff of the delivery,
+/// then dead-lettered. Returns the task consuming, to stop it.
+pub fn subscribe<F, Fut>(
+    cfg: &Kafka,
+    delivery: &Delivery,
+    dead: Store,
+    handle: F,
+) -> KafkaResult<JoinHandle<()>>
+where
+    F: Fn(Event) -> Fut + 'static,
+    Fut: Future<Output = actix_web::Result<()>>,
+{
+    let consumer: StreamConsumer = ClientConfig::new()
+        .set("bootstrap.servers", &cfg.brokers)
+        .set("group.id", &cfg.group)
+        .set("enable.auto.offset.store", "false")
+        .set("auto.offset.reset", "earliest")
+        .create()?;
+    let topics: Vec<&str> = cfg.topics.iter().map(String::as_str).collect();
+    consumer.subscribe(&topics)?;
+    log::info!("Consuming events from {:?}", topics);
+
+    let deliveries = cfg.deliveries;
+    let delivery = delivery.clone();
+    Ok(rt::spawn(async move {
+        loop {
+            let msg = match consumer.recv().await {
+                Ok(msg) => msg,
+                Err(err) => {
+                    log::error!("Failed to consume events: {}", err);
+                    rt::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+            match event(&msg) {
+                Ok(ce) => {
+                    let res = deliver(&ce, &handle, deliveries, &delivery);
+                    if let Err(error) = res.await {
+                        dead_letter(&dead, &delivery, ce, error).await;
+                    }
+                }
+                Err(err) => log::warn!(
+                    "Skipping record {} of {}: {}",
+                    msg.offset(),
+                    msg.topic(),
+                    err
+                ),
+            }
+            if let Err(err) = consumer.store_offset_from_message(&msg) {
+                log::error!("Failed to store offset: {}", err);
+            }
+        }
+    }))
+}
+
+/// Hands the event over until handled, or refused, as with a 4xx error, or
+/// failing after the given deliveries.
+async fn deliver<F, Fut>(
+    ce: &Event,
+    handle: &F,
+    deliveries: usize,
+    delivery: &Delivery,
+) -> std::result::Result<(), String>
+where
+    F: Fn(Event) -> Fut,
+    Fut: Future<Output = actix_web::Result<()>>,
+{
+    let mut attempt = 1;
+    loop {
+        let err = match handle(ce.clone()).await {
+            Ok(()) => return Ok(()),
+            Err(err) => err,
+        };
+        let status = err.as_response_error().status_code();
+        if status.is_client_error() || attempt >= deliveries {
+            return Err(err.to_string());
+        }
+        log::warn!("Retrying event, delivered {} times: {}", attempt, err);
+        rt::time::sleep(delay(delivery, attempt - 1, None)).await;
+        attempt += 1;
+    }
+}
+
+/// Keeps the event which couldn't be processed, trying until it's kept, as
+/// it's moved past afterwards. Without a store, it's only logged.
+async fn dead_letter(
+    dead: &Store,
+    delivery: &Delivery,
+    ce: Event,
+    error: String,
+) {
+    log::error!("Dead-lettering {} event {}: {}", ce.ty(), ce.id(), error);
+    let letter = Letter {
+        id: String::new(),
+        at: Utc::now(),
+        error,
+        event: ce,
+    };
+    for attempt in 0.. {
+        match dead.put(&letter).await {
+            Ok(()) => return,
+            Err(err) if matches!(dead, Store::None) => {
+                log::error!("Lost event: {}", err);
+                return;
+            }
+            Err(err) => log::error!("Failed to dead-letter event: {}", err),
+        }
+        rt::time::sleep(delay(delivery, attempt, None)).await;
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{
+        cell::RefCell,
+        rc::Rc,
+        time::Instant,
+    };
+
+    use cloudevents::{
+        AttributesWriter,
+        Data,
+        EventBuilder,
+        EventBuilderV10,
+    };
+    use rdkafka::{
+        message::OwnedMessage,
+        mocking::MockCluster,
+        producer::DefaultProducerContext,
+        Timestamp,
+    };
+
+    use super::*;
+
+    fn event() -> Event {
+        EventBuilderV10::new()
+            .id("1")
+            .source("usvc://cabs/drivers")
+            .ty("cabs.drivers.driver-fee")
+            .subject("7")
+            .extension("traceparent", "00-0af7651916cd43dd-b7ad6b7169203331-01")
+            .data("application/json", serde_json::json!({"fee": 10}))
+            .build()
+            .unwrap()
+    }
+
+    /// The event, with its data parsed as JSON, as it's kept as received.
+    fn json(mut ce: Event) -> Event {
+        let (ct, _, data) = ce.take_data();
+        let data = serde_json::Value::try_from(data.unwrap()).unwrap();
+        ce.set_data(ct.unwrap(), data);
+        ce
+    }
+
+    fn message(record: Record) -> OwnedMessage {
+        OwnedMessage::new(
+            record.payload.clone(),
+            None,
+            "cabs.drivers".to_string(),
+            Timestamp::NotAvailable,
+            0,
+            0,
+            Some(record.headers()),
+        )
+    }
+
+    #[test]
+    fn binary() {
+        let record = Record::from_event(event(), ContentMode::Binary).unwrap();
+        assert_eq!(record.headers[SPEC_VERSION], b"1.0");
+        assert_eq!(record.headers["ce_type"], b"cabs.drivers.driver-fee");
+        assert_eq!(record.headers[CONTENT_TYPE], b"application/json");
+        assert_eq!(record.payload.as_deref(), Some(&b"{\"fee\":10}"[..]));
+        assert_eq!(json(super::event(&message(record)).unwrap()), event());
+    }
+
+    #[test]
+    fn structured() {
+        let record =
+            Record::from_event(event(), ContentMode::Structured).unwrap();
+        assert_eq!(record.headers[CONTENT_TYPE], STRUCTURED.as_bytes());
+        assert!(!record.headers.contains_key(SPEC_VERSION));
+        let ce = super::event(&message(record)).unwrap();
+        assert_eq!(ce.data(), Some(&Data::Binary(b"{\"fee\":10}".to_vec())));
+        assert_eq!(json(ce), event());
+    }
+
+    #[test]
+    fn not_an_event() {
+        let record = Record {
+            headers: HashMap::new(),
+            payload: Some(b"{}".to_vec()),
+        };
+        assert!(super::event(&message(record)).is_err());
+    }
+
+    /// Waits for the condition to hold, for a while.
+    pub(crate) async fn eventually(cond: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(30);
+        while !cond() {
+            assert!(Instant::now() < deadline, "timed out");
+            rt::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    /// Stops the consumer, before the cluster it would otherwise linger on.
+    pub(crate) async fn stop(consumer: JoinHandle<()>) {
+        consumer.abort();
+        let _ = consumer.await;
+    }
+
+    fn cfg(cluster: &MockCluster<DefaultProducerContext>) -> Kafka {
+        Kafka {
+            brokers:     cluster.bootstrap_servers(),
+            topic:       "cabs.drivers".to_string(),
+            topics:      vec!["cabs.drivers".to_string()],
+            group:       "test".to_string(),
+            deliveries:  3,
+            dead_letter: "events-received-dead-letter".to_string(),
+        }
+    }
+
+    #[actix_web::test]
+    async fn produces_and_consumes() {
+        let cluster = MockCluster::new(1).unwrap();
+        cluster.create_topic("cabs.drivers", 1, 1).unwrap();
+        let cfg = cfg(&cluster);
+        let delivery = crate::app::config::Config::default().knative.delivery;
+
+        let received = Rc::new(RefCell::new(vec![]));
+        let consumer = {
+            let received = received.clone();
+            subscribe(&cfg, &delivery, Store::None, move |ce| {
+                received.borrow_mut().push(ce);
+                async { Ok(()) }
+            })
+            .unwrap()
+        };
+
+        for content in [ContentMode::Binary, ContentMode::Structured] {
+            let producer = Producer::new(&cfg, content, &delivery).unwrap();
+            producer.publish(event()).await.unwrap();
+        }
+
+        eventually(|| received.borrow().len() == 2).await;
+        stop(consumer).await;
+        let received: Vec<_> = received.take().into_iter().map(json).collect();
+        assert_eq!(received, [event(), event()]);
+    }
+
+    #[actix_web::test]
+    async fn retries_then_dead_letters() {
+        let cluster = MockCluster::new(1).unwrap();
+        cluster.create_topic("cabs.drivers", 1, 1).unwrap();
+        let cfg = cfg(&cluster);
+        let mut delivery =
+            crate::app::config::Config::default().knative.delivery;
+        delivery.backoff.factor = Duration::from_millis(1);
+        let path = std::env::temp_dir()
+            .join(format!("dead-letters-{}.ndjson", uuid::Uuid::new_v4()));
+        let dead = Store::File(path.clone());
+
+        // The first event fails, the second is refused, the third is handled
+        // on its second delivery.
+        let attempts = Rc::new(RefCell::new(vec![]));
+        let consumer = {
+            let attempts = attempts.clone();
+            subscribe(&cfg, &delivery, dead.clone(), move |ce| {
+                let id = ce.id().to_string();
+                let tried =
+                    attempts.borrow().iter().filter(|a| **a == id).count();
+                attempts.borrow_mut().push(id.clone());
+                async move {
+                    match id.as_str() {
+                        "1" => Err(actix_web::error::ErrorServiceUnavailable(
+                            "down",
+                        )),
+                        "2" => {
+                            Err(actix_web::error::ErrorBadRequest("refused"))
+                        }
+                        _ if tried == 0 => Err(
+                            actix_web::error::ErrorServiceUnavailable("down"),
+                        ),
+                        _ => Ok(()),
+                    }
+                }
+            })
+            .unwrap()
+        };
+
+        let producer =
+            Producer::new(&cfg, ContentMode::Binary, &delivery).unwrap();
+        for id in ["1", "2", "3"] {
+            let mut ce = event();
+            ce.set_id(id);
+            producer.publish(ce).await.unwrap();
+        }
+
+        eventually(|| attempts.borrow().len() == 6).await;
+        stop(consumer).await;
+        assert_eq!(*attempts.borrow(), ["1", "1", "1", "2", "3", "3"]);
+        let letters = dead.list().await.unwrap();
+        let ids: Vec<_> = letters.iter().map(|l| l.event.id()).collect();
+        assert_eq!(ids, ["1", "2"]);
+        assert!(letters[0].error.contains("down"), "{}", letters[0].error);
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -1,5 +1,13 @@
 pub mod clock;
 pub mod cloudevents;
+pub mod crypto;
+pub mod deadletter;
+pub mod dispatch;
 pub mod id;
+pub mod kafka;
 pub mod money;
 pub mod page;
+pub mod pii;
+pub mod pool;
+pub mod streams;
+pub mod trace;
@@ -1,10 +1,12 @@
+use schemars::JsonSchema;
 use serde::{
     Deserialize,
     Serialize,
 };
 use std::fmt::Display;
 
-#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// An amount, in cents.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
 pub struct Money(i64);
 
 impl Money {
@@ -25,11 +27,14 @@ impl Money {
     pub fn less_then(&self, other: &Self) -> bool {
         self.0 < other.0
     }
+
+    pub fn as_f64(&self) -> f64 {
+        self.0 as f64 / 100.0
+    }
 }
 
 impl Display for Money {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let d = self.0 as f64 / 100.0;
-        write!(f, "{:.2}", d)
+        write!(f, "{:.2}", self.as_f64())
     }
 }
@@ -34,8 +34,8 @@ impl Page {
         self.start() + self.per as isize - 1
     }
 
-    pub fn to_pagination(&self, total: isize) -> Pagination {
-        let mut page = self.clone();
+    pub fn to_pagination(self, total: isize) -> Pagination {
+        let mut page = self;
         if self.stop() > total {
             page.num = (total / self.per as isize) as u32 + 1;
         }
@@ -111,14 +111,11 @@ fn parse_range(range: &str) -> Result<Page> {
     let first = parts
         .next()
         .ok_or(error::ErrorBadRequest("Invalid range"))?;
-    match parts.next() {
-        Some(spec) => {
-            return Err(error::ErrorBadRequest(format!(
-                "Extra invalid range: {}",
-                spec
-            )))
-        }
-        None => (),
+    if let Some(spec) = parts.next() {
+        return Err(error::ErrorBadRequest(format!(
+            "Extra invalid range: {}",
+            spec
+        )));
     }
 
     let mut parts = first.splitn(2, '-');
@@ -250,6 +247,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::field_reassign_with_default)]
     fn test_pagination() {
         let p = Page::default();
         assert_eq!(p.start(), 0);
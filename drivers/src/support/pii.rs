@@ -0,0 +1,27 @@
+use std::{
+    fmt,
+    sync::atomic::{
+        AtomicBool,
+        Ordering,
+    },
+};
+
+static REDACT: AtomicBool = AtomicBool::new(true);
+
+/// Turns the redaction of personal data in debug output on or off. It's on by
+/// default.
+pub fn redact(on: bool) {
+    REDACT.store(on, Ordering::Relaxed);
+}
+
+/// Wraps personal data, so it's redacted when debug formatted.
+pub struct Pii<'a, T: fmt::Debug>(pub &'a T);
+
+impl<'a, T: fmt::Debug> fmt::Debug for Pii<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match REDACT.load(Ordering::Relaxed) {
+            true => write!(f, "***"),
+            false => self.0.fmt(f),
+        }
+    }
+}
@@ -0,0 +1,328 @@
+use std::{
+    fmt,
+    future::Future,
+    io,
+    sync::{
+        atomic::{
+            AtomicU64,
+            AtomicUsize,
+            Ordering,
+        },
+        Arc,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+use actix_web::rt;
+use redis::{
+    aio::{
+        ConnectionLike,
+        ConnectionManager,
+    },
+    Cmd,
+    Pipeline,
+    RedisFuture,
+    RedisResult,
+    Value,
+};
+use serde::Serialize;
+use tokio::sync::OnceCell;
+
+use crate::app::{
+    config::PoolConfig,
+    metrics,
+};
+
+/// A fixed set of long-lived, multiplexed Redis connections shared by all
+/// the requests. Connections are established lazily, on first use, and are
+/// re-established in the background with exponential backoff when lost.
+#[derive(Clone)]
+pub struct Pool {
+    client: redis::Client,
+    config: PoolConfig,
+    slots:  Arc<Vec<OnceCell<ConnectionManager>>>,
+    next:   Arc<AtomicUsize>,
+    stats:  Arc<Stats>,
+}
+
+/// A connection borrowed from the [`Pool`]. It's cheap to clone, and
+/// enforces the configured command timeout.
+#[derive(Clone)]
+pub struct Connection {
+    inner:   ConnectionManager,
+    timeout: Duration,
+    stats:   Arc<Stats>,
+}
+
+#[derive(Debug, Default)]
+struct Stats {
+    connects:       AtomicUsize,
+    acquired:       AtomicU64,
+    acquire_errors: AtomicU64,
+    in_flight:      AtomicUsize,
+    commands:       AtomicU64,
+    command_errors: AtomicU64,
+    timeouts:       AtomicU64,
+    command_micros: AtomicU64,
+}
+
+/// Point in time snapshot of the pool usage.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct PoolStats {
+    pub size:              usize,
+    /// Connections established, each slot keeping its own once it is, as
+    /// it reconnects by itself.
+    pub connects:          usize,
+    pub acquired:          u64,
+    pub acquire_errors:    u64,
+    pub in_flight:         usize,
+    pub commands:          u64,
+    pub command_errors:    u64,
+    pub timeouts:          u64,
+    pub command_time_secs: f64,
+}
+
+impl Pool {
+    pub fn new(client: redis::Client, config: &PoolConfig) -> Self {
+        let size = config.size.max(1);
+        Pool {
+            client,
+            config: config.clone(),
+            slots: Arc::new((0..size).map(|_| OnceCell::new()).collect()),
+            next: Arc::new(AtomicUsize::new(0)),
+            stats: Arc::new(Stats::default()),
+        }
+    }
+
+    /// Hands out the connections in a round-robin fashion.
+    pub async fn get(&self) -> RedisResult<Connection> {
+        let idx = self.next.fetch_add(1, Ordering::Relaxed) % self.slots.len();
+        let res = within(
+            self.config.connect_timeout,
+            self.slots[idx].get_or_try_init(|| self.connect()),
+        )
+        .await;
+        match res {
+            Ok(mgr) => {
+                self.stats.acquired.fetch_add(1, Ordering::Relaxed);
+                Ok(Connection {
+                    inner:   mgr.clone(),
+                    timeout: self.config.command_timeout,
+                    stats:   self.stats.clone(),
+                })
+            }
+            Err(err) => {
+                self.stats.acquire_errors.fetch_add(1, Ordering::Relaxed);
+                log::error!("Failed to acquire redis connection: {}", err);
+                Err(err)
+            }
+        }
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        let s = &self.stats;
+        PoolStats {
+            size:              self.slots.len(),
+            connects:          s.connects.load(Ordering::Relaxed),
+            acquired:          s.acquired.load(Ordering::Relaxed),
+            acquire_errors:    s.acquire_errors.load(Ordering::Relaxed),
+            in_flight:         s.in_flight.load(Ordering::Relaxed),
+            commands:          s.commands.load(Ordering::Relaxed),
+            command_errors:    s.command_errors.load(Ordering::Relaxed),
+            timeouts:          s.timeouts.load(Ordering::Relaxed),
+            command_time_secs: s.command_micros.load(Ordering::Relaxed) as f64
+                / 1_000_000.0,
+        }
+    }
+
+    async fn connect(&self) -> RedisResult<ConnectionManager> {
+        let backoff = &self.config.backoff;
+        log::debug!("Connecting to redis, backoff: {:?}", backoff);
+        let mgr = self
+            .client
+            .get_tokio_connection_manager_with_backoff(
+                backoff.base,
+                backoff.factor.as_millis() as u64,
+                backoff.retries,
+            )
+            .await?;
+        self.stats.connects.fetch_add(1, Ordering::Relaxed);
+        Ok(mgr)
+    }
+}
+
+impl fmt::Debug for Pool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Pool")
+            .field("client", &self.client)
+            .field("config", &self.config)
+            .field("stats", &self.stats())
+            .finish()
+    }
+}
+
+impl Connection {
+    async fn timed<T, F>(
+        command: String,
+        timeout: Duration,
+        stats: Arc<Stats>,
+        f: F,
+    ) -> RedisResult<T>
+    where
+        F: Future<Output = RedisResult<T>>,
+    {
+        let start = Instant::now();
+        let in_flight = InFlight::new(stats.clone());
+        let res = within(timeout, f).await;
+        drop(in_flight);
+        stats.commands.fetch_add(1, Ordering::Relaxed);
+        let elapsed = start.elapsed();
+        stats
+            .command_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        metrics::redis_command(&command, elapsed.as_secs_f64());
+        if let Err(err) = &res {
+            stats.command_errors.fetch_add(1, Ordering::Relaxed);
+            if err.is_timeout() {
+                stats.timeouts.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        res
+    }
+}
+
+/// Counts a command in flight until dropped, as when done, or when the
+/// caller stops waiting for it.
+struct InFlight(Arc<Stats>);
+
+impl InFlight {
+    fn new(stats: Arc<Stats>) -> Self {
+        stats.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight(stats)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl ConnectionLike for Connection {
+    fn req_packed_command<'a>(
+        &'a mut self,
+        cmd: &'a Cmd,
+    ) -> RedisFuture<'a, Value> {
+        let (timeout, stats) = (self.timeout, self.stats.clone());
+        Box::pin(Self::timed(
+            command(cmd),
+            timeout,
+            stats,
+            self.inner.req_packed_command(cmd),
+        ))
+    }
+
+    fn req_packed_commands<'a>(
+        &'a mut self,
+        cmd: &'a Pipeline,
+        offset: usize,
+        count: usize,
+    ) -> RedisFuture<'a, Vec<Value>> {
+        let (timeout, stats) = (self.timeout, self.stats.clone());
+        Box::pin(Self::timed(
+            "PIPELINE".to_string(),
+            timeout,
+            stats,
+            self.inner.req_packed_commands(cmd, offset, count),
+        ))
+    }
+
+    fn get_db(&self) -> i64 {
+        self.inner.get_db()
+    }
+}
+
+fn command(cmd: &Cmd) -> String {
+    match cmd.args_iter().next() {
+        Some(redis::Arg::Simple(name)) => {
+            String::from_utf8_lossy(name).to_ascii_uppercase()
+        }
+        _ => "UNKNOWN".to_string(),
+    }
+}
+
+async fn within<T, F>(timeout: Duration, f: F) -> RedisResult<T>
+where
+    F: Future<Output = RedisResult<T>>,
+{
+    match rt::time::timeout(timeout, f).await {
+        Ok(res) => res,
+        Err(_) => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("timed out after {}ms", timeout.as_millis()),
+        )
+        .into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+    use crate::app::config::Config;
+
+    #[actix_web::test]
+    async fn command_timeout() {
+        let mut config = Config::default().db.pool;
+        config.size = 2;
+        config.command_timeout = Duration::from_millis(100);
+        // Accepts connections, but never replies to any command.
+        let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("redis://{}/", silent.local_addr().unwrap());
+        let pool = Pool::new(redis::Client::open(url).unwrap(), &config);
+
+        let mut conn = pool.get().await.unwrap();
+        let res: RedisResult<String> =
+            redis::cmd("PING").query_async(&mut conn).await;
+        assert!(res.unwrap_err().is_timeout());
+
+        let stats = pool.stats();
+        assert_eq!(stats.size, 2);
+        assert_eq!(stats.connects, 1);
+        assert_eq!(stats.acquired, 1);
+        assert_eq!(stats.in_flight, 0);
+        assert_eq!(stats.commands, 1);
+        assert_eq!(stats.timeouts, 1);
+
+        // Given up on before it times out.
+        let ping = redis::cmd("PING");
+        let res = rt::time::timeout(
+            Duration::from_millis(10),
+            ping.query_async::<_, String>(&mut conn),
+        )
+        .await;
+        assert!(res.is_err());
+        assert_eq!(pool.stats().in_flight, 0);
+    }
+
+    #[actix_web::test]
+    async fn connect_failure() {
+        let mut config = Config::default().db.pool;
+        config.backoff.retries = 0;
+        let port = portpicker::pick_unused_port().expect("No free ports");
+        let url = format!("redis://127.0.0.1:{}/", port);
+        let pool = Pool::new(redis::Client::open(url).unwrap(), &config);
+
+        assert!(pool.get().await.is_err());
+
+        let stats = pool.stats();
+        assert_eq!(stats.connects, 0);
+        assert_eq!(stats.acquired, 0);
+        assert_eq!(stats.acquire_errors, 1);
+    }
+}
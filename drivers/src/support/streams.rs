@@ -0,0 +1,438 @@
+use std::{
+    future::Future,
+    time::Duration,
+};
+
+use actix_web::{
+    rt::{
+        self,
+        task::JoinHandle,
+    },
+    Result,
+};
+use async_trait::async_trait;
+use chrono::Utc;
+use cloudevents::{
+    AttributesReader,
+    Event,
+};
+use redis::{
+    aio::Connection,
+    streams::{
+        StreamClaimReply,
+        StreamId,
+        StreamPendingCountReply,
+        StreamReadOptions,
+        StreamReadReply,
+    },
+    RedisError,
+    RedisResult,
+};
+
+use crate::{
+    app::config::Streams,
+    support::{
+        cloudevents::{
+            from_structured,
+            Failure,
+            Publisher,
+        },
+        deadletter::{
+            Letter,
+            Store,
+        },
+        pool::Pool,
+    },
+};
+
+/// Field of the entries holding the event, as JSON.
+const FIELD: &str = "event";
+
+/// Entries read or claimed at once.
+const COUNT: usize = 10;
+
+/// Time reads wait for new entries, before claiming the pending ones again.
+const BLOCK: Duration = Duration::from_secs(1);
+
+/// Adds events to the stream, trimmed to about its maximum length.
+#[derive(Clone)]
+pub struct Producer {
+    pool:    Pool,
+    stream:  String,
+    max_len: usize,
+}
+
+impl Producer {
+    pub fn new(cfg: &Streams, pool: Pool) -> Self {
+        Self {
+            pool,
+            stream: cfg.stream.clone(),
+            max_len: cfg.max_len,
+        }
+    }
+}
+
+impl std::fmt::Debug for Producer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Producer")
+            .field("stream", &self.stream)
+            .field("max_len", &self.max_len)
+            .finish()
+    }
+}
+
+#[async_trait]
+impl Publisher for Producer {
+    async fn publish(&self, ce: Event) -> std::result::Result<(), Failure> {
+        let json = serde_json::to_string(&ce).map_err(|err| Failure {
+            error: err.to_string(),
+            retry: false,
+            after: None,
+        })?;
+        let failure = |err: RedisError| Failure {
+            error: err.to_string(),
+            retry: err.is_io_error()
+                || err.is_timeout()
+                || err.is_connection_dropped()
+                || err.is_connection_refusal(),
+            after: None,
+        };
+        let mut conn = self.pool.get().await.map_err(failure)?;
+        redis::cmd("XADD")
+            .arg(&self.stream)
+            .arg("MAXLEN")
+            .arg("~")
+            .arg(self.max_len)
+            .arg("*")
+            .arg(FIELD)
+            .arg(json)
+            .query_async::<_, ()>(&mut conn)
+            .await
+            .map_err(failure)
+    }
+}
+
+/// What becomes of an entry once handled.
+#[derive(Debug, PartialEq)]
+enum Fate {
+    Acked,
+    /// Left pending, to be claimed again once idle.
+    Retried,
+    DeadLettered(String),
+}
+
+/// Events refused, as with a 4xx error, are dead-lettered right away.
+/// Others failing are retried up to the configured deliveries.
+fn fate(
+    res: std::result::Result<(), (String, bool)>,
+    deliveries: usize,
+    cfg: &Streams,
+) -> Fate {
+    match res {
+        Ok(()) => Fate::Acked,
+        Err((error, retry)) if retry && deliveries < cfg.deliveries => {
+            log::warn!(
+                "Retrying event, delivered {} times: {}",
+                deliveries,
+                error
+            );
+            Fate::Retried
+        }
+        Err((error, _)) => Fate::DeadLettered(error),
+    }
+}
+
+fn event(entry: &StreamId) -> std::result::Result<Event, String> {
+    let json: String = entry
+        .get(FIELD)
+        .ok_or_else(|| format!("no {} field", FIELD))?;
+    from_structured(json.as_bytes()).map_err(|err| err.to_string())
+}
+
+/// Consumes the events of the streams in the background, as part of the
+/// consumer group, acknowledging them once handled. Entries left pending
+/// long enough, by a failure or a consumer gone, are claimed and handled
+/// again, until dead-lettered. Returns the task consuming, to stop it.
+pub fn subscribe<F, Fut>(
+    cfg: &Streams,
+    client: redis::Client,
+    dead: Store,
+    handle: F,
+) -> JoinHandle<()>
+where
+    F: Fn(Event) -> Fut + 'static,
+    Fut: Future<Output = Result<()>>,
+{
+    let consumer = Consumer {
+        cfg: cfg.clone(),
+        dead,
+    };
+    log::info!("Consuming events from {:?}", cfg.streams);
+    rt::spawn(async move {
+        loop {
+            // Reads block, so they get a connection of their own.
+            let res = match client.get_async_connection().await {
+                Ok(mut conn) => consumer.run(&mut conn, &handle).await,
+                Err(err) => Err(err),
+            };
+            if let Err(err) = res {
+                log::error!("Failed to consume events: {}", err);
+                rt::time::sleep(Duration::from_secs(1)).await;
+            }
+        }
+    })
+}
+
+struct Consumer {
+    cfg:  Streams,
+    dead: Store,
+}
+
+impl Consumer {
+    async fn run<F, Fut>(
+        &self,
+        conn: &mut Connection,
+        handle: &F,
+    ) -> RedisResult<()>
+    where
+        F: Fn(Event) -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        for stream in &self.cfg.streams {
+            let res = redis::Cmd::xgroup_create_mkstream(
+                stream,
+                &self.cfg.group,
+                "0",
+            )
+            .query_async::<_, ()>(conn)
+            .await;
+            match res {
+                Err(err) if err.code() != Some("BUSYGROUP") => return Err(err),
+                _ => (),
+            }
+        }
+        loop {
+            for stream in &self.cfg.streams {
+                self.claim(conn, stream, handle).await?;
+            }
+            self.read(conn, handle).await?;
+        }
+    }
+
+    async fn claim<F, Fut>(
+        &self,
+        conn: &mut Connection,
+        stream: &str,
+        handle: &F,
+    ) -> RedisResult<()>
+    where
+        F: Fn(Event) -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        let idle = self.cfg.claim_idle.as_millis() as usize;
+        let pending: StreamPendingCountReply = redis::cmd("XPENDING")
+            .arg(stream)
+            .arg(&self.cfg.group)
+            .arg("IDLE")
+            .arg(idle)
+            .arg("-")
+            .arg("+")
+            .arg(COUNT)
+            .query_async(conn)
+            .await?;
+        for pending in pending.ids {
+            let claimed: StreamClaimReply = redis::Cmd::xclaim(
+                stream,
+                &self.cfg.group,
+                &self.cfg.consumer,
+                idle,
+                &[&pending.id],
+            )
+            .query_async(conn)
+            .await?;
+            // Claiming counts as another delivery.
+            let deliveries = pending.times_delivered + 1;
+            for entry in claimed.ids {
+                self.handle(conn, stream, entry, deliveries, handle).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn read<F, Fut>(
+        &self,
+        conn: &mut Connection,
+        handle: &F,
+    ) -> RedisResult<()>
+    where
+        F: Fn(Event) -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        let ids = vec![">"; self.cfg.streams.len()];
+        let opts = StreamReadOptions::default()
+            .group(&self.cfg.group, &self.cfg.consumer)
+            .count(COUNT)
+            .block(BLOCK.as_millis() as usize);
+        let reply: Option<StreamReadReply> =
+            redis::Cmd::xread_options(&self.cfg.streams, &ids, &opts)
+                .query_async(conn)
+                .await?;
+        for key in reply.map(|reply| reply.keys).unwrap_or_default() {
+            for entry in key.ids {
+                self.handle(conn, &key.key, entry, 1, handle).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn handle<F, Fut>(
+        &self,
+        conn: &mut Connection,
+        stream: &str,
+        entry: StreamId,
+        deliveries: usize,
+        handle: &F,
+    ) -> RedisResult<()>
+    where
+        F: Fn(Event) -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        let ce = match event(&entry) {
+            Ok(ce) => ce,
+            Err(err) => {
+                log::warn!(
+                    "Skipping entry {} of {}: {}",
+                    entry.id,
+                    stream,
+                    err
+                );
+                return self.ack(conn, stream, &entry.id).await;
+            }
+        };
+        let res = handle(ce.clone()).await.map_err(|err| {
+            let status = err.as_response_error().status_code();
+            (err.to_string(), !status.is_client_error())
+        });
+        match fate(res, deliveries, &self.cfg) {
+            Fate::Acked => self.ack(conn, stream, &entry.id).await,
+            Fate::Retried => Ok(()),
+            Fate::DeadLettered(error) => {
+                log::error!(
+                    "Dead-lettering {} event {}: {}",
+                    ce.ty(),
+                    ce.id(),
+                    error
+                );
+                let letter = Letter {
+                    id: String::new(),
+                    at: Utc::now(),
+                    error,
+                    event: ce,
+                };
+                // Left pending if it can't be kept, to be claimed again.
+                match self.dead.put(&letter).await {
+                    Ok(()) => self.ack(conn, stream, &entry.id).await,
+                    Err(err) => {
+                        log::error!("Failed to dead-letter event: {}", err);
+                        Ok(())
+                    }
+                }
+            }
+        }
+    }
+
+    async fn ack(
+        &self,
+        conn: &mut Connection,
+        stream: &str,
+        id: &str,
+    ) -> RedisResult<()> {
+        redis::Cmd::xack(stream, &self.cfg.group, &[id])
+            .query_async(conn)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use cloudevents::{
+        Data,
+        EventBuilder,
+        EventBuilderV10,
+    };
+
+    use super::*;
+
+    fn cfg() -> Streams {
+        Streams {
+            stream:      "cabs.drivers".to_string(),
+            max_len:     100,
+            streams:     vec!["cabs.legacy".to_string()],
+            group:       "drivers".to_string(),
+            consumer:    "drivers-1".to_string(),
+            claim_idle:  Duration::from_secs(30),
+            deliveries:  3,
+            dead_letter: "events-received-dead-letter".to_string(),
+        }
+    }
+
+    fn entry(fields: &[(&str, &str)]) -> StreamId {
+        StreamId {
+            id:  "1-0".to_string(),
+            map: fields
+                .iter()
+                .map(|(k, v)| {
+                    (k.to_string(), redis::Value::Data(v.as_bytes().to_vec()))
+                })
+                .collect::<HashMap<_, _>>(),
+        }
+    }
+
+    #[test]
+    fn reads_events() {
+        let ce = EventBuilderV10::new()
+            .id("1")
+            .source("usvc://cabs/legacy")
+            .ty("cabs.drivers.calculate-fee")
+            .data("application/json", serde_json::json!({"driver-id": 1}))
+            .build()
+            .unwrap();
+        let json = r#"{"specversion": "1.0", "id": "1",
+            "source": "usvc://cabs/legacy", "type": "cabs.drivers.calculate-fee",
+            "datacontenttype": "application/json", "data": {"driver-id": 1}}"#;
+        let read = event(&entry(&[(FIELD, json)])).unwrap();
+        assert_eq!(
+            read.data(),
+            Some(&Data::Binary(br#"{"driver-id": 1}"#.to_vec()))
+        );
+        let data = serde_json::Value::try_from(read.data().unwrap().clone());
+        assert_eq!(ce.data(), Some(&Data::Json(data.unwrap())));
+        assert_eq!(read.id(), ce.id());
+        assert!(event(&entry(&[("other", json)])).is_err());
+        assert!(event(&entry(&[(FIELD, "{}")])).is_err());
+    }
+
+    #[test]
+    fn retries_until_dead_lettered() {
+        let cfg = cfg();
+        let failed = || Err(("503 Service Unavailable".to_string(), true));
+        assert_eq!(fate(Ok(()), 1, &cfg), Fate::Acked);
+        assert_eq!(fate(failed(), 1, &cfg), Fate::Retried);
+        assert_eq!(fate(failed(), 2, &cfg), Fate::Retried);
+        assert_eq!(
+            fate(failed(), 3, &cfg),
+            Fate::DeadLettered("503 Service Unavailable".to_string())
+        );
+    }
+
+    #[test]
+    fn dead_letters_refused() {
+        let refused = Err(("404 Not Found".to_string(), false));
+        assert_eq!(
+            fate(refused, 1, &cfg()),
+            Fate::DeadLettered("404 Not Found".to_string())
+        );
+    }
+}
@@ -0,0 +1,247 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+};
+
+use actix_web::{
+    body::MessageBody,
+    dev::{
+        ServiceRequest,
+        ServiceResponse,
+    },
+    http::header::HeaderMap,
+    middleware::Next,
+    Error,
+    Result,
+};
+use cloudevents::{
+    event::ExtensionValue,
+    AttributesReader,
+    Event,
+};
+use opentelemetry::{
+    global,
+    propagation::Extractor,
+    trace::{
+        FutureExt,
+        SpanKind,
+        Status,
+        TraceContextExt,
+        Tracer,
+    },
+    Context,
+    KeyValue,
+};
+
+const NAME: &str = "drivers";
+
+/// Runs the future within a new span, child of the current one.
+pub async fn in_span<T, F>(name: &'static str, f: F) -> Result<T>
+where
+    F: Future<Output = Result<T>>,
+{
+    in_span_with(name, &Context::current(), vec![], f).await
+}
+
+async fn in_span_with<T, F>(
+    name: impl Into<String>,
+    parent: &Context,
+    attrs: Vec<KeyValue>,
+    f: F,
+) -> Result<T>
+where
+    F: Future<Output = Result<T>>,
+{
+    let tracer = global::tracer(NAME);
+    let span = tracer
+        .span_builder(name.into())
+        .with_attributes(attrs)
+        .start_with_context(&tracer, parent);
+    let cx = parent.with_span(span);
+    let res = f.with_context(cx.clone()).await;
+    if let Err(err) = &res {
+        cx.span().set_status(Status::error(err.to_string()));
+    }
+    cx.span().end();
+    res
+}
+
+/// Runs the handling of the event in a span, continuing the trace carried by
+/// the event's distributed tracing extension, if any.
+pub async fn handle_event<T, F, Fut>(ce: Event, f: F) -> Result<T>
+where
+    F: FnOnce(Event) -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let remote =
+        global::get_text_map_propagator(|p| p.extract(&EventCarrier(&ce)));
+    let parent = match remote.span().span_context().is_valid() {
+        true => remote,
+        false => Context::current(),
+    };
+    let attrs = vec![
+        KeyValue::new("cloudevents.event_id", ce.id().to_string()),
+        KeyValue::new("cloudevents.event_type", ce.ty().to_string()),
+        KeyValue::new("cloudevents.event_source", ce.source().to_string()),
+    ];
+    let name = format!("{} process", ce.ty());
+    in_span_with(name, &parent, attrs, f(ce)).await
+}
+
+/// Sets the distributed tracing extension of the event from the current
+/// span.
+pub fn inject(ce: &mut Event) {
+    let mut fields = HashMap::new();
+    global::get_text_map_propagator(|p| {
+        p.inject_context(&Context::current(), &mut fields)
+    });
+    for (key, val) in fields.into_iter().filter(|(_, v)| !v.is_empty()) {
+        ce.set_extension(&key, val);
+    }
+}
+
+/// Middleware running the HTTP requests within a server span, continuing the
+/// trace given in the W3C `traceparent` header.
+pub async fn middleware(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let parent = global::get_text_map_propagator(|p| {
+        p.extract(&HeaderCarrier(req.headers()))
+    });
+    let route = req
+        .match_pattern()
+        .unwrap_or_else(|| req.path().to_string());
+    let tracer = global::tracer(NAME);
+    let span = tracer
+        .span_builder(format!("{} {}", req.method(), route))
+        .with_kind(SpanKind::Server)
+        .with_attributes(vec![
+            KeyValue::new("http.method", req.method().to_string()),
+            KeyValue::new("http.route", route),
+        ])
+        .start_with_context(&tracer, &parent);
+    let cx = parent.with_span(span);
+    let res = next.call(req).with_context(cx.clone()).await;
+    let span = cx.span();
+    match &res {
+        Ok(res) => {
+            let status = res.status();
+            span.set_attribute(KeyValue::new(
+                "http.status_code",
+                status.as_u16() as i64,
+            ));
+            if status.is_server_error() {
+                span.set_status(Status::error(status.to_string()));
+            }
+        }
+        Err(err) => span.set_status(Status::error(err.to_string())),
+    }
+    span.end();
+    res
+}
+
+struct EventCarrier<'a>(&'a Event);
+
+impl<'a> Extractor for EventCarrier<'a> {
+    fn get(&self, key: &str) -> Option<&str> {
+        match self.0.extension(key) {
+            Some(ExtensionValue::String(s)) => Some(s.as_str()),
+            _ => None,
+        }
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.iter_extensions().map(|(k, _)| k).collect()
+    }
+}
+
+struct HeaderCarrier<'a>(&'a HeaderMap);
+
+impl<'a> Extractor for HeaderCarrier<'a> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|v| v.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|k| k.as_str()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cloudevents::{
+        EventBuilder,
+        EventBuilderV10,
+    };
+    use opentelemetry::trace::{
+        SpanContext,
+        SpanId,
+        TraceFlags,
+        TraceId,
+        TraceState,
+    };
+    use opentelemetry_sdk::propagation::TraceContextPropagator;
+
+    use super::*;
+
+    const TRACEPARENT: &str =
+        "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    fn event(traceparent: Option<&str>) -> Event {
+        let mut builder = EventBuilderV10::new()
+            .id("1")
+            .source("usvc://cabs/legacy")
+            .ty("cabs.drivers.calculate-fee");
+        if let Some(tp) = traceparent {
+            builder = builder.extension("traceparent", tp);
+        }
+        builder.build().unwrap()
+    }
+
+    #[actix_web::test]
+    async fn propagates_through_event() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let incoming = event(Some(TRACEPARENT));
+
+        let outgoing = handle_event(incoming, |_| async {
+            let mut out = event(None);
+            inject(&mut out);
+            Ok(out)
+        })
+        .await
+        .unwrap();
+
+        // The no-op tracer keeps the remote span context, so the very same
+        // trace continues in the emitted event.
+        assert_eq!(
+            outgoing.extension("traceparent"),
+            Some(&ExtensionValue::String(TRACEPARENT.to_string()))
+        );
+    }
+
+    #[actix_web::test]
+    async fn inject_current_span() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let sc = SpanContext::new(
+            TraceId::from(1u128),
+            SpanId::from(2u64),
+            TraceFlags::SAMPLED,
+            true,
+            TraceState::default(),
+        );
+        let cx = Context::current().with_remote_span_context(sc);
+        let _guard = cx.attach();
+
+        let mut ce = event(None);
+        inject(&mut ce);
+        assert_eq!(
+            ce.extension("traceparent"),
+            Some(&ExtensionValue::String(
+                "00-00000000000000000000000000000001-0000000000000002-01"
+                    .to_string()
+            ))
+        );
+        assert_eq!(ce.extension("tracestate"), None);
+    }
+}